@@ -2,6 +2,15 @@ fn main() {
     tonic_build::configure()
         .out_dir("src/generated")
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
-        .compile(&["proto/api.proto"], &["proto"])
+        // unsigned txs made before ZIP 212 support
+        .field_attribute(".zams.SaplingTxIn.rseed", "#[serde(default)]")
+        .field_attribute(".zams.SaplingTxIn.zip212", "#[serde(default)]")
+        // unsigned txs made before batch payments, memos and fees
+        .field_attribute(".zams.SaplingTxOut.memo", "#[serde(default)]")
+        .field_attribute(".zams.UnsignedTx.fee", "#[serde(default)]")
+        .field_attribute(".zams.UnsignedTx.outputs", "#[serde(default)]")
+        // unsigned txs made before sweeps
+        .field_attribute(".zams.UTXO.address", "#[serde(default)]")
+        .compile(&["proto/api.proto", "proto/service.proto"], &["proto"])
         .unwrap();
 }
@@ -1 +1,2 @@
-grpcurl -import-path $HOME/projects/CoinPaymentsLib/zams/proto -proto api.proto -plaintext localhost:3001 zams.BlockExplorer.Sync
+# Wakes up the ZAMS sync scheduler (listens on port+10)
+curl -s http://127.0.0.1:3011/blocknotify/$1
@@ -1,8 +1,13 @@
+DROP TABLE IF EXISTS rescans;
+DROP TABLE IF EXISTS mempool_txs;
 DROP TABLE IF EXISTS utxos;
 DROP TABLE IF EXISTS sapling_witnesses;
 DROP TABLE IF EXISTS sent_notes;
 DROP TABLE IF EXISTS received_notes;
 DROP TABLE IF EXISTS transactions;
+DROP TABLE IF EXISTS payment_sources;
+DROP TABLE IF EXISTS payment_history;
+DROP TABLE IF EXISTS payment_outputs;
 DROP TABLE IF EXISTS payments;
 DROP TABLE IF EXISTS accounts;
 DROP TABLE IF EXISTS fvks;
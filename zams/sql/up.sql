@@ -2,16 +2,27 @@ CREATE TABLE IF NOT EXISTS fvks (
     id_fvk INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
     extfvk TEXT NOT NULL,
     diversifier_low BIGINT NOT NULL,
-    diversifier_high BIGINT NOT NULL
+    diversifier_high BIGINT NOT NULL,
+    unified BOOL NOT NULL DEFAULT FALSE,
+    transparent_key BYTEA,
+    birthday INTEGER,
+    id_seed INTEGER,
+    key_path TEXT
 );
-CREATE UNIQUE INDEX fvks_fvk ON fvks(extfvk);
+CREATE UNIQUE INDEX IF NOT EXISTS fvks_fvk ON fvks(extfvk);
 CREATE TABLE IF NOT EXISTS accounts (
     account INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
     fvk INTEGER,
     address TEXT NOT NULL,
+    unified_address TEXT,
+    transparent_receiver TEXT,
+    transparent_index INTEGER,
+    birthday INTEGER,
+    id_seed INTEGER,
+    key_path TEXT,
     FOREIGN KEY (fvk) REFERENCES fvks(id_fvk)
 );
-CREATE UNIQUE INDEX account_address ON accounts(address);
+CREATE UNIQUE INDEX IF NOT EXISTS account_address ON accounts(address);
 CREATE TABLE IF NOT EXISTS payments (
     id_payment INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
     datetime TIMESTAMP NOT NULL,
@@ -20,10 +31,40 @@ CREATE TABLE IF NOT EXISTS payments (
     recipient TEXT NOT NULL,
     change TEXT NOT NULL,
     amount BIGINT,
-    paid BOOL,
+    fee BIGINT NOT NULL,
+    state TEXT NOT NULL,
+    updated TIMESTAMP NOT NULL,
     txid TEXT,
+    expiry_height INTEGER NOT NULL,
+    reissue_of INTEGER,
+    FOREIGN KEY (account) REFERENCES accounts(account),
+    FOREIGN KEY (reissue_of) REFERENCES payments(id_payment)
+);
+CREATE TABLE IF NOT EXISTS payment_outputs (
+    id_output INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    payment INTEGER NOT NULL,
+    address TEXT NOT NULL,
+    amount BIGINT NOT NULL,
+    memo BYTEA,
+    FOREIGN KEY (payment) REFERENCES payments(id_payment)
+);
+CREATE INDEX IF NOT EXISTS payment_outputs_payment ON payment_outputs(payment);
+CREATE TABLE IF NOT EXISTS payment_sources (
+    payment INTEGER NOT NULL,
+    account INTEGER NOT NULL,
+    PRIMARY KEY (payment, account),
+    FOREIGN KEY (payment) REFERENCES payments(id_payment),
     FOREIGN KEY (account) REFERENCES accounts(account)
 );
+CREATE TABLE IF NOT EXISTS payment_history (
+    id_history INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    payment INTEGER NOT NULL,
+    datetime TIMESTAMP NOT NULL,
+    state TEXT NOT NULL,
+    detail TEXT,
+    FOREIGN KEY (payment) REFERENCES payments(id_payment)
+);
+CREATE INDEX IF NOT EXISTS payment_history_payment ON payment_history(payment);
 CREATE TABLE IF NOT EXISTS blocks (
     height INTEGER PRIMARY KEY,
     hash BYTEA NOT NULL,
@@ -49,6 +90,8 @@ CREATE TABLE IF NOT EXISTS received_notes (
     address TEXT NOT NULL,
     value BIGINT NOT NULL,
     rcm BYTEA NOT NULL,
+    rseed BYTEA,
+    zip212 BOOL NOT NULL DEFAULT FALSE,
     nf BYTEA NOT NULL UNIQUE,
     is_change BOOL,
     memo BYTEA,
@@ -61,7 +104,7 @@ CREATE TABLE IF NOT EXISTS received_notes (
     FOREIGN KEY (payment) REFERENCES payments(id_payment),
     CONSTRAINT tx_received_output UNIQUE (tx, output_index)
 );
-CREATE INDEX received_notes_address ON received_notes(address);
+CREATE INDEX IF NOT EXISTS received_notes_address ON received_notes(address);
 CREATE TABLE IF NOT EXISTS sapling_witnesses (
     id_witness INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
     note INTEGER NOT NULL,
@@ -98,17 +141,102 @@ CREATE TABLE IF NOT EXISTS utxos (
     FOREIGN KEY (account) REFERENCES accounts(account),
     FOREIGN KEY (payment) REFERENCES payments(id_payment)
 );
-CREATE INDEX utxo_tx ON utxos(tx_hash);
-CREATE UNIQUE INDEX utxo_tx_idx ON utxos(tx_hash, output_index);
+CREATE INDEX IF NOT EXISTS utxo_tx ON utxos(tx_hash);
+CREATE UNIQUE INDEX IF NOT EXISTS utxo_tx_idx ON utxos(tx_hash, output_index);
 CREATE TABLE IF NOT EXISTS notifications (
     id_notification INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
     datetime TIMESTAMP NOT NULL,
-    outgoing BOOL NOT NULL,
+    event_type TEXT NOT NULL,
     tx_hash BYTEA NOT NULL,
     account INT NOT NULL,
     tx_output_index INT NOT NULL,
     amount BIGINT NOT NULL,
     block INT NOT NULL,
     delivered BOOL NOT NULL,
-    CONSTRAINT notification_output UNIQUE (tx_hash, tx_output_index, outgoing)
+    reverted BOOL NOT NULL DEFAULT FALSE,
+    active BOOL NOT NULL DEFAULT TRUE,
+    confirmations INT NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS mempool_txs (
+    id_mempool_tx INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    tx_hash BYTEA NOT NULL,
+    output_index INTEGER NOT NULL,
+    shielded BOOL NOT NULL,
+    account INTEGER NOT NULL,
+    value BIGINT NOT NULL,
+    first_seen TIMESTAMP NOT NULL,
+    left_height INTEGER,
+    status TEXT NOT NULL,
+    FOREIGN KEY (account) REFERENCES accounts(account),
+    CONSTRAINT mempool_output UNIQUE (tx_hash, output_index, shielded)
+);
+CREATE TABLE IF NOT EXISTS rescans (
+    id_rescan INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    fvk INTEGER,
+    account INTEGER,
+    start_height INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    end_height INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    error TEXT,
+    FOREIGN KEY (fvk) REFERENCES fvks(id_fvk),
+    FOREIGN KEY (account) REFERENCES accounts(account)
 );
+
+-- Upgrade a database created by an earlier version of this script.
+-- Every statement below is a no-op on a database created from scratch.
+ALTER TABLE fvks ADD COLUMN IF NOT EXISTS unified BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE fvks ADD COLUMN IF NOT EXISTS transparent_key BYTEA;
+ALTER TABLE fvks ADD COLUMN IF NOT EXISTS birthday INTEGER;
+ALTER TABLE fvks ADD COLUMN IF NOT EXISTS id_seed INTEGER;
+ALTER TABLE fvks ADD COLUMN IF NOT EXISTS key_path TEXT;
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS unified_address TEXT;
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS transparent_receiver TEXT;
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS transparent_index INTEGER;
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS birthday INTEGER;
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS id_seed INTEGER;
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS key_path TEXT;
+
+-- Payments made before the fixed fee became a column paid DEFAULT_FEE.
+-- Their expiry height is unknown, so unmined ones expire on the next update.
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS fee BIGINT NOT NULL DEFAULT 1000;
+ALTER TABLE payments ALTER COLUMN fee DROP DEFAULT;
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS expiry_height INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE payments ALTER COLUMN expiry_height DROP DEFAULT;
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS reissue_of INTEGER REFERENCES payments(id_payment);
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS updated TIMESTAMP;
+UPDATE payments SET updated = datetime WHERE updated IS NULL;
+ALTER TABLE payments ALTER COLUMN updated SET NOT NULL;
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS state TEXT;
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'payments' AND column_name = 'paid') THEN
+        UPDATE payments SET state = CASE WHEN paid THEN 'broadcast' ELSE 'prepared' END
+            WHERE state IS NULL;
+        ALTER TABLE payments DROP COLUMN paid;
+    END IF;
+END $$;
+ALTER TABLE payments ALTER COLUMN state SET NOT NULL;
+ALTER TABLE payment_outputs ADD COLUMN IF NOT EXISTS memo BYTEA;
+
+ALTER TABLE received_notes ADD COLUMN IF NOT EXISTS rseed BYTEA;
+ALTER TABLE received_notes ADD COLUMN IF NOT EXISTS zip212 BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE received_notes ADD COLUMN IF NOT EXISTS memo BYTEA;
+
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS event_type TEXT;
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'notifications' AND column_name = 'outgoing') THEN
+        UPDATE notifications SET event_type = CASE WHEN outgoing THEN 'outgoingTx' ELSE 'incomingTx' END
+            WHERE event_type IS NULL;
+        ALTER TABLE notifications DROP CONSTRAINT notification_output;
+        ALTER TABLE notifications DROP COLUMN outgoing;
+    END IF;
+END $$;
+ALTER TABLE notifications ALTER COLUMN event_type SET NOT NULL;
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS reverted BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS active BOOL NOT NULL DEFAULT TRUE;
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS confirmations INT NOT NULL DEFAULT 0;
+CREATE UNIQUE INDEX IF NOT EXISTS notification_output ON notifications(tx_hash, tx_output_index, event_type, confirmations) WHERE active AND NOT reverted;
@@ -1,8 +1,9 @@
 use clap::Clap;
 use rand::thread_rng;
-use zams::{broadcast_tx, load_checkpoint, prepare_tx, rewind_to_height, scan_chain, sign_tx, import_fvk};
+use zams::{broadcast_tx, export_checkpoint, load_checkpoint, prepare_tx, reset_to_checkpoint, rewind_to_height, scan_chain, sign_tx, import_fvk};
 use postgres::{NoTls, Client};
-use zams::{DbPreparedStatements, get_balance, import_address, generate_address, cancel_payment};
+use zams::{rescan, DbPreparedStatements, RescanKey, get_balance, import_address, import_ufvk, generate_address, cancel_payment, Keyring};
+use zams::unified::is_unified_fvk;
 use std::time::SystemTime;
 use std::sync::{Mutex, Arc};
 use zams::config::ZamsConfig;
@@ -18,15 +19,31 @@ enum Command {
     LoadCheckpoint {
         height: u32,
     },
+    ExportCheckpoint {
+        height: u32,
+    },
     Rewind {
         height: u32,
     },
+    ResetToCheckpoint {
+        height: u32,
+    },
     Scan,
     ImportFVK {
         fvk: String,
+        birthday: Option<u32>,
     },
     ImportAddress {
         address: String,
+        birthday: Option<u32>,
+    },
+    RescanFVK {
+        id_fvk: i32,
+        from_height: Option<u32>,
+    },
+    RescanAddress {
+        id_account: i32,
+        from_height: Option<u32>,
     },
     GenerateNewAddress {
         id_fvk: i32,
@@ -47,6 +64,9 @@ enum Command {
     SignTx {
         sk: String,
         unsigned_tx: String,
+        /// more keys for the inputs of other addresses
+        #[clap(long)]
+        key: Vec<String>,
     },
     BroadcastTx {
         signed_tx: String,
@@ -54,7 +74,10 @@ enum Command {
 }
 
 fn main() {
-    let config = ZamsConfig::default();
+    let config = ZamsConfig::new("zams.ini").unwrap_or_else(|e| {
+        eprintln!("{:?}", e);
+        std::process::exit(1)
+    });
     let connection = Client::connect(&config.connection_string, NoTls).unwrap();
     let c = Arc::new(Mutex::new(connection));
     let statements = DbPreparedStatements::prepare(&mut *c.lock().unwrap()).unwrap();
@@ -67,48 +90,69 @@ fn main() {
             let mut client = c.lock().unwrap();
             load_checkpoint(&mut *client, height, &config).unwrap();
         }
+        Command::ExportCheckpoint { height } => {
+            let checkpoint = export_checkpoint(height, &config).unwrap();
+            println!("{}", serde_json::to_string(&checkpoint).unwrap());
+        }
         Command::Rewind { height } => {
             rewind_to_height(c, height, &config).unwrap();
         }
+        Command::ResetToCheckpoint { height } => {
+            reset_to_checkpoint(c, height, &config).unwrap();
+        }
         Command::Scan => {
             scan_chain(c, &config).unwrap();
         }
-        Command::ImportFVK { fvk } => {
+        Command::ImportFVK { fvk, birthday } => {
             let mut client = c.lock().unwrap();
-            let id_fvk = import_fvk(&mut *client, &fvk).unwrap();
+            let id_fvk = if is_unified_fvk(&fvk) {
+                import_ufvk(&config.network, &mut *client, &fvk, birthday).unwrap()
+            } else {
+                import_fvk(&mut *client, &fvk, birthday).unwrap()
+            };
             println!("FVK {} imported as {}", fvk, id_fvk);
         }
-        Command::ImportAddress { address } => {
+        Command::ImportAddress { address, birthday } => {
             let mut client = c.lock().unwrap();
-            let id_account = import_address(&mut *client, &address).unwrap();
+            let id_account = import_address(&mut *client, &address, birthday).unwrap();
             println!("Address {} imported as {}", address, id_account);
         }
+        Command::RescanFVK { id_fvk, from_height } => {
+            let id_rescan = rescan(c, Arc::new(Mutex::new(())), RescanKey::Fvk(id_fvk), from_height, &config).unwrap();
+            println!("Rescan {} completed", id_rescan);
+        }
+        Command::RescanAddress { id_account, from_height } => {
+            let id_rescan = rescan(c, Arc::new(Mutex::new(())), RescanKey::Account(id_account), from_height, &config).unwrap();
+            println!("Rescan {} completed", id_rescan);
+        }
         Command::GenerateNewAddress {
             id_fvk,
         } => {
             let mut client = c.lock().unwrap();
-            let (id_account, addr) = generate_address(config.network, &mut *client, id_fvk).unwrap();
+            let (id_account, addr) = generate_address(&config.network, &mut *client, id_fvk).unwrap();
             println!("New account {} generated with address {}", id_account, &addr);
         }
         Command::GetBalance { account, min_confirmations } => {
             let mut client = c.lock().unwrap();
             let min_confirmations = min_confirmations.unwrap_or(1);
             let balance = get_balance(&mut *client, account, min_confirmations, &config).unwrap();
-            println!("total = {} available = {}", balance.total, balance.available);
+            println!("total = {} available = {} pending = {}", balance.total, balance.available, balance.pending);
         }
         Command::PrepareTx { from_account, to_address, change_account, amount} => {
             let mut client = c.lock().unwrap();
             let tx =
-                prepare_tx(config.network, SystemTime::now(), from_account, &to_address, change_account, amount, &mut *client, &statements, &mut rng).unwrap();
+                prepare_tx(&config.network, SystemTime::now(), from_account, &to_address, change_account, amount, &config.fee_rule, &config.coin_selector, &mut *client, &statements, &mut rng).unwrap();
             println!("{}", serde_json::to_string(&tx).unwrap());
         }
         Command::CancelTx { id } => {
             let mut client = c.lock().unwrap();
             cancel_payment(&mut *client, id).unwrap();
         }
-        Command::SignTx { sk, unsigned_tx } => {
+        Command::SignTx { sk, unsigned_tx, mut key } => {
             let unsigned_tx = serde_json::from_str(&unsigned_tx).unwrap();
-            let signed_tx = sign_tx(config.network, &sk, unsigned_tx).unwrap();
+            key.push(sk);
+            let keyring = Keyring::new(&config.network, &key).unwrap();
+            let signed_tx = sign_tx(&config.network, &keyring, unsigned_tx).unwrap();
             println!("{}", serde_json::to_string(&signed_tx).unwrap());
         }
         Command::BroadcastTx { signed_tx } => {
@@ -22,6 +22,11 @@ grpcurl -import-path proto -proto api.proto -plaintext \
 "path": "m/0'/2147483647'"}' \
 localhost:3002 zams.Signer.GenerateTransparentKey
 
+# with a keystore, import the reply with ImportPublicKey
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"id_seed": 0, "path": "m/44'"'"'/1'"'"'/0'"'"'/0/0"}' \
+localhost:3002 zams.Signer.DeriveTransparentKey
+
 {
   "pk": {
     "address": "tmJ3oV1rtGNEvV3BR6aHCfb4Gns5e4gE1mL"
@@ -43,7 +48,7 @@ grpcurl -import-path proto -proto api.proto -plaintext \
 localhost:3001 zams.BlockExplorer.BatchNewAccounts
 
 grpcurl -import-path proto -proto api.proto -plaintext \
--d '{"address": "tmJ3oV1rtGNEvV3BR6aHCfb4Gns5e4gE1mL"}' \
+-d '{"address": "tmJ3oV1rtGNEvV3BR6aHCfb4Gns5e4gE1mL", "birthday": 1410000}' \
 localhost:3001 zams.BlockExplorer.ImportPublicKey
 
 grpcurl -import-path proto -proto api.proto -plaintext \
@@ -60,6 +65,10 @@ localhost:3001 zams.BlockExplorer.ValidateAddress
 grpcurl -import-path proto -proto api.proto -plaintext \
 localhost:3001 zams.BlockExplorer.EstimateFee
 
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"transparent_inputs": 25, "sapling_outputs": 2}' \
+localhost:3001 zams.BlockExplorer.EstimateFee
+
 grpcurl -import-path proto -proto api.proto -plaintext \
 localhost:3001 zams.BlockExplorer.GetCurrentHeight
 
@@ -67,14 +76,35 @@ grpcurl -import-path proto -proto api.proto -plaintext \
 -d '{"id": 5}' \
 localhost:3001 zams.BlockExplorer.CancelTx
 
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"id": 5}' \
+localhost:3001 zams.BlockExplorer.ReissuePayment
+
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"id_fvk": 1, "max_inputs": 20}' \
+localhost:3001 zams.BlockExplorer.PrepareConsolidation > data/consolidation.json
+
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"to_account": 1, "min_balance": 100000, "min_confirmations": 10}' \
+localhost:3001 zams.BlockExplorer.PrepareSweep > data/sweep.json
+
 grpcurl -import-path proto -proto api.proto -plaintext \
 -d '{"from_account": 1, "to_address": "tmJ3oV1rtGNEvV3BR6aHCfb4Gns5e4gE1mL", "change_account": 1, "amount": 50000, "timestamp": 1623054216}' \
 localhost:3001 zams.BlockExplorer.PrepareUnsignedTx > data/payment.json
 
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"from_account": 1, "outputs": [{"address": "tmJ3oV1rtGNEvV3BR6aHCfb4Gns5e4gE1mL", "amount": 50000}, {"address": "ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn", "amount": 20000}], "change_account": 1, "timestamp": 1623054216}' \
+localhost:3001 zams.BlockExplorer.PrepareBatchTx > data/batch-payment.json
+
 cat data/payment.json | grpcurl -import-path proto -proto api.proto -plaintext \
 -d @ \
 localhost:3002 zams.Signer.SignTx
 
+# unsigned_tx is the tx of data/sweep.json
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"keys": ["877c779ad9687164e9c2f4f0f4ff0340814392330693ce95a58fe18fd52e6e93", "..."], "unsigned_tx": {...}}' \
+localhost:3002 zams.Signer.SignTx
+
 cat data/signed.json | grpcurl -import-path proto -proto api.proto -plaintext \
 -d @ \
 localhost:3001 zams.BlockExplorer.BroadcastSignedTx
@@ -87,6 +117,22 @@ grpcurl -import-path proto -proto api.proto -plaintext \
 -d '{"id": 1}' \
 localhost:3001 zams.BlockExplorer.GetPaymentInfo
 
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"hash": "cb4620db7187a7995073f4d24084e42f6f4d99f25b50083250d229127029c34e"}' \
+localhost:3001 zams.BlockExplorer.GetTxStatus
+
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"tx_hash": "cb4620db7187a7995073f4d24084e42f6f4d99f25b50083250d229127029c34e", "output_index": 0}' \
+localhost:3001 zams.BlockExplorer.GetNoteInfo
+
 grpcurl -import-path proto -proto api.proto -plaintext \
 -d '{"id": 1}' \
 localhost:3001 zams.BlockExplorer.ListPaymentId
+
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"id_fvk": 1, "from_height": 1410000}' \
+localhost:3001 zams.BlockExplorer.Rescan
+
+grpcurl -import-path proto -proto api.proto -plaintext \
+-d '{"id": 1}' \
+localhost:3001 zams.BlockExplorer.GetRescanStatus
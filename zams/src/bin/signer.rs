@@ -1,21 +1,54 @@
 use std::net::{SocketAddr, Ipv4Addr};
+use std::path::Path;
+use clap::Clap;
 use tonic::transport::Server;
 use zams::error::WalletError;
 use anyhow::anyhow;
 use tonic::{Request, Response, Status};
 use tokio::runtime::Runtime;
 
-use zams::{zams_rpc as grpc, get_bip39_seed, generate_sapling_keys, generate_transparent_address, sign_tx, ZamsConfig};
-use zams::zams_rpc::{Empty, VersionReply, Keys, Entropy, PubKey, pub_key, SignTxRequest, SignedTx};
+use zams::{zams_rpc as grpc, get_bip39_seed, generate_sapling_keys, generate_transparent_address, sign_tx, Keyring, Keystore, ZamsConfig};
+use zams::zams_rpc::{Empty, VersionReply, Keys, Entropy, KeyPath, PubKey, pub_key, SignTxRequest, SignedTx};
+
+#[derive(Clap)]
+struct CommandArgs {
+    #[clap(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Adds a new seed to the keystore, or with --restore the seed phrase read from stdin.
+    /// Creates the keystore if needed
+    AddSeed {
+        #[clap(long)]
+        restore: bool,
+    },
+    /// Prints the seed phrases of the keystore
+    Backup,
+}
 
 struct Signer {
     config: ZamsConfig,
+    keystore: Option<Keystore>,
 }
 
 impl Signer {
-    pub fn new(config: &ZamsConfig) -> Signer {
+    pub fn new(config: &ZamsConfig, keystore: Option<Keystore>) -> Signer {
         Signer {
-            config: config.clone()
+            config: config.clone(),
+            keystore,
+        }
+    }
+
+    fn keystore(&self) -> Result<&Keystore, Status> {
+        self.keystore.as_ref().ok_or_else(|| Status::failed_precondition("The signer has no keystore"))
+    }
+
+    fn check_no_keystore(&self) -> Result<(), Status> {
+        match self.keystore {
+            Some(_) => Err(Status::failed_precondition("The signer has a keystore and does not take secrets")),
+            None => Ok(()),
         }
     }
 }
@@ -29,43 +62,132 @@ impl grpc::signer_server::Signer for Signer {
     }
 
     async fn generate_transparent_key(&self, request: Request<Entropy>) -> Result<Response<Keys>, Status> {
+        self.check_no_keystore()?;
         let request = request.into_inner();
         let seed = get_bip39_seed(request.clone())?;
-        let (sk, address) = generate_transparent_address(self.config.network, seed, &request.path);
+        let (sk, address) = generate_transparent_address(&self.config.network, seed, &request.path);
         let keys = Keys {
-            pk: Some(PubKey { type_of_address: Some(pub_key::TypeOfAddress::Address(address)) }),
+            pk: Some(PubKey { type_of_address: Some(pub_key::TypeOfAddress::Address(address)), birthday: 0, key_path: None }),
             sk
         };
         Ok(Response::new(keys))
     }
 
     async fn generate_sapling_key(&self, request: Request<Entropy>) -> Result<Response<Keys>, Status> {
+        self.check_no_keystore()?;
         let request = request.into_inner();
         let seed = get_bip39_seed(request.clone())?;
-        let (sk, fvk) = generate_sapling_keys(self.config.network, seed, &request.path);
+        let (sk, fvk) = generate_sapling_keys(&self.config.network, seed, &request.path);
         let keys = Keys {
-            pk: Some(PubKey { type_of_address: Some(pub_key::TypeOfAddress::Fvk(fvk)) }),
+            pk: Some(PubKey { type_of_address: Some(pub_key::TypeOfAddress::Fvk(fvk)), birthday: 0, key_path: None }),
             sk
         };
         Ok(Response::new(keys))
     }
 
+    async fn derive_transparent_key(&self, request: Request<KeyPath>) -> Result<Response<PubKey>, Status> {
+        let key_path = request.into_inner();
+        let address = self.keystore()?.transparent_address(&self.config.network, &key_path)?;
+        Ok(Response::new(PubKey {
+            type_of_address: Some(pub_key::TypeOfAddress::Address(address)),
+            birthday: 0,
+            key_path: Some(key_path),
+        }))
+    }
+
+    async fn derive_sapling_key(&self, request: Request<KeyPath>) -> Result<Response<PubKey>, Status> {
+        let key_path = request.into_inner();
+        let fvk = self.keystore()?.sapling_fvk(&self.config.network, &key_path)?;
+        Ok(Response::new(PubKey {
+            type_of_address: Some(pub_key::TypeOfAddress::Fvk(fvk)),
+            birthday: 0,
+            key_path: Some(key_path),
+        }))
+    }
+
     async fn sign_tx(&self, request: Request<SignTxRequest>) -> Result<Response<SignedTx>, Status> {
         let request = request.into_inner();
         let unsigned_tx = request.unsigned_tx.ok_or_else(|| WalletError::Error(anyhow!("Missing unsigned tx")))?;
-        let signed_tx = sign_tx(self.config.network, &request.secret_key, unsigned_tx)?;
+        let keyring = match self.keystore {
+            Some(ref keystore) => {
+                if !request.secret_key.is_empty() || !request.keys.is_empty() {
+                    return Err(Status::invalid_argument("The signer has a keystore and does not take secret keys"));
+                }
+                keystore.keyring(&self.config.network, &unsigned_tx)?
+            }
+            None => {
+                let mut keys = request.keys;
+                if !request.secret_key.is_empty() {
+                    keys.push(request.secret_key);
+                }
+                Keyring::new(&self.config.network, &keys)?
+            }
+        };
+        let signed_tx = sign_tx(&self.config.network, &keyring, unsigned_tx)?;
         Ok(Response::new(signed_tx))
     }
 }
 
+/// From ZAMS_KEYSTORE_PASSPHRASE or else the next line of stdin
+fn read_passphrase() -> String {
+    std::env::var("ZAMS_KEYSTORE_PASSPHRASE").unwrap_or_else(|_| {
+        eprintln!("Keystore passphrase:");
+        read_line()
+    })
+}
+
+fn read_line() -> String {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    line.trim_end_matches(&['\r', '\n'][..]).to_string()
+}
+
 fn main() {
-    let config = ZamsConfig::default();
-    let port = config.port + 1;
-    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
-    let signer = Signer::new(&config);
-    let r = Runtime::new().unwrap();
-    r.block_on(Server::builder()
-        .add_service(grpc::signer_server::SignerServer::new(signer))
-        .serve(addr)
-    ).unwrap();
+    let args: CommandArgs = CommandArgs::parse();
+    let config = ZamsConfig::new("zams.ini").unwrap_or_else(|e| {
+        eprintln!("{:?}", e);
+        std::process::exit(1)
+    });
+    match args.cmd {
+        Some(Command::AddSeed { restore }) => {
+            let path = config.keystore.as_ref().expect("No keystore in the configuration");
+            let path = Path::new(path);
+            let passphrase = read_passphrase();
+            let mut keystore = if path.exists() {
+                Keystore::open(path, &passphrase)
+            } else {
+                Keystore::create(path, &passphrase)
+            }
+            .unwrap();
+            let phrase = if restore {
+                eprintln!("Seed phrase:");
+                Some(read_line())
+            } else {
+                None
+            };
+            let id_seed = keystore.add_seed(phrase.as_deref()).unwrap();
+            println!("{}", id_seed);
+        }
+        Some(Command::Backup) => {
+            let path = config.keystore.as_ref().expect("No keystore in the configuration");
+            let keystore = Keystore::open(Path::new(path), &read_passphrase()).unwrap();
+            for (id_seed, phrase) in keystore.backup().unwrap().iter().enumerate() {
+                println!("{} {}", id_seed, phrase);
+            }
+        }
+        None => {
+            let keystore = config
+                .keystore
+                .as_ref()
+                .map(|path| Keystore::open(Path::new(path), &read_passphrase()).unwrap());
+            let port = config.port + 1;
+            let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+            let signer = Signer::new(&config, keystore);
+            let r = Runtime::new().unwrap();
+            r.block_on(Server::builder()
+                .add_service(grpc::signer_server::SignerServer::new(signer))
+                .serve(addr)
+            ).unwrap();
+        }
+    }
 }
@@ -3,12 +3,17 @@ use std::net::{Ipv4Addr, SocketAddr};
 use tonic::transport::Server;
 
 use postgres::{Client, NoTls};
-use zams::{broadcast_tx, prepare_tx, scan_chain, ZamsConfig};
 use zams::{
-    cancel_payment, generate_address, get_balance, get_latest_height, get_payment_info,
-    import_address, import_fvk, list_pending_payments, rewind_to_height, DbPreparedStatements,
+    broadcast_tx, prepare_batch_tx, prepare_consolidation, prepare_sweep, reissue_payment, scan_chain, CoinSelector,
+    ConsolidationSource, TxShape, ZamsConfig,
 };
-use zams::{register_custom_metrics, metrics_handler, REQUESTS};
+use zams::{
+    cancel_payment, fail_interrupted_rescans, generate_address, get_balance, get_latest_height, get_note_info,
+    get_payment_info, get_rescan_status, get_tx_status, import_address, import_fvk, import_ufvk,
+    list_pending_payments, rewind_to_height, set_account_key_path, set_fvk_key_path, start_rescan, DbPreparedStatements,
+    RescanKey,
+};
+use zams::{register_custom_metrics, metrics_handler, start_mempool_watcher, start_sync_scheduler, REQUESTS};
 use std::sync::{Arc, Mutex};
 use tonic::{Request, Response, Status};
 
@@ -20,20 +25,19 @@ use tokio::task::block_in_place;
 use chrono::{DateTime, Local};
 use flexi_logger::{Age, Cleanup, Criterion, Logger, Naming};
 use zams::zams_rpc as grpc;
+use zams::unified::{decode_recipient, decode_unified_address, is_unified_fvk};
 use zcash_client_backend::address::RecipientAddress;
-use zcash_primitives::transaction::components::amount::DEFAULT_FEE;
 use warp::Filter;
 
 struct ZAMS {
     config: ZamsConfig,
     client: Arc<Mutex<Client>>,
     statements: DbPreparedStatements,
-    data_mutex: Mutex<()>,
+    data_mutex: Arc<Mutex<()>>,
 }
 
 impl ZAMS {
-    pub fn new() -> ZAMS {
-        let config = ZamsConfig::default();
+    pub fn new(config: ZamsConfig) -> ZAMS {
         let connection = Client::connect(&config.connection_string, NoTls).unwrap();
         let client = Arc::new(Mutex::new(connection));
         let statements = {
@@ -44,7 +48,15 @@ impl ZAMS {
             config,
             client,
             statements,
-            data_mutex: Mutex::new(()),
+            data_mutex: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The coin selection of the request, or the one of the config
+    fn coin_selector(&self, coin_selection: &str) -> Result<CoinSelector, WalletError> {
+        match coin_selection {
+            "" => Ok(self.config.coin_selector),
+            s => s.parse(),
         }
     }
 }
@@ -63,10 +75,26 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
     async fn validate_address(
         &self,
         request: Request<grpc::ValidateAddressRequest>,
-    ) -> Result<Response<grpc::Boolean>, Status> {
+    ) -> Result<Response<grpc::AddressInfo>, Status> {
         let request = request.into_inner();
-        let valid = RecipientAddress::decode(self.config.network, &request.address).is_some();
-        let rep = grpc::Boolean { value: valid };
+        let network = &self.config.network;
+        let rep = match decode_unified_address(network, &request.address) {
+            Ok(ua) => grpc::AddressInfo {
+                value: decode_recipient(network, &request.address).is_some(),
+                receivers: ua
+                    .receivers
+                    .iter()
+                    .map(|r| grpc::Receiver {
+                        r#type: r.type_name().to_string(),
+                        address: r.to_recipient().map(|r| r.encode(network)).unwrap_or_default(),
+                    })
+                    .collect(),
+            },
+            Err(_) => grpc::AddressInfo {
+                value: RecipientAddress::decode(network, &request.address).is_some(),
+                receivers: vec![],
+            },
+        };
         Ok(Response::new(rep))
     }
 
@@ -93,16 +121,49 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
         request: Request<grpc::PrepareUnsignedTxRequest>,
     ) -> Result<Response<grpc::UnsignedTx>, Status> {
         let request = request.into_inner();
+        let selector = self.coin_selector(&request.coin_selection)?;
         let unsigned_tx = block_in_place(|| {
             let mut client = self.client.lock().unwrap();
             let datetime = SystemTime::UNIX_EPOCH + Duration::from_secs(request.timestamp);
-            prepare_tx(
-                self.config.network,
+            let output = grpc::PaymentOutput {
+                address: request.to_address,
+                amount: request.amount,
+                memo: request.memo,
+                memo_hex: request.memo_hex,
+            };
+            prepare_batch_tx(
+                &self.config.network,
                 datetime,
                 request.from_account,
-                &request.to_address,
+                &[output],
                 request.change_account,
-                request.amount as i64,
+                &self.config.fee_rule,
+                &selector,
+                &mut *client,
+                &self.statements,
+                &mut OsRng,
+            )
+        })?;
+        Ok(Response::new(unsigned_tx))
+    }
+
+    async fn prepare_batch_tx(
+        &self,
+        request: Request<grpc::PrepareBatchTxRequest>,
+    ) -> Result<Response<grpc::UnsignedTx>, Status> {
+        let request = request.into_inner();
+        let selector = self.coin_selector(&request.coin_selection)?;
+        let unsigned_tx = block_in_place(|| {
+            let mut client = self.client.lock().unwrap();
+            let datetime = SystemTime::UNIX_EPOCH + Duration::from_secs(request.timestamp);
+            prepare_batch_tx(
+                &self.config.network,
+                datetime,
+                request.from_account,
+                &request.outputs,
+                request.change_account,
+                &self.config.fee_rule,
+                &selector,
                 &mut *client,
                 &self.statements,
                 &mut OsRng,
@@ -123,6 +184,71 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
         Ok(Response::new(grpc::Empty {}))
     }
 
+    async fn reissue_payment(
+        &self,
+        request: Request<grpc::PaymentId>,
+    ) -> Result<Response<grpc::UnsignedTx>, Status> {
+        let request = request.into_inner();
+        let unsigned_tx = block_in_place(|| {
+            let mut client = self.client.lock().unwrap();
+            reissue_payment(
+                &self.config.network,
+                request.id,
+                &self.config.fee_rule,
+                &self.config.coin_selector,
+                &mut *client,
+                &self.statements,
+                &mut OsRng,
+            )
+        })?;
+        Ok(Response::new(unsigned_tx))
+    }
+
+    async fn prepare_consolidation(
+        &self,
+        request: Request<grpc::ConsolidationRequest>,
+    ) -> Result<Response<grpc::Consolidation>, Status> {
+        let request = request.into_inner();
+        let source = match request.source {
+            Some(grpc::consolidation_request::Source::IdAccount(id_account)) => ConsolidationSource::Account(id_account),
+            Some(grpc::consolidation_request::Source::IdFvk(id_fvk)) => ConsolidationSource::Fvk(id_fvk),
+            None => return Err(Status::invalid_argument("Missing source")),
+        };
+        let consolidation = block_in_place(|| {
+            let mut client = self.client.lock().unwrap();
+            prepare_consolidation(
+                &self.config.network,
+                &source,
+                request.max_inputs as usize,
+                &self.config.fee_rule,
+                &mut *client,
+                &self.statements,
+            )
+        })?;
+        Ok(Response::new(consolidation))
+    }
+
+    async fn prepare_sweep(
+        &self,
+        request: Request<grpc::SweepRequest>,
+    ) -> Result<Response<grpc::Sweep>, Status> {
+        let request = request.into_inner();
+        let sweep = block_in_place(|| {
+            let mut client = self.client.lock().unwrap();
+            prepare_sweep(
+                &self.config.network,
+                &request.from_accounts,
+                request.to_account,
+                request.min_balance,
+                request.min_confirmations,
+                request.max_inputs as usize,
+                &self.config.fee_rule,
+                &mut *client,
+            )
+        })?;
+        Ok(Response::new(sweep))
+    }
+
     async fn list_pending_payments(
         &self,
         request: Request<grpc::AccountId>,
@@ -148,6 +274,30 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
         Ok(Response::new(payment))
     }
 
+    async fn get_tx_status(
+        &self,
+        request: Request<grpc::TxId>,
+    ) -> Result<Response<grpc::TxStatus>, Status> {
+        let request = request.into_inner();
+        let status = block_in_place(|| {
+            let mut client = self.client.lock().unwrap();
+            get_tx_status(&mut *client, &request.hash)
+        })?;
+        Ok(Response::new(status))
+    }
+
+    async fn get_note_info(
+        &self,
+        request: Request<grpc::OutputRef>,
+    ) -> Result<Response<grpc::ReceivedNote>, Status> {
+        let request = request.into_inner();
+        let note = block_in_place(|| {
+            let mut client = self.client.lock().unwrap();
+            get_note_info(&mut *client, &request.tx_hash, request.output_index)
+        })?;
+        Ok(Response::new(note))
+    }
+
     async fn broadcast_signed_tx(
         &self,
         request: Request<grpc::SignedTx>,
@@ -163,10 +313,25 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
 
     async fn estimate_fee(
         &self,
-        _request: Request<grpc::EstimateFeeRequest>,
+        request: Request<grpc::EstimateFeeRequest>,
     ) -> Result<Response<grpc::Fee>, Status> {
+        let request = request.into_inner();
+        let mut shape = TxShape {
+            transparent_inputs: request.transparent_inputs as usize,
+            transparent_outputs: request.transparent_outputs as usize,
+            sapling_spends: request.sapling_spends as usize,
+            sapling_outputs: request.sapling_outputs as usize,
+        };
+        // a payment from a single note or utxo, with its change
+        if shape == TxShape::default() {
+            shape = if request.shielded {
+                TxShape { sapling_spends: 1, sapling_outputs: 2, ..shape }
+            } else {
+                TxShape { transparent_inputs: 1, transparent_outputs: 1, sapling_outputs: 1, ..shape }
+            };
+        }
         let fee = grpc::Fee {
-            amount: u64::from(DEFAULT_FEE),
+            amount: u64::from(self.config.fee_rule.fee(&shape)),
             perkb: false,
         };
         Ok(Response::new(fee))
@@ -204,15 +369,30 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
         request: Request<grpc::PubKey>,
     ) -> Result<Response<grpc::PubKeyId>, Status> {
         let request = request.into_inner();
+        let birthday = Some(request.birthday).filter(|&h| h != 0);
+        let key_path = request.key_path;
         let id_fvk = block_in_place(|| {
             let mut client = self.client.lock().unwrap();
             match request.type_of_address {
                 Some(grpc::pub_key::TypeOfAddress::Address(address)) => {
-                    let id_account = import_address(&mut *client, &address).unwrap();
+                    let id_account = import_address(&mut *client, &address, birthday).unwrap();
+                    if let Some(key_path) = key_path {
+                        set_account_key_path(&mut *client, id_account, &key_path)?;
+                    }
                     Ok(id_account)
                 }
+                Some(grpc::pub_key::TypeOfAddress::Fvk(fvk)) if is_unified_fvk(&fvk) => {
+                    let id_fvk = import_ufvk(&self.config.network, &mut *client, &fvk, birthday)?;
+                    if let Some(key_path) = key_path {
+                        set_fvk_key_path(&mut *client, id_fvk, &key_path)?;
+                    }
+                    Ok(id_fvk)
+                }
                 Some(grpc::pub_key::TypeOfAddress::Fvk(fvk)) => {
-                    let id_fvk = import_fvk(&mut *client, &fvk).unwrap();
+                    let id_fvk = import_fvk(&mut *client, &fvk, birthday).unwrap();
+                    if let Some(key_path) = key_path {
+                        set_fvk_key_path(&mut *client, id_fvk, &key_path)?;
+                    }
                     Ok(id_fvk)
                 }
                 _ => Err(WalletError::Error(anyhow::anyhow!("Invalid address type"))),
@@ -230,7 +410,7 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
         let account = block_in_place(|| {
             let mut client = self.client.lock().unwrap();
             let (id_account, address) = generate_address(
-                self.config.network,
+                &self.config.network,
                 &mut *client,
                 request.id
             )?;
@@ -242,6 +422,35 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
         Ok(Response::new(account))
     }
 
+    async fn rescan(
+        &self,
+        request: Request<grpc::RescanRequest>,
+    ) -> Result<Response<grpc::RescanId>, Status> {
+        let request = request.into_inner();
+        let key = match request.key {
+            Some(grpc::rescan_request::Key::IdFvk(id_fvk)) => RescanKey::Fvk(id_fvk),
+            Some(grpc::rescan_request::Key::IdAccount(id_account)) => RescanKey::Account(id_account),
+            None => return Err(Status::invalid_argument("Missing key")),
+        };
+        let from_height = Some(request.from_height).filter(|&h| h != 0);
+        let id = block_in_place(|| {
+            start_rescan(self.client.clone(), self.data_mutex.clone(), key, from_height, &self.config)
+        })?;
+        Ok(Response::new(grpc::RescanId { id }))
+    }
+
+    async fn get_rescan_status(
+        &self,
+        request: Request<grpc::RescanId>,
+    ) -> Result<Response<grpc::RescanStatus>, Status> {
+        let request = request.into_inner();
+        let status = block_in_place(|| {
+            let mut client = self.client.lock().unwrap();
+            get_rescan_status(&mut *client, request.id)
+        })?;
+        Ok(Response::new(status))
+    }
+
     async fn batch_new_accounts(
         &self,
         request: Request<grpc::BatchNewAccountsRequest>,
@@ -252,7 +461,7 @@ impl grpc::block_explorer_server::BlockExplorer for ZAMS {
             let mut client = self.client.lock().unwrap();
             for _ in 0..count {
                 generate_address(
-                    self.config.network,
+                    &self.config.network,
                     &mut *client,
                     request.id_pubkey
                 )
@@ -287,13 +496,27 @@ fn main() {
     let now = SystemTime::now();
     let dt: DateTime<Local> = now.into();
     log::info!("ZAMS started on {}", dt.to_rfc2822());
-    let config = ZamsConfig::default();
+    let config = ZamsConfig::new("zams.ini").unwrap_or_else(|e| {
+        log::error!("{:?}", e);
+        std::process::exit(1)
+    });
     let port = config.port;
     let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
-    let explorer = ZAMS::new();
+    let explorer = ZAMS::new(config.clone());
     let r = Runtime::new().unwrap();
+    fail_interrupted_rescans(&mut *explorer.client.lock().unwrap()).unwrap();
+
+    let sync_trigger = start_sync_scheduler(explorer.client.clone(), explorer.data_mutex.clone(), &config);
+    let sync_trigger = Arc::new(Mutex::new(sync_trigger));
+    start_mempool_watcher(explorer.client.clone(), explorer.data_mutex.clone(), &config);
+    // zcashd -blocknotify="curl -s http://127.0.0.1:<port+10>/blocknotify/%s"
+    let blocknotify_route = warp::path!("blocknotify" / String).map(move |hash: String| {
+        log::info!("Block notification {}", hash);
+        let _ = sync_trigger.lock().unwrap().send(());
+        "OK"
+    });
 
-    r.spawn(warp::serve(metrics_route).run(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port + 10)));
+    r.spawn(warp::serve(metrics_route.or(blocknotify_route)).run(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port + 10)));
 
     r.block_on(
         Server::builder()
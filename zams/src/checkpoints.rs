@@ -0,0 +1,70 @@
+use crate::network::ZamsNetwork;
+use crate::trp::zcashdrpc::TreeState;
+use serde::{Deserialize, Serialize};
+
+/// Sapling tree state at a given height, bundled with ZAMS for when the node
+/// cannot give the tree state at the wallet birthday.
+/// New entries come from `cli export-checkpoint <height>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub hash: String,
+    pub time: u32,
+    pub tree: String,
+}
+
+impl Checkpoint {
+    pub fn new(height: u32, tree_state: TreeState) -> Checkpoint {
+        Checkpoint {
+            height,
+            hash: tree_state.hash,
+            time: tree_state.time,
+            tree: tree_state.tree,
+        }
+    }
+}
+
+/// Checkpoints of mainnet and testnet. Regtest and custom networks have none
+pub fn bundled_checkpoints(network: &ZamsNetwork) -> Vec<Checkpoint> {
+    let checkpoints = match network.name.as_str() {
+        "main" => include_str!("../checkpoints/main.json"),
+        "test" => include_str!("../checkpoints/test.json"),
+        _ => return vec![],
+    };
+    serde_json::from_str(checkpoints).expect("Invalid bundled checkpoints")
+}
+
+/// The highest checkpoint at or below `height`
+pub fn find_checkpoint(checkpoints: &[Checkpoint], height: u32) -> Option<&Checkpoint> {
+    checkpoints
+        .iter()
+        .filter(|cp| cp.height <= height)
+        .max_by_key(|cp| cp.height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_checkpoint() {
+        for network in [ZamsNetwork::main(), ZamsNetwork::test()].iter() {
+            bundled_checkpoints(network);
+        }
+        let mainnet = bundled_checkpoints(&ZamsNetwork::main());
+        assert_eq!(find_checkpoint(&mainnet, 1_000_000).map(|cp| cp.height), Some(419_200));
+        assert!(find_checkpoint(&mainnet, 419_199).is_none());
+        let checkpoints: Vec<Checkpoint> = [1_000_000, 1_200_000, 1_100_000]
+            .iter()
+            .map(|&height| Checkpoint {
+                height,
+                hash: String::new(),
+                time: 0,
+                tree: "000000".to_string(),
+            })
+            .collect();
+        assert_eq!(find_checkpoint(&checkpoints, 1_150_000).map(|cp| cp.height), Some(1_100_000));
+        assert_eq!(find_checkpoint(&checkpoints, 1_200_000).map(|cp| cp.height), Some(1_200_000));
+        assert!(find_checkpoint(&checkpoints, 999_999).is_none());
+    }
+}
@@ -1,30 +1,84 @@
+use anyhow::anyhow;
 use configparser::ini::Ini;
-use zcash_primitives::consensus::Network::{self, TestNetwork, MainNetwork};
+use std::collections::HashMap;
+use crate::error::WalletError;
+use crate::network::ZamsNetwork;
+use crate::wallet::coin_selector::CoinSelector;
+use crate::wallet::fee::FeeRule;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockSourceType {
+    Zcashd,
+    Lightwalletd(String),
+}
 
 #[derive(Debug, Clone)]
 pub struct ZamsConfig {
-    pub network: &'static Network,
+    pub network: ZamsNetwork,
     pub zcashd: String,
     pub rpc_user: String,
     pub rpc_password: String,
     pub port: u16,
     pub connection_string: String,
     pub notification_url: String,
+    pub block_source: BlockSourceType,
+    pub max_inflight_requests: usize,
+    pub sync_interval: u64,
+    pub sync_max_backoff: u64,
+    pub zmq_hashblock: Option<String>,
+    pub mempool_interval: u64,
+    /// Number of confirmations at which to send a `confirmed` event, by event type
+    pub confirmation_thresholds: HashMap<String, Vec<u32>>,
+    /// Number of confirmations after which a mined payment is `confirmed`
+    pub payment_confirmations: u32,
+    pub fee_rule: FeeRule,
+    /// Used when the request does not choose one
+    pub coin_selector: CoinSelector,
+    /// Encrypted seeds of the signer. Without it, the signer takes the secret keys in the requests
+    pub keystore: Option<String>,
 }
 
 impl ZamsConfig {
-    pub fn new(config_path: &str) -> ZamsConfig {
+    /// Fails with the name of the first missing or invalid option
+    pub fn new(config_path: &str) -> crate::Result<ZamsConfig> {
         let mut conf = Ini::new();
-        conf.load(config_path).unwrap();
-        let zcashd = conf.get("zams", "zcashd").unwrap();
-        let rpc_user = conf.get("zams", "rpcuser").unwrap();
-        let rpc_password = conf.get("zams", "rpcpassword").unwrap();
-        let port = conf.getuint("zams", "port").unwrap().unwrap() as u16;
-        let connection_string = conf.get("zams", "connection_string").unwrap();
-        let testnet = conf.getbool("zams", "testnet").unwrap().unwrap_or(false);
-        let network = if testnet { &TestNetwork } else { &MainNetwork };
-        let notification_url = conf.get("zams", "notification_url").unwrap();
-        ZamsConfig {
+        conf.load(config_path)
+            .map_err(|e| WalletError::Error(anyhow!("Cannot load {}: {}", config_path, e)))?;
+        let zcashd = required(&conf, "zcashd")?;
+        let rpc_user = required(&conf, "rpcuser")?;
+        let rpc_password = required(&conf, "rpcpassword")?;
+        let port = get_uint(&conf, "port")?.ok_or_else(|| config_error("port", "is required"))? as u16;
+        let connection_string = required(&conf, "connection_string")?;
+        let network = ZamsNetwork::from_config(&conf)?;
+        let notification_url = required(&conf, "notification_url")?;
+        let block_source = match conf.get("zams", "block_source").as_deref() {
+            None | Some("zcashd") => BlockSourceType::Zcashd,
+            Some("lightwalletd") => BlockSourceType::Lightwalletd(required(&conf, "lightwalletd")?),
+            Some(other) => return Err(config_error("block_source", &format!("unknown block source {}", other))),
+        };
+        let max_inflight_requests = get_uint(&conf, "max_inflight_requests")?.unwrap_or(16) as usize;
+        let sync_interval = get_uint(&conf, "sync_interval")?.unwrap_or(60);
+        let sync_max_backoff = get_uint(&conf, "sync_max_backoff")?.unwrap_or(300);
+        let zmq_hashblock = conf.get("zams", "zmq_hashblock");
+        let mempool_interval = get_uint(&conf, "mempool_interval")?.unwrap_or(10);
+        let mut confirmation_thresholds: HashMap<String, Vec<u32>> = HashMap::new();
+        confirmation_thresholds.insert("incomingTx".to_string(), parse_thresholds(&conf, "incoming_confirmations")?);
+        confirmation_thresholds.insert("outgoingTx".to_string(), parse_thresholds(&conf, "outgoing_confirmations")?);
+        let payment_confirmations = get_uint(&conf, "payment_confirmations")?.unwrap_or(6) as u32;
+        let fee_rule = conf
+            .get("zams", "fee_rule")
+            .as_deref()
+            .unwrap_or("fixed")
+            .parse::<FeeRule>()
+            .map_err(|e| config_error("fee_rule", &format!("{:?}", e)))?;
+        let coin_selector = conf
+            .get("zams", "coin_selection")
+            .as_deref()
+            .unwrap_or("random")
+            .parse::<CoinSelector>()
+            .map_err(|e| config_error("coin_selection", &format!("{:?}", e)))?;
+        let keystore = conf.get("zams", "keystore").filter(|k| !k.is_empty());
+        Ok(ZamsConfig {
             network,
             zcashd,
             rpc_user,
@@ -32,12 +86,52 @@ impl ZamsConfig {
             port,
             connection_string,
             notification_url,
-        }
+            block_source,
+            max_inflight_requests,
+            sync_interval,
+            sync_max_backoff,
+            zmq_hashblock,
+            mempool_interval,
+            confirmation_thresholds,
+            payment_confirmations,
+            fee_rule,
+            coin_selector,
+            keystore,
+        })
     }
 }
 
+pub(crate) fn config_error(key: &str, error: &str) -> WalletError {
+    WalletError::Error(anyhow!("Invalid configuration option {}: {}", key, error))
+}
+
+/// An option of the [zams] section that has no default
+pub(crate) fn required(conf: &Ini, key: &str) -> crate::Result<String> {
+    conf.get("zams", key).ok_or_else(|| config_error(key, "is required"))
+}
+
+pub(crate) fn get_uint(conf: &Ini, key: &str) -> crate::Result<Option<u64>> {
+    conf.getuint("zams", key).map_err(|e| config_error(key, &e))
+}
+
+// A comma separated list of confirmation counts, e.g. 1,6,24
+fn parse_thresholds(conf: &Ini, key: &str) -> crate::Result<Vec<u32>> {
+    let mut thresholds: Vec<u32> = conf
+        .get("zams", key)
+        .unwrap_or_default()
+        .split(',')
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.parse::<u32>().map_err(|_| config_error(key, &format!("{} is not a number", t))))
+        .collect::<crate::Result<Vec<_>>>()?;
+    thresholds.retain(|&t| t > 0);
+    thresholds.sort_unstable();
+    thresholds.dedup();
+    Ok(thresholds)
+}
+
 impl Default for ZamsConfig {
     fn default() -> Self {
-        ZamsConfig::new("zams.ini")
+        ZamsConfig::new("zams.ini").unwrap()
     }
 }
@@ -1,23 +1,37 @@
 use crate::{db, ZamsConfig};
 use crate::error::WalletError;
+use crate::wallet::memo::memo_to_string;
+use crate::wallet::rescan::RescanKey;
 use crate::wallet::to_spendable_note;
 use crate::wallet::transaction::{Account, SpendableNoteWithId};
 use anyhow::anyhow;
 use postgres::{Client, GenericClient, Statement};
 
 use std::cmp;
+use std::collections::HashMap;
+use std::convert::TryInto;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 use zcash_client_backend::data_api::wallet::ANCHOR_OFFSET;
-use zcash_client_backend::encoding::{decode_extended_full_viewing_key, encode_payment_address};
+use zcash_client_backend::encoding::{
+    decode_extended_full_viewing_key, encode_extended_full_viewing_key, encode_payment_address,
+    encode_transparent_address,
+};
 
 use zcash_primitives::consensus::{BlockHeight, Parameters};
+use zcash_primitives::legacy::TransparentAddress;
 use zcash_primitives::zip32::DiversifierIndex;
 
 use crate::zams_rpc as grpc;
 use crate::trp::zcashdrpc::get_latest_height;
 use crate::perfcounters::ACCOUNTS;
+use crate::trp::Utxo;
 use crate::notification::NotificationRecord;
+use crate::payment::PaymentState;
+use crate::network::UnifiedParameters;
+use crate::unified::{
+    decode_unified_full_viewing_key, derive_p2pkh_receiver, encode_unified_address, Receiver, UnifiedAddress,
+};
 
 pub struct DbPreparedStatements {
     pub stmt_select_sapling_notes: Statement,
@@ -29,7 +43,7 @@ impl DbPreparedStatements {
     pub fn prepare(c: &mut Client) -> crate::Result<DbPreparedStatements> {
         Ok(DbPreparedStatements {
             stmt_select_sapling_notes: c.prepare(
-                "SELECT id_note, diversifier, value, rcm, witness
+                "SELECT id_note, diversifier, value, rcm, witness, rseed, zip212
                 FROM received_notes
                 INNER JOIN transactions ON transactions.id_tx = received_notes.tx
                 INNER JOIN sapling_witnesses ON sapling_witnesses.note = received_notes.id_note
@@ -71,39 +85,155 @@ pub fn load_checkpoint<C: GenericClient>(
     Ok(())
 }
 
-pub fn import_fvk<C: GenericClient>(c: &mut C, fvk: &str) -> crate::Result<i32> {
+/// Imports a Sapling viewing key. `birthday` is the height of its first transaction, if known.
+/// A key without birthday is scanned from the Sapling activation. Importing a key
+/// again keeps the earliest birthday, LEAST ignores a missing one
+pub fn import_fvk<C: GenericClient>(c: &mut C, fvk: &str, birthday: Option<u32>) -> crate::Result<i32> {
+    let birthday = birthday.map(|h| h as i32);
     let row = c.query_one(
-        "INSERT INTO fvks(extfvk, diversifier_low, diversifier_high) VALUES ($1, 0, 0)
+        "INSERT INTO fvks(extfvk, diversifier_low, diversifier_high, birthday) VALUES ($1, 0, 0, $2)
             ON CONFLICT (extfvk) DO UPDATE SET
-            extfvk = excluded.extfvk
+            birthday = LEAST(fvks.birthday, excluded.birthday)
             RETURNING id_fvk",
-        &[&fvk],
+        &[&fvk, &birthday],
     )?;
     let id_fvk: i32 = row.get(0);
     Ok(id_fvk)
 }
 
-pub fn import_address<C: GenericClient>(c: &mut C, address: &str) -> crate::Result<i32> {
+/// Imports the Sapling and transparent keys of a unified full viewing key.
+/// Accounts derived from it get unified addresses
+pub fn import_ufvk<P: UnifiedParameters, C: GenericClient>(
+    network: &P,
+    c: &mut C,
+    ufvk: &str,
+    birthday: Option<u32>,
+) -> crate::Result<i32> {
+    let ufvk = decode_unified_full_viewing_key(network, ufvk)?;
+    let fvk = encode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), &ufvk.sapling);
+    let transparent_key = ufvk.transparent.map(|k| k.to_vec());
+    let birthday = birthday.map(|h| h as i32);
+    let row = c.query_one(
+        "INSERT INTO fvks(extfvk, diversifier_low, diversifier_high, unified, transparent_key, birthday)
+            VALUES ($1, 0, 0, TRUE, $2, $3)
+            ON CONFLICT (extfvk) DO UPDATE SET
+            unified = TRUE,
+            transparent_key = excluded.transparent_key,
+            birthday = LEAST(fvks.birthday, excluded.birthday)
+            RETURNING id_fvk",
+        &[&fvk, &transparent_key, &birthday],
+    )?;
+    let id_fvk: i32 = row.get(0);
+    Ok(id_fvk)
+}
+
+pub fn import_address<C: GenericClient>(c: &mut C, address: &str, birthday: Option<u32>) -> crate::Result<i32> {
+    let birthday = birthday.map(|h| h as i32);
     let row = c.query_one(
-        "INSERT INTO accounts(fvk, address) VALUES (NULL, $1)
+        "INSERT INTO accounts(fvk, address, birthday) VALUES (NULL, $1, $2)
             ON CONFLICT (address) DO UPDATE SET
-            address = excluded.address
+            birthday = LEAST(accounts.birthday, excluded.birthday)
             RETURNING account",
-        &[&address],
+        &[&address, &birthday],
     )?;
     let account: i32 = row.get(0);
     Ok(account)
 }
 
-pub fn generate_address<P: Parameters, C: GenericClient>(
+/// Records where the signer keystore derives the key of an imported viewing key
+pub fn set_fvk_key_path<C: GenericClient>(c: &mut C, id_fvk: i32, key_path: &grpc::KeyPath) -> crate::Result<()> {
+    c.execute(
+        "UPDATE fvks SET id_seed = $1, key_path = $2 WHERE id_fvk = $3",
+        &[&(key_path.id_seed as i32), &key_path.path, &id_fvk],
+    )?;
+    Ok(())
+}
+
+/// Records where the signer keystore derives the key of an imported transparent address
+pub fn set_account_key_path<C: GenericClient>(c: &mut C, id_account: i32, key_path: &grpc::KeyPath) -> crate::Result<()> {
+    c.execute(
+        "UPDATE accounts SET id_seed = $1, key_path = $2 WHERE account = $3",
+        &[&(key_path.id_seed as i32), &key_path.path, &id_account],
+    )?;
+    Ok(())
+}
+
+/// The key path of an address, from its viewing key for shielded addresses
+pub fn get_key_path<C: GenericClient>(c: &mut C, address: &str) -> crate::Result<Option<grpc::KeyPath>> {
+    let row = c.query_opt(
+        "SELECT COALESCE(f.id_seed, a.id_seed), COALESCE(f.key_path, a.key_path)
+        FROM accounts a LEFT JOIN fvks f ON a.fvk = f.id_fvk WHERE a.address = $1",
+        &[&address],
+    )?;
+    let key_path = match row {
+        Some(row) => {
+            let id_seed: Option<i32> = row.get(0);
+            let path: Option<String> = row.get(1);
+            id_seed.zip(path)
+        }
+        None => get_transparent_receiver_key_path(c, address)?,
+    };
+    Ok(key_path.map(|(id_seed, path)| grpc::KeyPath {
+        id_seed: id_seed as u32,
+        path,
+    }))
+}
+
+/// The transparent receiver of a unified address is at m/44'/coin'/account'/0/index (ZIP-316)
+/// when its viewing key is at m/32'/coin'/account'
+fn get_transparent_receiver_key_path<C: GenericClient>(c: &mut C, address: &str) -> crate::Result<Option<(i32, String)>> {
+    let row = c.query_opt(
+        "SELECT f.id_seed, f.key_path, a.transparent_index
+        FROM accounts a JOIN fvks f ON a.fvk = f.id_fvk WHERE a.transparent_receiver = $1",
+        &[&address],
+    )?;
+    Ok(row.and_then(|row| {
+        let id_seed: Option<i32> = row.get(0);
+        let path: Option<String> = row.get(1);
+        let index: Option<i32> = row.get(2);
+        let path = path?;
+        let account_path = path.strip_prefix("m/32'/")?;
+        Some((id_seed?, format!("m/44'/{}/0/{}", account_path, index?)))
+    }))
+}
+
+/// Earliest birthday of the imported keys and addresses (the accounts of a viewing key
+/// share its birthday). None if there is no key yet or some key has no birthday
+pub fn get_wallet_birthday<C: GenericClient>(c: &mut C) -> crate::Result<Option<u32>> {
+    let row = c.query_one(
+        "SELECT COUNT(*), COUNT(birthday), MIN(birthday) FROM
+            (SELECT birthday FROM fvks UNION ALL
+            SELECT birthday FROM accounts WHERE fvk IS NULL) AS k",
+        &[],
+    )?;
+    let keys: i64 = row.get(0);
+    let with_birthday: i64 = row.get(1);
+    let birthday: Option<i32> = row.get(2);
+    if keys == 0 || with_birthday < keys {
+        return Ok(None);
+    }
+    Ok(birthday.map(|h| h as u32))
+}
+
+pub fn has_keys<C: GenericClient>(c: &mut C) -> crate::Result<bool> {
+    let row = c.query_one(
+        "SELECT EXISTS(SELECT 1 FROM fvks) OR EXISTS(SELECT 1 FROM accounts WHERE fvk IS NULL)",
+        &[],
+    )?;
+    Ok(row.get(0))
+}
+
+pub fn generate_address<P: UnifiedParameters, C: GenericClient>(
     network: &P,
     c: &mut C,
     id_fvk: i32
 ) -> std::result::Result<(i32, String), WalletError> {
-    let row = c.query_one("SELECT extfvk, diversifier_low, diversifier_high FROM fvks WHERE id_fvk = $1", &[&id_fvk])?;
+    let row = c.query_one("SELECT extfvk, diversifier_low, diversifier_high, unified, transparent_key FROM fvks WHERE id_fvk = $1", &[&id_fvk])?;
     let key: String = row.get(0);
     let di_low: u128 = row.get::<_, i64>(1) as u128;
     let di_high: u128 = row.get::<_, i64>(2) as u128;
+    let unified: bool = row.get(3);
+    let transparent_key: Option<Vec<u8>> = row.get(4);
     let diversifier_index = di_high << 64 | di_low;
     let fvk = decode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), &key)
         .map_err(WalletError::Bech32)?
@@ -122,19 +252,45 @@ pub fn generate_address<P: Parameters, C: GenericClient>(
     let di_low = diversifier_index_out as i64;
     let di_high = (diversifier_index_out >> 64) as i64;
 
+
+    // The receivers of a unified address share the diversifier index
+    let (unified_address, transparent_receiver, transparent_index) = if unified {
+        let mut receivers = vec![Receiver::Sapling(pa.to_bytes())];
+        let mut transparent_receiver: Option<String> = None;
+        let mut transparent_index: Option<i32> = None;
+        if let Some(transparent_key) = transparent_key {
+            let transparent_key: [u8; 65] = transparent_key.try_into().map_err(|_| anyhow!("Invalid transparent key"))?;
+            let index: u32 = diversifier_index_out
+                .try_into()
+                .map_err(|_| anyhow!("Diversifier index too large for a transparent receiver"))?;
+            let hash = derive_p2pkh_receiver(&transparent_key, index)?;
+            transparent_receiver = Some(encode_transparent_address(
+                &network.b58_pubkey_address_prefix(),
+                &network.b58_script_address_prefix(),
+                &TransparentAddress::PublicKey(hash),
+            ));
+            transparent_index = Some(index as i32);
+            receivers.push(Receiver::P2pkh(hash));
+        }
+        let ua = encode_unified_address(network, &UnifiedAddress { receivers })?;
+        (Some(ua), transparent_receiver, transparent_index)
+    } else {
+        (None, None, None)
+    };
+
     c.execute("UPDATE fvks SET diversifier_low = $1, diversifier_high = $2 WHERE id_fvk = $3", &[&di_low, &di_high, &id_fvk])?;
     let row = c.query_one(
-        "INSERT INTO accounts(fvk, address)
-            VALUES ($1, $2)
+        "INSERT INTO accounts(fvk, address, unified_address, transparent_receiver, transparent_index)
+            VALUES ($1, $2, $3, $4, $5)
             ON CONFLICT (address) DO UPDATE SET
             fvk = excluded.fvk RETURNING account",
-        &[&id_fvk, &address],
+        &[&id_fvk, &address, &unified_address, &transparent_receiver, &transparent_index],
     )?;
     let account: i32 = row.get(0);
 
     ACCOUNTS.inc();
 
-    Ok((account, address))
+    Ok((account, unified_address.unwrap_or(address)))
 }
 
 pub fn get_spendable_notes_by_address<C: GenericClient>(
@@ -174,12 +330,53 @@ pub fn get_spendable_transparent_notes_by_address<C: GenericClient>(
                 output_index,
                 hex: hex::encode(&script_hex),
                 spent: false,
+                address: address.to_string(),
+                key_path: None,
             }
         })
         .collect();
     Ok(notes)
 }
 
+/// Unreserved utxos of the imported transparent addresses with at least `min_balance`
+/// in utxos mined at or before `max_height`, oldest first, with their account
+pub fn get_sweep_utxos<C: GenericClient>(
+    c: &mut C,
+    accounts: &[i32],
+    min_balance: i64,
+    max_height: u32,
+) -> crate::Result<Vec<(i32, grpc::Utxo)>> {
+    let rows = c.query(
+        "WITH available AS (SELECT u.* FROM utxos u JOIN accounts a ON a.account = u.account
+        WHERE a.fvk IS NULL AND NOT u.spent AND u.payment IS NULL AND u.height <= $1
+        AND (cardinality($2::INTEGER[]) = 0 OR u.account = ANY($2)))
+        SELECT id_utxo, tx_hash, output_index, value, script, address, account FROM available
+        WHERE account IN (SELECT account FROM available GROUP BY account HAVING SUM(value)::BIGINT >= $3)
+        ORDER BY height, id_utxo",
+        &[&(max_height as i32), &accounts, &min_balance],
+    )?;
+    let utxos: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            let tx_hash: Vec<u8> = row.get(1);
+            let value: i64 = row.get(3);
+            let script: Vec<u8> = row.get(4);
+            let utxo = grpc::Utxo {
+                id: row.get(0),
+                amount: value as u64,
+                tx_hash: hex::encode(&tx_hash),
+                output_index: row.get(2),
+                hex: hex::encode(&script),
+                spent: false,
+                address: row.get(5),
+                key_path: None,
+            };
+            (row.get(6), utxo)
+        })
+        .collect();
+    Ok(utxos)
+}
+
 pub fn get_account<C: GenericClient>(c: &mut C, id: i32) -> crate::Result<Account> {
     let row = c.query_opt("SELECT a.address, f.extfvk FROM accounts a LEFT JOIN fvks f ON a.fvk = f.id_fvk WHERE a.account = $1", &[&id])?;
     match row {
@@ -195,10 +392,23 @@ pub fn get_account<C: GenericClient>(c: &mut C, id: i32) -> crate::Result<Accoun
     }
 }
 
+/// The transparent receiver of the unified addresses of a shielded account
+pub fn get_transparent_receiver<C: GenericClient>(c: &mut C, account: i32) -> crate::Result<Option<String>> {
+    let row = c.query_opt("SELECT transparent_receiver FROM accounts WHERE account = $1", &[&account])?;
+    Ok(row.and_then(|row| row.get(0)))
+}
+
+/// The diversified addresses of a viewing key, the first one first
+pub fn get_fvk_accounts<C: GenericClient>(c: &mut C, id_fvk: i32) -> crate::Result<Vec<i32>> {
+    let rows = c.query("SELECT account FROM accounts WHERE fvk = $1 ORDER BY account", &[&id_fvk])?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
 pub fn get_all_trp_addresses<C: GenericClient>(c: &mut C) -> crate::Result<Vec<(i32, String)>> {
     let row = c
         .query(
-            "SELECT account, address FROM accounts WHERE fvk IS NULL",
+            "SELECT account, address FROM accounts WHERE fvk IS NULL
+            UNION SELECT account, transparent_receiver FROM accounts WHERE transparent_receiver IS NOT NULL",
             &[],
         )
         .map_err(WalletError::Postgres)?;
@@ -212,6 +422,26 @@ pub fn get_all_trp_addresses<C: GenericClient>(c: &mut C) -> crate::Result<Vec<(
         .collect())
 }
 
+pub fn get_unspent_utxos<C: GenericClient>(c: &mut C) -> crate::Result<Vec<((String, u32), Utxo)>> {
+    let rows = c.query("SELECT tx_hash, output_index, account, address, value, script, height FROM utxos WHERE NOT spent", &[])?;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let tx_hash: Vec<u8> = row.get(0);
+            let output_index: i32 = row.get(1);
+            let height: i32 = row.get(6);
+            let utxo = Utxo {
+                account: row.get(2),
+                address: row.get(3),
+                value: row.get(4),
+                script: row.get(5),
+                height: height as u32,
+            };
+            ((hex::encode(tx_hash), output_index as u32), utxo)
+        })
+        .collect())
+}
+
 pub fn get_target_and_anchor_heights<C: GenericClient>(
     c: &mut C,
 ) -> crate::Result<Option<(BlockHeight, BlockHeight)>> {
@@ -254,19 +484,32 @@ pub fn store_payment<C: GenericClient>(
     datetime: SystemTime,
     account: i32,
     sender: &str,
-    recipient: &str,
+    outputs: &[grpc::PaymentOutput],
     change: &str,
-    amount: i64,
+    fee: i64,
+    expiry_height: u32,
     notes: &[i32],
     utxos: &[i32],
 ) -> crate::Result<i32> {
+    // the first recipient and the total amount
+    let recipient = &outputs[0].address;
+    let amount: i64 = outputs.iter().map(|o| o.amount as i64).sum();
     let row = client.query_one(
         "INSERT INTO payments(datetime, account, sender, recipient,
-        change, amount, paid) VALUES ($1, $2, $3, $4, $5, $6, FALSE)
+        change, amount, state, updated, expiry_height, fee) VALUES ($1, $2, $3, $4, $5, $6, $7, $1, $8, $9)
         RETURNING id_payment",
-        &[&datetime, &account, &sender, &recipient, &change, &amount],
+        &[&datetime, &account, &sender, &recipient, &change, &amount, &PaymentState::Prepared.as_str(),
+            &(expiry_height as i32), &fee],
     )?;
     let id: i32 = row.get(0);
+    store_payment_history(client, id, datetime, PaymentState::Prepared, None)?;
+    for output in outputs.iter() {
+        let memo = if output.memo_hex.is_empty() { None } else { Some(hex::decode(&output.memo_hex)?) };
+        client.execute(
+            "INSERT INTO payment_outputs(payment, address, amount, memo) VALUES ($1, $2, $3, $4)",
+            &[&id, &output.address, &(output.amount as i64), &memo],
+        )?;
+    }
     for utxo in utxos.iter() {
         client.execute(
             "UPDATE utxos SET payment = $1 WHERE id_utxo = $2",
@@ -282,31 +525,317 @@ pub fn store_payment<C: GenericClient>(
     Ok(id)
 }
 
+fn store_payment_history<C: GenericClient>(
+    client: &mut C,
+    id_payment: i32,
+    datetime: SystemTime,
+    state: PaymentState,
+    detail: Option<&str>,
+) -> crate::Result<()> {
+    client.execute(
+        "INSERT INTO payment_history(payment, datetime, state, detail) VALUES ($1, $2, $3, $4)",
+        &[&id_payment, &datetime, &state.as_str(), &detail],
+    )?;
+    Ok(())
+}
+
+pub fn get_payment_state<C: GenericClient>(client: &mut C, id_payment: i32) -> crate::Result<PaymentState> {
+    let row = client
+        .query_opt("SELECT state FROM payments WHERE id_payment = $1", &[&id_payment])?
+        .ok_or_else(|| anyhow!("Unknown payment {}", id_payment))?;
+    let state: String = row.get(0);
+    state.parse()
+}
+
+/// Moves the payment to `state` and records the transition.
+/// Fails if the payment cannot go there from its current state.
+/// Expired, failed and cancelled payments release their notes and utxos
+pub fn set_payment_state<C: GenericClient>(
+    client: &mut C,
+    id_payment: i32,
+    state: PaymentState,
+    detail: Option<&str>,
+) -> crate::Result<()> {
+    let mut db_tx = client.transaction()?;
+    let row = db_tx
+        .query_opt("SELECT state FROM payments WHERE id_payment = $1 FOR UPDATE", &[&id_payment])?
+        .ok_or_else(|| anyhow!("Unknown payment {}", id_payment))?;
+    let current: PaymentState = row.get::<_, String>(0).parse()?;
+    current.check_transition(state)?;
+    let datetime = SystemTime::now();
+    db_tx.execute(
+        "UPDATE payments SET state = $2, updated = $3 WHERE id_payment = $1",
+        &[&id_payment, &state.as_str(), &datetime],
+    )?;
+    store_payment_history(&mut db_tx, id_payment, datetime, state, detail)?;
+    if state.releases_inputs() {
+        db_tx.execute(
+            "UPDATE utxos SET payment = NULL WHERE payment = $1",
+            &[&id_payment],
+        )?;
+        db_tx.execute(
+            "UPDATE received_notes SET payment = NULL WHERE payment = $1",
+            &[&id_payment],
+        )?;
+    }
+    db_tx.commit()?;
+    Ok(())
+}
+
 pub fn mark_paid<C: GenericClient>(
     client: &mut C,
     id_payment: i32,
     txid: &str,
 ) -> crate::Result<()> {
-    client.execute(
-        "UPDATE payments SET paid = TRUE, txid = $2 WHERE id_payment = $1",
+    let mut db_tx = client.transaction()?;
+    set_payment_state(&mut db_tx, id_payment, PaymentState::Broadcast, None)?;
+    db_tx.execute(
+        "UPDATE payments SET txid = $2 WHERE id_payment = $1",
         &[&id_payment, &txid],
     )?;
+    db_tx.commit()?;
     Ok(())
 }
 
-pub fn cancel_payment<C: GenericClient>(client: &mut C, id_payment: i32) -> crate::Result<()> {
+/// The signer may have chosen another expiry height than the one we prepared
+pub fn set_payment_expiry_height<C: GenericClient>(
+    client: &mut C,
+    id_payment: i32,
+    expiry_height: u32,
+) -> crate::Result<()> {
     client.execute(
-        "UPDATE payments SET paid = FALSE WHERE id_payment = $1",
-        &[&id_payment],
+        "UPDATE payments SET expiry_height = $2 WHERE id_payment = $1",
+        &[&id_payment, &(expiry_height as i32)],
     )?;
+    Ok(())
+}
+
+/// Records that `id_payment` is a new attempt at the expired or failed payment `reissue_of`
+pub fn set_payment_reissue_of<C: GenericClient>(
+    client: &mut C,
+    id_payment: i32,
+    reissue_of: i32,
+) -> crate::Result<()> {
     client.execute(
-        "UPDATE utxos SET payment = NULL WHERE payment = $1",
-        &[&id_payment],
+        "UPDATE payments SET reissue_of = $2 WHERE id_payment = $1",
+        &[&id_payment, &reissue_of],
     )?;
+    Ok(())
+}
+
+/// The accounts whose utxos a sweep spends
+pub fn store_payment_sources<C: GenericClient>(client: &mut C, id_payment: i32, accounts: &[i32]) -> crate::Result<()> {
     client.execute(
-        "UPDATE received_notes SET payment = NULL WHERE payment = $1",
+        "INSERT INTO payment_sources(payment, account) SELECT $1, UNNEST($2::INTEGER[])",
+        &[&id_payment, &accounts],
+    )?;
+    Ok(())
+}
+
+fn get_payment_sources<C: GenericClient>(client: &mut C, id_payment: i32) -> crate::Result<Vec<i32>> {
+    let rows = client.query(
+        "SELECT account FROM payment_sources WHERE payment = $1 ORDER BY account",
         &[&id_payment],
     )?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+fn get_payment_outputs<C: GenericClient>(client: &mut C, id_payment: i32) -> crate::Result<Vec<grpc::PaymentOutput>> {
+    let rows = client.query(
+        "SELECT address, amount, memo FROM payment_outputs WHERE payment = $1 ORDER BY id_output",
+        &[&id_payment],
+    )?;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let memo: Option<Vec<u8>> = row.get(2);
+            grpc::PaymentOutput {
+                address: row.get(0),
+                amount: row.get::<_, i64>(1) as u64,
+                memo: String::new(),
+                memo_hex: memo.map(hex::encode).unwrap_or_default(),
+            }
+        })
+        .collect())
+}
+
+/// Account, recipients and change account of a payment to reissue
+pub fn get_reissue_request<C: GenericClient>(
+    client: &mut C,
+    id_payment: i32,
+) -> crate::Result<(i32, Vec<grpc::PaymentOutput>, i32)> {
+    let state = get_payment_state(client, id_payment)?;
+    if !matches!(state, PaymentState::Expired | PaymentState::Failed) {
+        return Err(WalletError::Error(anyhow!(
+            "Payment {} is {} and cannot be reissued",
+            id_payment,
+            state.as_str()
+        )));
+    }
+    if let Some(row) = client.query_opt(
+        "SELECT id_payment FROM payments WHERE reissue_of = $1",
+        &[&id_payment],
+    )? {
+        let reissued_as: i32 = row.get(0);
+        return Err(WalletError::Error(anyhow!(
+            "Payment {} was already reissued as {}",
+            id_payment,
+            reissued_as
+        )));
+    }
+    // the payment only records the destination of a sweep, not where the funds came from
+    if !get_payment_sources(client, id_payment)?.is_empty() {
+        return Err(WalletError::Error(anyhow!(
+            "Payment {} is a sweep and cannot be reissued, prepare a new sweep instead",
+            id_payment
+        )));
+    }
+    // a consolidation has a single output to its own address, which prepare_batch_tx cannot rebuild
+    let row = client.query_one(
+        "SELECT p.recipient = p.sender AND p.change = p.sender
+        AND (SELECT COUNT(*) FROM payment_outputs o WHERE o.payment = p.id_payment) = 1
+        FROM payments p WHERE p.id_payment = $1",
+        &[&id_payment],
+    )?;
+    let consolidation: bool = row.get(0);
+    if consolidation {
+        return Err(WalletError::Error(anyhow!(
+            "Payment {} is a consolidation and cannot be reissued, prepare a new consolidation instead",
+            id_payment
+        )));
+    }
+    let row = client.query_one(
+        "SELECT p.account, a.account FROM payments p, accounts a
+        WHERE p.change = a.address AND p.id_payment = $1",
+        &[&id_payment],
+    )?;
+    let outputs = get_payment_outputs(client, id_payment)?;
+    Ok((row.get(0), outputs, row.get(1)))
+}
+
+/// Sapling keys that can recover the outgoing notes of a payment: the sender's and the change's
+pub fn get_payment_fvks<C: GenericClient>(client: &mut C, id_payment: i32) -> crate::Result<Vec<(i32, String)>> {
+    let rows = client.query(
+        "SELECT DISTINCT f.id_fvk, f.extfvk FROM payments p, accounts a, fvks f
+        WHERE a.fvk = f.id_fvk AND (a.account = p.account OR a.address = p.change) AND p.id_payment = $1",
+        &[&id_payment],
+    )?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+/// Records the transaction of a payment and its outgoing shielded notes with their memos
+pub fn store_sent_notes<C: GenericClient>(
+    client: &mut C,
+    id_payment: i32,
+    txid: &[u8],
+    expiry_height: u32,
+    raw_tx: &[u8],
+    notes: &[(i32, String, i64, Vec<u8>)],
+) -> crate::Result<()> {
+    let mut db_tx = client.transaction()?;
+    let row = db_tx.query_one(
+        "INSERT INTO transactions(txid, expiry_height, raw) VALUES ($1, $2, $3)
+        ON CONFLICT (txid) DO UPDATE SET raw = excluded.raw RETURNING id_tx",
+        &[&txid, &(expiry_height as i32), &raw_tx],
+    )?;
+    let id_tx: i32 = row.get(0);
+    for (output_index, address, value, memo) in notes.iter() {
+        db_tx.execute(
+            "INSERT INTO sent_notes(tx, output_index, from_account, address, value, memo)
+            SELECT $1, $2, account, $3, $4, $5 FROM payments WHERE id_payment = $6
+            ON CONFLICT (tx, output_index) DO UPDATE SET memo = excluded.memo",
+            &[&id_tx, output_index, address, value, memo, &id_payment],
+        )?;
+    }
+    db_tx.commit()?;
+    Ok(())
+}
+
+/// Only possible before the transaction is broadcast
+pub fn cancel_payment<C: GenericClient>(client: &mut C, id_payment: i32) -> crate::Result<()> {
+    set_payment_state(client, id_payment, PaymentState::Cancelled, None)
+}
+
+/// Follows the broadcast payments on chain: mined, confirmed after `confirmations`
+/// blocks, or back to broadcast if their block was reorged out.
+/// Payments that are not mined by their expiry height are expired
+pub fn update_payment_states<C: GenericClient>(client: &mut C, confirmations: u32) -> crate::Result<()> {
+    let tip_height = get_tip_height(client)?;
+    let rows = client.query(
+        "SELECT id_payment, state, txid FROM payments WHERE state IN ('broadcast', 'mined') AND txid IS NOT NULL",
+        &[],
+    )?;
+    for row in rows.iter() {
+        let id_payment: i32 = row.get(0);
+        let state: PaymentState = row.get::<_, String>(1).parse()?;
+        let txid: String = row.get(2);
+        let tx_height = get_tx_height(client, &hex::decode(&txid)?)?;
+        let tx_confirmations = get_confirmations(tip_height, tx_height);
+        match (state, tx_height) {
+            (PaymentState::Broadcast, Some(height)) => {
+                let detail = format!("height {}", height);
+                set_payment_state(client, id_payment, PaymentState::Mined, Some(&detail))?;
+                if tx_confirmations >= confirmations {
+                    set_payment_state(client, id_payment, PaymentState::Confirmed, None)?;
+                }
+            }
+            (PaymentState::Mined, Some(_)) if tx_confirmations >= confirmations => {
+                set_payment_state(client, id_payment, PaymentState::Confirmed, None)?;
+            }
+            (PaymentState::Mined, None) => {
+                set_payment_state(client, id_payment, PaymentState::Broadcast, Some("reorg"))?;
+            }
+            _ => {}
+        }
+    }
+    if let Some(tip_height) = tip_height {
+        expire_payments(client, tip_height)?;
+    }
+    Ok(())
+}
+
+fn expire_payments<C: GenericClient>(client: &mut C, tip_height: u32) -> crate::Result<()> {
+    let rows = client.query(
+        "SELECT id_payment, account, amount, txid, expiry_height FROM payments
+        WHERE state IN ('prepared', 'signed', 'broadcast') AND expiry_height < $1",
+        &[&(tip_height as i32)],
+    )?;
+    for row in rows.iter() {
+        let id_payment: i32 = row.get(0);
+        let account: i32 = row.get(1);
+        let amount: i64 = row.get(2);
+        let txid: Option<String> = row.get(3);
+        let expiry_height: i32 = row.get(4);
+        if let Some(txid) = txid.as_ref() {
+            if get_tx_height(client, &hex::decode(txid)?)?.is_some() {
+                continue; // mined, picked up at the next update
+            }
+        }
+        let detail = format!("expiry height {}", expiry_height);
+        let mut db_tx = client.transaction()?;
+        set_payment_state(&mut db_tx, id_payment, PaymentState::Expired, Some(&detail))?;
+        // only broadcast transactions were ever seen by the client
+        if let Some(txid) = txid {
+            store_notification(
+                &mut db_tx,
+                &NotificationRecord {
+                    id: 0,
+                    eventType: "paymentExpired".to_string(),
+                    txHash: txid,
+                    account,
+                    address: None,
+                    txOutputIndex: 0,
+                    amount,
+                    block: 0,
+                    revertedEventType: None,
+                    confirmedEventType: None,
+                    confirmations: None,
+                    memo: None,
+                },
+            )?;
+        }
+        db_tx.commit()?;
+    }
     Ok(())
 }
 
@@ -322,6 +851,35 @@ pub fn trp_rewind_to_height<C: GenericClient>(
     Ok(())
 }
 
+/// Drops the chain data so that the wallet can be rescanned from a checkpoint at `height`.
+/// Only possible if every note received at or before `height` is spent because
+/// their witnesses cannot be rebuilt
+pub fn reset_to_checkpoint<C: GenericClient>(
+    client: &mut C,
+    height: u32,
+) -> Result<(), WalletError> {
+    let row = client.query_one("SELECT MIN(height) FROM received_notes WHERE spent IS NULL", &[])?;
+    let earliest_unspent: Option<i32> = row.get(0);
+    if let Some(earliest_unspent) = earliest_unspent {
+        if earliest_unspent <= height as i32 {
+            return Err(WalletError::Error(anyhow!(
+                "Checkpoint must be below the earliest unspent note at height {}",
+                earliest_unspent
+            )));
+        }
+    }
+    client.execute("DELETE FROM sapling_witnesses", &[])?;
+    client.execute("DELETE FROM received_notes WHERE height > $1", &[&(height as i32)])?;
+    client.execute(
+        "UPDATE transactions SET block = NULL, tx_index = NULL WHERE block IS NOT NULL",
+        &[],
+    )?;
+    client.execute("DELETE FROM blocks", &[])?;
+    trp_rewind_to_height(client, height)?;
+    revert_notifications(client, height)?;
+    Ok(())
+}
+
 pub fn get_block_by_height<C: GenericClient>(client: &mut C, height: u32) -> crate::Result<Option<Vec<u8>>> {
     let row = client.query_opt("SELECT hash FROM blocks WHERE height = $1", &[&(height as i32)])?;
     Ok(row.map(|row| {
@@ -337,15 +895,21 @@ pub fn get_balance<C: GenericClient>(
 ) -> crate::Result<grpc::Balance> {
     let tip_height = get_latest_height(config)? as i32;
     let min_height = (tip_height - min_confirmations) as i32;
+    let row = client.query_one("SELECT SUM(value)::BIGINT FROM mempool_txs WHERE status = 'pending' AND account = $1", &[&account])?;
+    let pending = row.get::<_, Option<i64>>(0).unwrap_or(0) as u64;
     let balance = match db::get_account(client, account)? {
         Account::Shielded(_, _) => {
-            let row = client.query_one("SELECT SUM(value)::BIGINT FROM received_notes WHERE spent IS NULL AND payment IS NULL AND account = $1 AND height <= $2", &[&account, &min_height])?;
-            let available= row.get::<_, Option<i64>>(0).unwrap_or(0) as u64;
-            let row = client.query_one("SELECT SUM(value)::BIGINT FROM received_notes WHERE spent IS NULL AND account = $1 AND height <= $2", &[&account, &min_height])?;
-            let total = row.get::<_, Option<i64>>(0).unwrap_or(0) as u64;
+            // with the utxos of the transparent receiver of its unified addresses
+            let row = client.query_one("SELECT ((SELECT COALESCE(SUM(value), 0) FROM received_notes WHERE spent IS NULL AND payment IS NULL AND account = $1 AND height <= $2)
+                + (SELECT COALESCE(SUM(value), 0) FROM utxos WHERE NOT spent AND payment IS NULL AND account = $1 AND height <= $2))::BIGINT", &[&account, &min_height])?;
+            let available = row.get::<_, i64>(0) as u64;
+            let row = client.query_one("SELECT ((SELECT COALESCE(SUM(value), 0) FROM received_notes WHERE spent IS NULL AND account = $1 AND height <= $2)
+                + (SELECT COALESCE(SUM(value), 0) FROM utxos WHERE NOT spent AND account = $1 AND height <= $2))::BIGINT", &[&account, &min_height])?;
+            let total = row.get::<_, i64>(0) as u64;
             grpc::Balance {
                 total,
-                available
+                available,
+                pending,
             }
         }
         Account::Transparent(address) => {
@@ -355,7 +919,8 @@ pub fn get_balance<C: GenericClient>(
             let total= row.get::<_, Option<i64>>(0).unwrap_or(0) as u64;
             grpc::Balance {
                 total,
-                available
+                available,
+                pending,
             }
         }
     };
@@ -365,7 +930,7 @@ pub fn get_balance<C: GenericClient>(
 pub fn get_payment_info<C: GenericClient>(client: &mut C, id_payment: i32) -> crate::Result<grpc::Payment> {
     let row = client.query_one(
         "SELECT datetime, account, sender, recipient,
-        change, amount, paid, txid FROM payments WHERE id_payment = $1",
+        change, amount, state, txid, updated, expiry_height, reissue_of, fee FROM payments WHERE id_payment = $1",
         &[&id_payment],
     )?;
     let datetime: SystemTime = row.get(0);
@@ -374,9 +939,51 @@ pub fn get_payment_info<C: GenericClient>(client: &mut C, id_payment: i32) -> cr
     let recipient: String = row.get(3);
     let change: String = row.get(4);
     let amount: i64 = row.get(5);
-    let paid: bool = row.get(6);
+    let state: String = row.get(6);
+    let paid = state.parse::<PaymentState>()?.is_paid();
     let txid: Option<String> = row.get(7);
+    let updated: SystemTime = row.get(8);
+    let expiry_height: i32 = row.get(9);
+    let reissue_of: Option<i32> = row.get(10);
+    let fee: i64 = row.get(11);
+    let outputs = get_payment_outputs(client, id_payment)?
+        .into_iter()
+        .map(|o| {
+            let memo = hex::decode(&o.memo_hex).ok().and_then(|m| memo_to_string(&m));
+            grpc::PaymentLine {
+                address: o.address,
+                amount: o.amount,
+                state: state.clone(),
+                memo: memo.unwrap_or_default(),
+            }
+        })
+        .collect();
+    let source_accounts = get_payment_sources(client, id_payment)?;
     let datetime = datetime.duration_since(UNIX_EPOCH).unwrap();
+    let history = client
+        .query(
+            "SELECT datetime, state, detail FROM payment_history WHERE payment = $1 ORDER BY id_history",
+            &[&id_payment],
+        )?
+        .iter()
+        .map(|row| {
+            let datetime: SystemTime = row.get(0);
+            let detail: Option<String> = row.get(2);
+            grpc::PaymentTransition {
+                datetime: datetime.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
+                state: row.get(1),
+                detail: detail.unwrap_or_default(),
+            }
+        })
+        .collect();
+    let confirmations = match txid.as_deref() {
+        Some(txid) if !txid.is_empty() => {
+            let tip_height = get_tip_height(client)?;
+            let tx_height = get_tx_height(client, &hex::decode(txid)?)?;
+            get_confirmations(tip_height, tx_height)
+        }
+        _ => 0,
+    };
     Ok(grpc::Payment {
         id: id_payment,
         datetime: datetime.as_secs() as u32,
@@ -386,7 +993,16 @@ pub fn get_payment_info<C: GenericClient>(client: &mut C, id_payment: i32) -> cr
         change_address: change,
         amount: amount as u64,
         paid,
-        tx_id: txid.unwrap_or_else(String::new)
+        tx_id: txid.unwrap_or_else(String::new),
+        confirmations,
+        state,
+        updated: updated.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
+        history,
+        expiry_height: expiry_height as u32,
+        reissue_of: reissue_of.unwrap_or_default(),
+        outputs,
+        fee: fee as u64,
+        source_accounts,
     })
 }
 
@@ -397,52 +1013,356 @@ pub fn list_pending_payments<C: GenericClient>(client: &mut C, id_account: i32)
 
 pub fn store_notification<C: GenericClient>(client: &mut C, notification_record: &NotificationRecord) -> crate::Result<()> {
     let datetime = SystemTime::now();
-    let outgoing = notification_record.eventType == "outgoingTx";
-    client.execute("INSERT INTO notifications(datetime, outgoing, tx_hash, account, tx_output_index, amount, block, delivered)
-    VALUES ($1, $2, $3, $4, $5, $6, $7, FALSE)
-    ON CONFLICT ON CONSTRAINT notification_output DO NOTHING", &[
+    client.execute("INSERT INTO notifications(datetime, event_type, tx_hash, account, tx_output_index, amount, block, delivered, confirmations)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, FALSE, $8)
+    ON CONFLICT (tx_hash, tx_output_index, event_type, confirmations) WHERE active AND NOT reverted DO NOTHING", &[
         &datetime,
-        &outgoing,
+        &notification_record.eventType,
         &hex::decode(&notification_record.txHash).unwrap(),
         &notification_record.account,
         &notification_record.txOutputIndex,
         &notification_record.amount,
         &(notification_record.block as i32),
+        &(notification_record.confirmations.unwrap_or(0) as i32),
+    ])?;
+    Ok(())
+}
+
+/// Adds a confirmed event for every mined transaction that reached one
+/// of the confirmation thresholds of its event type since the last call
+pub fn store_confirmations<C: GenericClient>(
+    client: &mut C,
+    confirmation_thresholds: &HashMap<String, Vec<u32>>,
+) -> crate::Result<()> {
+    let tip_height = match get_tip_height(client)? {
+        Some(height) => height,
+        None => return Ok(()),
+    };
+    let datetime = SystemTime::now();
+    for (event_type, thresholds) in confirmation_thresholds.iter() {
+        for &threshold in thresholds.iter() {
+            if tip_height + 1 < threshold {
+                continue;
+            }
+            // mined at or below this height => at least `threshold` confirmations
+            let max_height = tip_height + 1 - threshold;
+            client.execute("INSERT INTO notifications(datetime, event_type, tx_hash, account, tx_output_index, amount, block, delivered, confirmations)
+            SELECT $1, event_type, tx_hash, account, tx_output_index, amount, block, FALSE, $2
+            FROM notifications WHERE event_type = $3 AND confirmations = 0 AND block > 0 AND block <= $4 AND active AND NOT reverted
+            ON CONFLICT (tx_hash, tx_output_index, event_type, confirmations) WHERE active AND NOT reverted DO NOTHING", &[
+                &datetime,
+                &(threshold as i32),
+                event_type,
+                &(max_height as i32),
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+pub fn get_tip_height<C: GenericClient>(client: &mut C) -> crate::Result<Option<u32>> {
+    let row = client.query_one("SELECT MAX(height) FROM blocks", &[])?;
+    let height: Option<i32> = row.get(0);
+    Ok(height.map(|h| h as u32))
+}
+
+/// Height of the block that mined the transaction, if we know about it
+fn get_tx_height<C: GenericClient>(client: &mut C, tx_hash: &[u8]) -> crate::Result<Option<u32>> {
+    let row = client.query_one("SELECT MIN(height) FROM (
+    SELECT block AS height FROM transactions WHERE txid = $1
+    UNION SELECT height FROM utxos WHERE tx_hash = $1
+    UNION SELECT u.spent_height FROM utxos u, payments p WHERE u.payment = p.id_payment AND p.txid = encode($1, 'hex')
+    ) h", &[&tx_hash])?;
+    let height: Option<i32> = row.get(0);
+    Ok(height.map(|h| h as u32))
+}
+
+fn get_confirmations(tip_height: Option<u32>, tx_height: Option<u32>) -> u32 {
+    match (tip_height, tx_height) {
+        (Some(tip_height), Some(tx_height)) if tip_height >= tx_height => tip_height - tx_height + 1,
+        _ => 0,
+    }
+}
+
+pub fn get_tx_status<C: GenericClient>(client: &mut C, tx_hash: &str) -> crate::Result<grpc::TxStatus> {
+    let txid = hex::decode(tx_hash)?;
+    let tip_height = get_tip_height(client)?;
+    let tx_height = get_tx_height(client, &txid)?;
+    let row = client.query_one("SELECT COUNT(*) FROM mempool_txs WHERE tx_hash = $1 AND status = 'pending'", &[&txid])?;
+    let in_mempool = tx_height.is_none() && row.get::<_, i64>(0) > 0;
+    Ok(grpc::TxStatus {
+        hash: tx_hash.to_string(),
+        height: tx_height.unwrap_or(0),
+        confirmations: get_confirmations(tip_height, tx_height),
+        in_mempool,
+    })
+}
+
+/// Retracts the notifications of transactions mined above `height`.
+/// Those that were never delivered are dropped, the others get a reverted event.
+/// Either way, they are no longer active so that a new event is emitted
+/// if the transaction gets mined again
+pub fn revert_notifications<C: GenericClient>(client: &mut C, height: u32) -> crate::Result<()> {
+    let datetime = SystemTime::now();
+    client.execute("DELETE FROM notifications WHERE block > $1 AND active AND NOT reverted AND NOT delivered", &[&(height as i32)])?;
+    client.execute("INSERT INTO notifications(datetime, event_type, tx_hash, account, tx_output_index, amount, block, delivered, reverted, active, confirmations)
+    SELECT $2, event_type, tx_hash, account, tx_output_index, amount, block, FALSE, TRUE, FALSE, confirmations
+    FROM notifications WHERE block > $1 AND active AND NOT reverted", &[&(height as i32), &datetime])?;
+    client.execute("UPDATE notifications SET active = FALSE WHERE block > $1 AND active AND NOT reverted", &[&(height as i32)])?;
+    Ok(())
+}
+
+/// Mined transactions that have received notes without memo
+pub fn list_txs_without_memo<C: GenericClient>(client: &mut C) -> crate::Result<Vec<(String, u32)>> {
+    let rows = client.query("SELECT DISTINCT t.txid, t.block FROM received_notes rn, transactions t
+    WHERE rn.tx = t.id_tx AND rn.memo IS NULL AND t.block IS NOT NULL", &[])?;
+    Ok(rows.iter().map(|row| {
+        let tx_hash: Vec<u8> = row.get(0);
+        let height: i32 = row.get(1);
+        (hex::encode(tx_hash), height as u32)
+    }).collect())
+}
+
+pub fn store_memo<C: GenericClient>(client: &mut C, tx_hash: &str, output_index: i32, memo: &[u8]) -> crate::Result<()> {
+    client.execute("UPDATE received_notes SET memo = $3 FROM transactions t
+    WHERE received_notes.tx = t.id_tx AND t.txid = $1 AND received_notes.output_index = $2", &[
+        &hex::decode(tx_hash)?,
+        &output_index,
+        &memo,
+    ])?;
+    Ok(())
+}
+
+/// Gives an empty memo to the notes of the tx that still have none, when
+/// the tx cannot be read or decrypted
+pub fn mark_memos_unavailable<C: GenericClient>(client: &mut C, tx_hash: &str) -> crate::Result<()> {
+    let empty: &[u8] = &[];
+    client.execute("UPDATE received_notes SET memo = $2 FROM transactions t
+    WHERE received_notes.tx = t.id_tx AND t.txid = $1 AND received_notes.memo IS NULL", &[
+        &hex::decode(tx_hash)?,
+        &empty,
     ])?;
     Ok(())
 }
 
+pub fn get_note_info<C: GenericClient>(client: &mut C, tx_hash: &str, output_index: i32) -> crate::Result<grpc::ReceivedNote> {
+    let row = client.query_opt("SELECT rn.id_note, rn.account, rn.address, rn.value, rn.height, rn.memo, rn.spent IS NOT NULL
+    FROM received_notes rn, transactions t WHERE rn.tx = t.id_tx AND t.txid = $1 AND rn.output_index = $2", &[
+        &hex::decode(tx_hash)?,
+        &output_index,
+    ])?.ok_or_else(|| anyhow!("Unknown note"))?;
+    let id: i32 = row.get(0);
+    let account: i32 = row.get(1);
+    let address: String = row.get(2);
+    let amount: i64 = row.get(3);
+    let height: i32 = row.get(4);
+    let memo: Option<Vec<u8>> = row.get(5);
+    let spent: bool = row.get(6);
+    Ok(grpc::ReceivedNote {
+        id,
+        account,
+        address,
+        tx_hash: tx_hash.to_string(),
+        output_index,
+        amount: amount as u64,
+        height: height as u32,
+        memo: memo.and_then(|m| memo_to_string(&m)).unwrap_or_default(),
+        spent,
+    })
+}
+
+/// Returns true if the output was not already known
+pub fn store_mempool_output<C: GenericClient>(
+    client: &mut C,
+    tx_hash: &str,
+    output_index: i32,
+    shielded: bool,
+    account: i32,
+    value: i64,
+) -> crate::Result<bool> {
+    let datetime = SystemTime::now();
+    let inserted = client.execute("INSERT INTO mempool_txs(tx_hash, output_index, shielded, account, value, first_seen, status)
+    VALUES ($1, $2, $3, $4, $5, $6, 'pending')
+    ON CONFLICT ON CONSTRAINT mempool_output DO NOTHING", &[
+        &hex::decode(tx_hash)?,
+        &output_index,
+        &shielded,
+        &account,
+        &value,
+        &datetime,
+    ])?;
+    Ok(inserted == 1)
+}
+
+pub fn list_pending_mempool_txs<C: GenericClient>(client: &mut C) -> crate::Result<Vec<(String, Option<u32>)>> {
+    let rows = client.query("SELECT tx_hash, MAX(left_height) FROM mempool_txs WHERE status = 'pending' GROUP BY tx_hash", &[])?;
+    Ok(rows.iter().map(|row| {
+        let tx_hash: Vec<u8> = row.get(0);
+        let left_height: Option<i32> = row.get(1);
+        (hex::encode(tx_hash), left_height.map(|h| h as u32))
+    }).collect())
+}
+
+pub fn is_tx_mined<C: GenericClient>(client: &mut C, tx_hash: &str) -> crate::Result<bool> {
+    let tx_hash = hex::decode(tx_hash)?;
+    let row = client.query_opt("SELECT 1 FROM transactions WHERE txid = $1 AND block IS NOT NULL
+    UNION SELECT 1 FROM utxos WHERE tx_hash = $1", &[&tx_hash])?;
+    Ok(row.is_some())
+}
+
+pub fn update_mempool_tx<C: GenericClient>(client: &mut C, tx_hash: &str, left_height: Option<u32>, status: &str) -> crate::Result<()> {
+    client.execute("UPDATE mempool_txs SET left_height = $2, status = $3 WHERE tx_hash = $1 AND status = 'pending'", &[
+        &hex::decode(tx_hash)?,
+        &left_height.map(|h| h as i32),
+        &status,
+    ])?;
+    Ok(())
+}
+
+/// The tx left the mempool without being mined
+pub fn drop_mempool_tx<C: GenericClient>(client: &mut C, tx_hash: &str) -> crate::Result<()> {
+    let datetime = SystemTime::now();
+    let tx_hash = hex::decode(tx_hash)?;
+    client.execute("INSERT INTO notifications(datetime, event_type, tx_hash, account, tx_output_index, amount, block, delivered)
+    SELECT $2, 'droppedIncomingTx', tx_hash, account, output_index, value, 0, FALSE
+    FROM mempool_txs WHERE tx_hash = $1 AND status = 'pending'
+    ON CONFLICT (tx_hash, tx_output_index, event_type, confirmations) WHERE active AND NOT reverted DO NOTHING", &[&tx_hash, &datetime])?;
+    client.execute("UPDATE mempool_txs SET status = 'dropped' WHERE tx_hash = $1 AND status = 'pending'", &[&tx_hash])?;
+    Ok(())
+}
+
 pub fn mark_delivered<C: GenericClient>(client: &mut C, id_notification: i32) -> crate::Result<()> {
     client.execute("UPDATE notifications SET delivered = TRUE WHERE id_notification = $1", &[&id_notification])?;
     Ok(())
 }
 
 pub fn list_undelivered<C: GenericClient>(client: &mut C) -> crate::Result<Vec<NotificationRecord>> {
-    let rows = client.query("SELECT id_notification, outgoing, tx_hash, a.account, address, tx_output_index, amount, block \
-    FROM notifications n, accounts a WHERE n.account = a.account AND n.delivered = FALSE", &[])?;
+    // incoming shielded notes have their memo
+    let rows = client.query("SELECT id_notification, event_type, tx_hash, a.account, COALESCE(a.unified_address, a.address), tx_output_index, amount, block, reverted, confirmations, \
+    (SELECT rn.memo FROM received_notes rn, transactions t WHERE rn.tx = t.id_tx AND t.txid = n.tx_hash \
+    AND rn.output_index = n.tx_output_index AND rn.account = n.account AND n.event_type = 'incomingTx') \
+    FROM notifications n, accounts a WHERE n.account = a.account AND n.delivered = FALSE ORDER BY id_notification", &[])?;
     let notification_records: Vec<_> = rows.into_iter().map(|row| {
         let id: i32 = row.get(0);
-        let outgoing: bool = row.get(1);
+        let event_type: String = row.get(1);
         let tx_hash: Vec<u8> = row.get(2);
         let account: i32 = row.get(3);
         let address: String = row.get(4);
         let tx_output_index: i32 = row.get(5);
         let amount: i64 = row.get(6);
         let block: i32 = row.get(7);
+        let reverted: bool = row.get(8);
+        let confirmations: i32 = row.get(9);
+        let memo: Option<Vec<u8>> = row.get(10);
+        let confirmations = if confirmations > 0 { Some(confirmations as u32) } else { None };
+        let (event_type, reverted_event_type, confirmed_event_type) = if reverted {
+            ("reverted".to_string(), Some(event_type), None)
+        } else if confirmations.is_some() {
+            ("confirmed".to_string(), None, Some(event_type))
+        } else {
+            (event_type, None, None)
+        };
         NotificationRecord {
             id,
-            eventType: if outgoing { "outgoingTx".to_string() } else { "incomingTx".to_string() },
+            eventType: event_type,
             txHash: hex::encode(tx_hash),
             account,
             address: Some(address),
             txOutputIndex: tx_output_index,
             amount,
             block: block as u32,
+            revertedEventType: reverted_event_type,
+            confirmedEventType: confirmed_event_type,
+            confirmations,
+            memo: memo.and_then(|m| memo_to_string(&m)),
         }
     }).collect();
     Ok(notification_records)
 }
 
+pub fn get_key_birthday<C: GenericClient>(client: &mut C, key: &RescanKey) -> crate::Result<Option<u32>> {
+    let row = match key {
+        RescanKey::Fvk(id_fvk) => client.query_one("SELECT birthday FROM fvks WHERE id_fvk = $1", &[id_fvk])?,
+        RescanKey::Account(account) => client.query_one("SELECT birthday FROM accounts WHERE account = $1", &[account])?,
+    };
+    let birthday: Option<i32> = row.get(0);
+    Ok(birthday.map(|h| h as u32))
+}
+
+/// Transparent addresses of the key: the imported address, or the transparent
+/// receivers of the unified addresses of a viewing key
+pub fn get_key_trp_addresses<C: GenericClient>(client: &mut C, key: &RescanKey) -> crate::Result<Vec<(i32, String)>> {
+    let rows = match key {
+        RescanKey::Fvk(id_fvk) => client.query(
+            "SELECT account, transparent_receiver FROM accounts WHERE fvk = $1 AND transparent_receiver IS NOT NULL",
+            &[id_fvk],
+        )?,
+        RescanKey::Account(account) => client.query(
+            "SELECT account, address FROM accounts WHERE account = $1 AND fvk IS NULL",
+            &[account],
+        )?,
+    };
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+pub fn create_rescan<C: GenericClient>(client: &mut C, key: &RescanKey, start_height: u32, end_height: u32) -> crate::Result<i32> {
+    let (fvk, account) = match key {
+        RescanKey::Fvk(id_fvk) => (Some(*id_fvk), None),
+        RescanKey::Account(account) => (None, Some(*account)),
+    };
+    let row = client.query_one(
+        "INSERT INTO rescans(fvk, account, start_height, height, end_height, status)
+        VALUES ($1, $2, $3, $3 - 1, $4, 'running') RETURNING id_rescan",
+        &[&fvk, &account, &(start_height as i32), &(end_height as i32)],
+    )?;
+    Ok(row.get(0))
+}
+
+pub fn update_rescan_progress<C: GenericClient>(client: &mut C, id_rescan: i32, height: u32, end_height: u32) -> crate::Result<()> {
+    client.execute(
+        "UPDATE rescans SET height = $2, end_height = $3 WHERE id_rescan = $1",
+        &[&id_rescan, &(height as i32), &(end_height as i32)],
+    )?;
+    Ok(())
+}
+
+pub fn finish_rescan<C: GenericClient>(client: &mut C, id_rescan: i32, error: Option<String>) -> crate::Result<()> {
+    let status = if error.is_some() { "failed" } else { "completed" };
+    client.execute(
+        "UPDATE rescans SET status = $2, error = $3 WHERE id_rescan = $1",
+        &[&id_rescan, &status, &error],
+    )?;
+    Ok(())
+}
+
+/// Rescans that were running when ZAMS stopped will never complete
+pub fn fail_interrupted_rescans<C: GenericClient>(client: &mut C) -> crate::Result<()> {
+    client.execute(
+        "UPDATE rescans SET status = 'failed', error = 'Interrupted' WHERE status = 'running'",
+        &[],
+    )?;
+    Ok(())
+}
+
+pub fn get_rescan_status<C: GenericClient>(client: &mut C, id_rescan: i32) -> crate::Result<grpc::RescanStatus> {
+    let row = client.query_one(
+        "SELECT start_height, height, end_height, status, error FROM rescans WHERE id_rescan = $1",
+        &[&id_rescan],
+    )?;
+    let start_height: i32 = row.get(0);
+    let height: i32 = row.get(1);
+    let end_height: i32 = row.get(2);
+    let error: Option<String> = row.get(4);
+    Ok(grpc::RescanStatus {
+        id: id_rescan,
+        start_height: start_height as u32,
+        height: height as u32,
+        end_height: end_height as u32,
+        status: row.get(3),
+        error: error.unwrap_or_default(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -456,26 +1376,175 @@ mod tests {
         let now = SystemTime::now();
         store_payment(&mut client, now, 1,
         "ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn",
+        &[grpc::PaymentOutput { address: "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string(), amount: 100_000,
+            memo: String::new(), memo_hex: String::new() }],
         "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8",
-        "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8",
-        100_000,
+        1_000,
+        1_438_949,
         &[1, 2],
         &[1]
         ).unwrap();
     }
 
+    fn store_test_payment<C: GenericClient>(client: &mut C) -> i32 {
+        store_payment(client, SystemTime::now(), 1,
+        "ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn",
+        &[grpc::PaymentOutput { address: "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string(), amount: 100_000,
+            memo: String::new(), memo_hex: String::new() }],
+        "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8",
+        1_000,
+        1_438_949,
+        &[],
+        &[]
+        ).unwrap()
+    }
+
+    #[test]
+    fn test_reimport_keeps_birthday() {
+        let config = ZamsConfig::default();
+        let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let address = "zs1reimporttestaddress";
+        let account = import_address(&mut db_tx, address, Some(1_500_000)).unwrap();
+        import_address(&mut db_tx, address, None).unwrap();
+        let birthday = get_key_birthday(&mut db_tx, &RescanKey::Account(account)).unwrap();
+        assert_eq!(birthday, Some(1_500_000));
+        import_address(&mut db_tx, address, Some(1_400_000)).unwrap();
+        let birthday = get_key_birthday(&mut db_tx, &RescanKey::Account(account)).unwrap();
+        assert_eq!(birthday, Some(1_400_000));
+        db_tx.rollback().unwrap();
+    }
+
     #[test]
     fn test_mark_paid() {
         let config = ZamsConfig::default();
         let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
-        mark_paid(&mut client, 2, "").unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let id = store_test_payment(&mut db_tx);
+        set_payment_state(&mut db_tx, id, PaymentState::Signed, None).unwrap();
+        mark_paid(&mut db_tx, id, "").unwrap();
+        assert_eq!(get_payment_state(&mut db_tx, id).unwrap(), PaymentState::Broadcast);
+        // too late to cancel
+        assert!(cancel_payment(&mut db_tx, id).is_err());
+        let history = db_tx.query("SELECT state FROM payment_history WHERE payment = $1 ORDER BY id_history", &[&id]).unwrap();
+        let history: Vec<String> = history.iter().map(|row| row.get(0)).collect();
+        assert_eq!(history, vec!["prepared", "signed", "broadcast"]);
+        db_tx.rollback().unwrap();
     }
 
     #[test]
     fn test_cancel_paid() {
         let config = ZamsConfig::default();
         let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
-        cancel_payment(&mut client, 2).unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let id = store_test_payment(&mut db_tx);
+        cancel_payment(&mut db_tx, id).unwrap();
+        assert_eq!(get_payment_state(&mut db_tx, id).unwrap(), PaymentState::Cancelled);
+        assert!(set_payment_state(&mut db_tx, id, PaymentState::Signed, None).is_err());
+        db_tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_expire_payment() {
+        let config = ZamsConfig::default();
+        let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let id = store_test_payment(&mut db_tx);
+        set_payment_expiry_height(&mut db_tx, id, 1).unwrap();
+        expire_payments(&mut db_tx, 2).unwrap();
+        assert_eq!(get_payment_state(&mut db_tx, id).unwrap(), PaymentState::Expired);
+        assert!(cancel_payment(&mut db_tx, id).is_err());
+        db_tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_revert_notifications() {
+        let config = ZamsConfig::default();
+        let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let record = NotificationRecord {
+            id: 0,
+            eventType: "incomingTx".to_string(),
+            txHash: "d04bb83a234496e033fbd480d24be47a53d38b984cfc575b05bc24580e44a42d".to_string(),
+            account: 1,
+            address: None,
+            txOutputIndex: 0,
+            amount: 1000,
+            block: 2_000_000,
+            revertedEventType: None,
+            confirmedEventType: None,
+            confirmations: None,
+            memo: None,
+        };
+        store_notification(&mut db_tx, &record).unwrap();
+        db_tx.execute("UPDATE notifications SET delivered = TRUE WHERE block = 2000000", &[]).unwrap();
+        revert_notifications(&mut db_tx, 1_999_999).unwrap();
+        let undelivered = list_undelivered(&mut db_tx).unwrap();
+        let reverted = undelivered.iter().find(|n| n.block == 2_000_000).unwrap();
+        assert_eq!(reverted.eventType, "reverted");
+        assert_eq!(reverted.revertedEventType.as_deref(), Some("incomingTx"));
+
+        // mined again at the same height
+        store_notification(&mut db_tx, &record).unwrap();
+        let undelivered = list_undelivered(&mut db_tx).unwrap();
+        assert_eq!(undelivered.iter().filter(|n| n.block == 2_000_000).count(), 2);
+        db_tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_store_confirmations() {
+        let config = ZamsConfig::default();
+        let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        db_tx.execute("INSERT INTO blocks(height, hash, time, sapling_tree) VALUES (3000000, '', 0, '')", &[]).unwrap();
+        let record = NotificationRecord {
+            id: 0,
+            eventType: "incomingTx".to_string(),
+            txHash: "d04bb83a234496e033fbd480d24be47a53d38b984cfc575b05bc24580e44a42d".to_string(),
+            account: 1,
+            address: None,
+            txOutputIndex: 0,
+            amount: 1000,
+            block: 2_999_995,
+            revertedEventType: None,
+            confirmedEventType: None,
+            confirmations: None,
+            memo: None,
+        };
+        store_notification(&mut db_tx, &record).unwrap();
+        let mut thresholds = HashMap::new();
+        thresholds.insert("incomingTx".to_string(), vec![1, 6, 24]);
+        store_confirmations(&mut db_tx, &thresholds).unwrap();
+        store_confirmations(&mut db_tx, &thresholds).unwrap();
+        let undelivered = list_undelivered(&mut db_tx).unwrap();
+        let confirmations: Vec<_> = undelivered
+            .iter()
+            .filter(|n| n.block == 2_999_995 && n.eventType == "confirmed")
+            .map(|n| n.confirmations.unwrap())
+            .collect();
+        assert_eq!(confirmations, vec![1, 6]);
+        db_tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_mark_memos_unavailable() {
+        let config = ZamsConfig::default();
+        let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let tx_hash = "e04bb83a234496e033fbd480d24be47a53d38b984cfc575b05bc24580e44a42d";
+        db_tx.execute("INSERT INTO blocks(height, hash, time, sapling_tree) VALUES (3000000, '', 0, '')", &[]).unwrap();
+        db_tx.execute("INSERT INTO transactions(txid, block) VALUES ($1, 3000000)", &[&hex::decode(tx_hash).unwrap()]).unwrap();
+        db_tx.execute("INSERT INTO received_notes(tx, output_index, account, diversifier, address, value, rcm, nf, height)
+        SELECT t.id_tx, 0, (SELECT MIN(account) FROM accounts), '', '', 1000, '', $2, 3000000 FROM transactions t WHERE t.txid = $1", &[
+            &hex::decode(tx_hash).unwrap(),
+            &vec![0xEEu8; 32],
+        ]).unwrap();
+        assert!(list_txs_without_memo(&mut db_tx).unwrap().iter().any(|(h, _)| h == tx_hash));
+        mark_memos_unavailable(&mut db_tx, tx_hash).unwrap();
+        assert!(!list_txs_without_memo(&mut db_tx).unwrap().iter().any(|(h, _)| h == tx_hash));
+        let note = get_note_info(&mut db_tx, tx_hash, 0).unwrap();
+        assert!(note.memo.is_empty());
+        db_tx.rollback().unwrap();
     }
 
     #[test]
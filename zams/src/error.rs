@@ -11,9 +11,11 @@ pub enum WalletError {
     Error(anyhow::Error),
     Postgres(postgres::Error),
     Tonic(tonic::Status),
+    TonicTransport(tonic::transport::Error),
     TxBuilder(zcash_primitives::transaction::builder::Error),
     Reqwest(reqwest::Error),
     Reorg,
+    ReorgTooDeep(u32),
 }
 
 impl From<data_api::error::Error<i32>> for WalletError {
@@ -40,6 +42,12 @@ impl From<tonic::Status> for WalletError {
     }
 }
 
+impl From<tonic::transport::Error> for WalletError {
+    fn from(e: tonic::transport::Error) -> Self {
+        WalletError::TonicTransport(e)
+    }
+}
+
 impl From<hex::FromHexError> for WalletError {
     fn from(_: hex::FromHexError) -> Self {
         WalletError::Error(anyhow!("Could not decode hex string"))
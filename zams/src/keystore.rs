@@ -0,0 +1,281 @@
+use crate::error::WalletError;
+use crate::keys::{generate_sapling_keys, generate_transparent_address, get_bip39_seed};
+use crate::wallet::keyring::Keyring;
+use crate::zams_rpc as grpc;
+use anyhow::{anyhow, Context};
+use argon2::{Algorithm, Argon2, Params, Version};
+use bip39::{Language, Mnemonic, MnemonicType, Seed};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tiny_hderive::bip44::DerivationPath;
+use zcash_primitives::consensus::Parameters;
+
+/// Encrypted with the passphrase key to detect a wrong passphrase
+const CHECK: &[u8] = b"zams keystore";
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Argon2 parameters of the passphrase key, so that a keystore still opens
+/// when the defaults of the library change
+#[derive(Serialize, Deserialize)]
+struct KdfParams {
+    algorithm: String,
+    version: u32,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            algorithm: Algorithm::Argon2id.as_str().to_string(),
+            version: u32::from(Version::V0x13),
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    salt: String,
+    kdf: KdfParams,
+    check: Sealed,
+    seeds: Vec<Sealed>,
+}
+
+/// Seeds encrypted at rest with a key derived from a passphrase (Argon2id),
+/// with ChaCha20-Poly1305. Keys are derived from the seeds by path and never leave the signer
+pub struct Keystore {
+    path: PathBuf,
+    key: [u8; 32],
+    file: KeystoreFile,
+    /// bip39 entropy, by seed id
+    entropies: Vec<Vec<u8>>,
+}
+
+impl Keystore {
+    /// Creates an empty keystore. Fails if the file exists
+    pub fn create(path: &Path, passphrase: &str) -> crate::Result<Keystore> {
+        if path.exists() {
+            return Err(WalletError::Error(anyhow!("The keystore {} already exists", path.display())));
+        }
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let kdf = KdfParams::default();
+        let key = derive_key(passphrase, &salt, &kdf)?;
+        let keystore = Keystore {
+            path: path.to_path_buf(),
+            key,
+            file: KeystoreFile {
+                salt: hex::encode(salt),
+                kdf,
+                check: seal(&key, CHECK)?,
+                seeds: vec![],
+            },
+            entropies: vec![],
+        };
+        keystore.save()?;
+        Ok(keystore)
+    }
+
+    /// Unlocks the keystore. Fails if the passphrase is wrong
+    pub fn open(path: &Path, passphrase: &str) -> crate::Result<Keystore> {
+        let file = fs::read_to_string(path).context("Cannot read the keystore")?;
+        let file: KeystoreFile = serde_json::from_str(&file).context("Invalid keystore")?;
+        let key = derive_key(passphrase, &hex::decode(&file.salt)?, &file.kdf)?;
+        if unseal(&key, &file.check)? != CHECK {
+            return Err(WalletError::Error(anyhow!("Invalid keystore")));
+        }
+        let entropies = file
+            .seeds
+            .iter()
+            .map(|seed| unseal(&key, seed))
+            .collect::<crate::Result<Vec<_>>>()?;
+        Ok(Keystore {
+            path: path.to_path_buf(),
+            key,
+            file,
+            entropies,
+        })
+    }
+
+    /// Adds the seed of the phrase, or a new random seed. Returns its id
+    pub fn add_seed(&mut self, phrase: Option<&str>) -> crate::Result<u32> {
+        let mnemonic = match phrase {
+            Some(phrase) => Mnemonic::from_phrase(phrase, Language::English)?,
+            None => Mnemonic::new(MnemonicType::Words24, Language::English),
+        };
+        let entropy = mnemonic.entropy().to_vec();
+        self.file.seeds.push(seal(&self.key, &entropy)?);
+        self.entropies.push(entropy);
+        self.save()?;
+        Ok((self.entropies.len() - 1) as u32)
+    }
+
+    /// The seed phrases, by seed id. Only for the backup command
+    pub fn backup(&self) -> crate::Result<Vec<String>> {
+        self.entropies
+            .iter()
+            .map(|entropy| Ok(Mnemonic::from_entropy(entropy, Language::English)?.into_phrase()))
+            .collect()
+    }
+
+    /// The transparent address at a BIP-44 path
+    pub fn transparent_address<P: Parameters>(&self, network: &P, key_path: &grpc::KeyPath) -> crate::Result<String> {
+        let (_, address) = generate_transparent_address(network, self.seed(key_path)?, &key_path.path);
+        Ok(address)
+    }
+
+    /// The Sapling full viewing key at a ZIP-32 path
+    pub fn sapling_fvk<P: Parameters>(&self, network: &P, key_path: &grpc::KeyPath) -> crate::Result<String> {
+        let (_, fvk) = generate_sapling_keys(network, self.seed(key_path)?, &key_path.path);
+        Ok(fvk)
+    }
+
+    /// The keys of the inputs of the transaction, from their key paths
+    pub fn keyring<P: Parameters>(&self, network: &P, unsigned_tx: &grpc::UnsignedTx) -> crate::Result<Keyring> {
+        let mut keys: Vec<String> = vec![];
+        for key_path in unsigned_tx.trp_inputs.iter().filter_map(|input| input.key_path.as_ref()) {
+            let (sk, _) = generate_transparent_address(network, self.seed(key_path)?, &key_path.path);
+            keys.push(sk);
+        }
+        for key_path in unsigned_tx.sap_inputs.iter().filter_map(|input| input.key_path.as_ref()) {
+            let (sk, _) = generate_sapling_keys(network, self.seed(key_path)?, &key_path.path);
+            keys.push(sk);
+        }
+        keys.sort();
+        keys.dedup();
+        Keyring::new(network, &keys)
+    }
+
+    fn seed(&self, key_path: &grpc::KeyPath) -> crate::Result<Seed> {
+        key_path
+            .path
+            .parse::<DerivationPath>()
+            .map_err(|_| WalletError::Error(anyhow!("Invalid path {}", key_path.path)))?;
+        let entropy = self
+            .entropies
+            .get(key_path.id_seed as usize)
+            .ok_or_else(|| WalletError::Error(anyhow!("Unknown seed {}", key_path.id_seed)))?;
+        get_bip39_seed(grpc::Entropy {
+            type_of_entropy: Some(grpc::entropy::TypeOfEntropy::Hex(hex::encode(entropy))),
+            path: String::new(),
+        })
+    }
+
+    fn save(&self) -> crate::Result<()> {
+        let file = serde_json::to_string_pretty(&self.file).context("Cannot serialize the keystore")?;
+        let tmp_path = self.path.with_extension("tmp");
+        // left over by an interrupted save, and maybe with other permissions
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path).map_err(WalletError::IO)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut tmp_file = options.open(&tmp_path).map_err(WalletError::IO)?;
+        tmp_file.write_all(file.as_bytes()).map_err(WalletError::IO)?;
+        tmp_file.sync_all().map_err(WalletError::IO)?;
+        fs::rename(&tmp_path, &self.path).map_err(WalletError::IO)?;
+        Ok(())
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], kdf: &KdfParams) -> crate::Result<[u8; 32]> {
+    let kdf_error = |e: argon2::Error| WalletError::Error(anyhow!("Invalid keystore key parameters: {}", e));
+    let algorithm = kdf.algorithm.parse::<Algorithm>().map_err(kdf_error)?;
+    let version = Version::try_from(kdf.version).map_err(kdf_error)?;
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32)).map_err(kdf_error)?;
+    let mut key = [0u8; 32];
+    Argon2::new(algorithm, version, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| WalletError::Error(anyhow!("Cannot derive the keystore key: {}", e)))?;
+    Ok(key)
+}
+
+fn seal(key: &[u8; 32], plaintext: &[u8]) -> crate::Result<Sealed> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| WalletError::Error(anyhow!("Cannot encrypt the seed")))?;
+    Ok(Sealed {
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+fn unseal(key: &[u8; 32], sealed: &Sealed) -> crate::Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = hex::decode(&sealed.nonce)?;
+    if nonce.len() != 12 {
+        return Err(WalletError::Error(anyhow!("Invalid keystore")));
+    }
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), hex::decode(&sealed.ciphertext)?.as_slice())
+        .map_err(|_| WalletError::Error(anyhow!("Wrong passphrase")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zcash_primitives::consensus::TestNetwork;
+
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn test_keystore() {
+        let path = std::env::temp_dir().join(format!("zams-keystore-{}.json", OsRng.next_u64()));
+        let mut keystore = Keystore::create(&path, "passphrase").unwrap();
+        assert_eq!(keystore.add_seed(Some(PHRASE)).unwrap(), 0);
+        assert_eq!(keystore.add_seed(None).unwrap(), 1);
+        assert!(!fs::read_to_string(&path).unwrap().contains("abandon"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let file: KeystoreFile = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(file.kdf.algorithm, "argon2id");
+        assert_eq!(file.kdf.m_cost, Params::DEFAULT_M_COST);
+
+        assert!(Keystore::open(&path, "wrong").is_err());
+        let keystore = Keystore::open(&path, "passphrase").unwrap();
+        assert_eq!(keystore.backup().unwrap()[0], PHRASE);
+
+        let key_path = grpc::KeyPath {
+            id_seed: 0,
+            path: "m/44'/1'/0'/0/0".to_string(),
+        };
+        let seed = get_bip39_seed(grpc::Entropy {
+            type_of_entropy: Some(grpc::entropy::TypeOfEntropy::SeedPhrase(PHRASE.to_string())),
+            path: String::new(),
+        })
+        .unwrap();
+        let (_, address) = generate_transparent_address(&TestNetwork, seed, &key_path.path);
+        assert_eq!(keystore.transparent_address(&TestNetwork, &key_path).unwrap(), address);
+
+        let unknown_seed = grpc::KeyPath {
+            id_seed: 2,
+            ..key_path
+        };
+        assert!(keystore.transparent_address(&TestNetwork, &unknown_seed).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,32 +1,54 @@
 #[path = "generated/zams.rs"]
 pub mod zams_rpc;
 
+#[path = "generated/cash.z.wallet.sdk.rpc.rs"]
+pub mod lwd_rpc;
+
 pub type Result<T> = std::result::Result<T, WalletError>;
 
 pub mod config;
 pub mod error;
+pub mod network;
+pub mod unified;
 
+mod checkpoints;
 mod db;
 mod keys;
+mod keystore;
+mod mempool;
 mod perfcounters;
+mod scheduler;
 mod trp;
 mod wallet;
 mod notification;
+mod payment;
 mod utils;
 
 pub use crate::config::ZamsConfig;
 pub use crate::db::{
-    cancel_payment, generate_address, get_balance, get_payment_info, import_address, import_fvk,
-    list_pending_payments, DbPreparedStatements,
+    cancel_payment, fail_interrupted_rescans, generate_address, get_balance, get_note_info, get_payment_info,
+    get_rescan_status, get_tx_status, import_address, import_fvk, import_ufvk, list_pending_payments,
+    set_account_key_path, set_fvk_key_path, DbPreparedStatements,
 };
 pub use crate::error::WalletError;
 pub use crate::keys::{generate_sapling_keys, generate_transparent_address, get_bip39_seed};
+pub use crate::keystore::Keystore;
+pub use crate::mempool::start_mempool_watcher;
 pub use crate::perfcounters::{metrics_handler, register_custom_metrics, REGISTRY, REQUESTS};
+pub use crate::scheduler::start_sync_scheduler;
 pub use crate::trp::zcashdrpc::get_latest_height;
 pub use crate::trp::TrpWallet;
 pub use crate::utils::{populate_taddr, populate_zaddr};
-pub use crate::wallet::scan::{load_checkpoint, rewind_to_height, scan_chain};
-pub use crate::wallet::transaction::{broadcast_tx, prepare_tx, sign_tx};
+pub use crate::checkpoints::Checkpoint;
+pub use crate::wallet::coin_selector::CoinSelector;
+pub use crate::wallet::fee::{FeeRule, TxShape};
+pub use crate::wallet::keyring::Keyring;
+pub use crate::wallet::rescan::{rescan, start_rescan, RescanKey};
+pub use crate::wallet::scan::{export_checkpoint, load_checkpoint, reset_to_checkpoint, rewind_to_height, scan_chain};
+pub use crate::wallet::transaction::{
+    broadcast_tx, prepare_batch_tx, prepare_consolidation, prepare_sweep, prepare_tx, reissue_payment, sign_tx,
+    ConsolidationSource,
+};
 
 pub const ZATPERZEC: f64 = 1e8;
 
@@ -0,0 +1,196 @@
+use crate::db;
+use crate::notification::{notify_tx, NotificationRecord};
+use crate::trp::zcashdrpc::{get_latest_height, get_raw_mempool, get_raw_transaction_hex};
+use crate::wallet::PostgresWallet;
+use crate::{WalletError, ZamsConfig};
+use postgres::Client;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use zcash_client_backend::data_api::WalletRead;
+use zcash_client_backend::decrypt_transaction;
+use zcash_client_backend::encoding::{encode_payment_address, encode_transparent_address};
+use zcash_primitives::consensus::{BlockHeight, Parameters};
+use zcash_client_backend::wallet::AccountId;
+use zcash_primitives::transaction::Transaction;
+use zcash_primitives::zip32::ExtendedFullViewingKey;
+
+/// Number of blocks after which a transaction that left the mempool
+/// without being scanned is considered dropped
+const DROP_DEPTH: u32 = 10;
+
+pub struct MempoolWatcher {
+    client: Arc<Mutex<Client>>,
+    scan_lock: Arc<Mutex<()>>,
+    config: ZamsConfig,
+    seen: HashSet<String>,
+}
+
+impl MempoolWatcher {
+    pub fn new(client: Arc<Mutex<Client>>, scan_lock: Arc<Mutex<()>>, config: &ZamsConfig) -> MempoolWatcher {
+        MempoolWatcher {
+            client,
+            scan_lock,
+            config: config.clone(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Checks the new mempool transactions for incoming payments, then
+    /// follows up on the pending ones that left the mempool
+    pub fn poll(&mut self) -> crate::Result<()> {
+        let tip_height = get_latest_height(&self.config)?;
+        let r = Runtime::new().unwrap();
+        let txids = r.block_on(async {
+            let client = reqwest::Client::new();
+            get_raw_mempool(&client, &self.config).await
+        })?;
+        let mempool: HashSet<String> = txids.into_iter().collect();
+        let new_txids: Vec<String> = mempool.difference(&self.seen).cloned().collect();
+
+        if !new_txids.is_empty() {
+            let raw_txs = r.block_on(async {
+                let client = reqwest::Client::new();
+                let mut raw_txs: Vec<(String, String)> = vec![];
+                for txid in new_txids.iter() {
+                    // the tx may have been mined or evicted since we got the mempool
+                    if let Ok(raw_tx) = get_raw_transaction_hex(txid, &client, &self.config).await {
+                        raw_txs.push((txid.clone(), raw_tx));
+                    }
+                }
+                raw_txs
+            });
+            let wallet = PostgresWallet::new(self.client.clone(), &self.config)?;
+            let extfvks = wallet.get_extended_full_viewing_keys()?;
+            let addresses: HashMap<String, i32> = {
+                let mut c = self.client.lock().unwrap();
+                db::get_all_trp_addresses(&mut *c)?
+            }
+            .into_iter()
+            .map(|(id, address)| (address, id))
+            .collect();
+
+            let mut notifications: Vec<NotificationRecord> = vec![];
+            for (txid, raw_tx) in raw_txs.iter() {
+                let tx = Transaction::read(&*hex::decode(raw_tx)?).map_err(WalletError::IO)?;
+                self.scan_tx(txid, &tx, tip_height + 1, &extfvks, &addresses, &mut notifications)?;
+            }
+            let mut c = self.client.lock().unwrap();
+            for n in notifications.iter() {
+                db::store_notification(&mut *c, n)?;
+            }
+        }
+        self.seen = mempool;
+
+        self.update_pending(tip_height)?;
+
+        // The scan delivers the notifications of mined txs once their memos are fetched
+        let _lock = self.scan_lock.lock().unwrap();
+        let mut c = self.client.lock().unwrap();
+        let _ = notify_tx(&mut *c, &self.config); // ignore failures - will retry
+        Ok(())
+    }
+
+    fn scan_tx(
+        &self,
+        txid: &str,
+        tx: &Transaction,
+        height: u32,
+        extfvks: &HashMap<AccountId, ExtendedFullViewingKey>,
+        addresses: &HashMap<String, i32>,
+        notifications: &mut Vec<NotificationRecord>,
+    ) -> crate::Result<()> {
+        let network = &self.config.network;
+        let mut c = self.client.lock().unwrap();
+        if db::is_tx_mined(&mut *c, txid)? {
+            return Ok(());
+        }
+
+        let mut outputs: Vec<(i32, bool, i32, i64)> = vec![];
+        for (index, output) in tx.vout.iter().enumerate() {
+            if let Some(address) = output.script_pubkey.address() {
+                let address = encode_transparent_address(
+                    &network.b58_pubkey_address_prefix(),
+                    &network.b58_script_address_prefix(),
+                    &address,
+                );
+                if let Some(account) = addresses.get(&address) {
+                    outputs.push((index as i32, false, *account, i64::from(output.value)));
+                }
+            }
+        }
+        let decrypted = decrypt_transaction(network, BlockHeight::from_u32(height), tx, extfvks);
+        for output in decrypted.iter().filter(|o| !o.outgoing) {
+            let address = encode_payment_address(network.hrp_sapling_payment_address(), &output.to);
+            let row = c.query_opt("SELECT account FROM accounts WHERE address = $1", &[&address])?;
+            if let Some(row) = row {
+                let account: i32 = row.get(0);
+                outputs.push((output.index as i32, true, account, output.note.value as i64));
+            }
+        }
+
+        for (output_index, shielded, account, amount) in outputs {
+            if db::store_mempool_output(&mut *c, txid, output_index, shielded, account, amount)? {
+                notifications.push(NotificationRecord {
+                    id: 0, // ignored
+                    eventType: "pendingIncomingTx".to_string(),
+                    txHash: txid.to_string(),
+                    account,
+                    address: None,
+                    txOutputIndex: output_index,
+                    amount,
+                    block: 0,
+                    revertedEventType: None,
+                    confirmedEventType: None,
+                    confirmations: None,
+                    memo: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Pending txs become confirmed when the scanner picks them up. The incomingTx
+    // event is the confirmation. If they are not scanned DROP_DEPTH blocks after they
+    // left the mempool, they are dropped
+    fn update_pending(&self, tip_height: u32) -> crate::Result<()> {
+        let mut c = self.client.lock().unwrap();
+        let pending = db::list_pending_mempool_txs(&mut *c)?;
+        for (txid, left_height) in pending {
+            if self.seen.contains(&txid) {
+                continue;
+            }
+            if db::is_tx_mined(&mut *c, &txid)? {
+                db::update_mempool_tx(&mut *c, &txid, left_height, "confirmed")?;
+            } else {
+                match left_height {
+                    None => db::update_mempool_tx(&mut *c, &txid, Some(tip_height), "pending")?,
+                    Some(left_height) if tip_height >= left_height + DROP_DEPTH => {
+                        log::info!("Mempool tx {} dropped", txid);
+                        db::drop_mempool_tx(&mut *c, &txid)?
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Polls the mempool every `mempool_interval` seconds in a background thread.
+/// `scan_lock` is the lock of the main scan
+pub fn start_mempool_watcher(client: Arc<Mutex<Client>>, scan_lock: Arc<Mutex<()>>, config: &ZamsConfig) {
+    if config.mempool_interval == 0 {
+        return;
+    }
+    let mut watcher = MempoolWatcher::new(client, scan_lock, config);
+    let interval = Duration::from_secs(config.mempool_interval);
+    thread::spawn(move || loop {
+        if let Err(e) = watcher.poll() {
+            log::error!("Mempool scan failed: {:?}", e);
+        }
+        thread::sleep(interval);
+    });
+}
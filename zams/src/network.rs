@@ -0,0 +1,286 @@
+use crate::config::{config_error, get_uint, required};
+use configparser::ini::Ini;
+use zcash_primitives::consensus::{BlockHeight, MainNetwork, Network, NetworkUpgrade, Parameters, TestNetwork};
+
+const UPGRADES: [(NetworkUpgrade, &str); 5] = [
+    (NetworkUpgrade::Overwinter, "overwinter_height"),
+    (NetworkUpgrade::Sapling, "sapling_height"),
+    (NetworkUpgrade::Blossom, "blossom_height"),
+    (NetworkUpgrade::Heartwood, "heartwood_height"),
+    (NetworkUpgrade::Canopy, "canopy_height"),
+];
+
+/// Consensus and encoding parameters of the chain ZAMS runs on.
+/// Mainnet and testnet use the librustzcash constants, regtest and custom
+/// networks (Zcash forks) take theirs from the config file
+#[derive(Debug, Clone)]
+pub struct ZamsNetwork {
+    pub name: String,
+    activation_heights: Vec<(NetworkUpgrade, BlockHeight)>,
+    coin_type: u32,
+    hrp_sapling_extended_spending_key: String,
+    hrp_sapling_extended_full_viewing_key: String,
+    hrp_sapling_payment_address: String,
+    b58_pubkey_address_prefix: [u8; 2],
+    b58_script_address_prefix: [u8; 2],
+    hrp_unified_address: Option<String>,
+    hrp_unified_fvk: Option<String>,
+}
+
+/// ZIP-316 human readable parts, which our version of librustzcash predates
+pub trait UnifiedParameters: Parameters {
+    /// None if the network has no unified addresses
+    fn hrp_unified_address(&self) -> Option<&str>;
+    fn hrp_unified_fvk(&self) -> Option<&str>;
+}
+
+impl ZamsNetwork {
+    fn from_params<P: UnifiedParameters>(name: &str, params: &P) -> ZamsNetwork {
+        ZamsNetwork {
+            name: name.to_string(),
+            activation_heights: UPGRADES
+                .iter()
+                .filter_map(|(nu, _)| params.activation_height(*nu).map(|h| (*nu, h)))
+                .collect(),
+            coin_type: params.coin_type(),
+            hrp_sapling_extended_spending_key: params.hrp_sapling_extended_spending_key().to_string(),
+            hrp_sapling_extended_full_viewing_key: params.hrp_sapling_extended_full_viewing_key().to_string(),
+            hrp_sapling_payment_address: params.hrp_sapling_payment_address().to_string(),
+            b58_pubkey_address_prefix: params.b58_pubkey_address_prefix(),
+            b58_script_address_prefix: params.b58_script_address_prefix(),
+            hrp_unified_address: params.hrp_unified_address().map(str::to_string),
+            hrp_unified_fvk: params.hrp_unified_fvk().map(str::to_string),
+        }
+    }
+
+    pub fn main() -> ZamsNetwork {
+        ZamsNetwork::from_params("main", &MainNetwork)
+    }
+
+    pub fn test() -> ZamsNetwork {
+        ZamsNetwork::from_params("test", &TestNetwork)
+    }
+
+    /// zcashd -regtest with every upgrade active from block 1 unless overridden
+    /// Regtest encodings. The activation heights depend on how the node
+    /// was started, so [`ZamsNetwork::from_config`] requires all of them
+    pub fn regtest() -> ZamsNetwork {
+        ZamsNetwork {
+            name: "regtest".to_string(),
+            activation_heights: vec![],
+            coin_type: 1,
+            hrp_sapling_extended_spending_key: "secret-extended-key-regtest".to_string(),
+            hrp_sapling_extended_full_viewing_key: "zxviewregtestsapling".to_string(),
+            hrp_sapling_payment_address: "zregtestsapling".to_string(),
+            b58_pubkey_address_prefix: [0x1d, 0x25],
+            b58_script_address_prefix: [0x1c, 0xba],
+            hrp_unified_address: Some("uregtest".to_string()),
+            hrp_unified_fvk: Some("uviewregtest".to_string()),
+        }
+    }
+
+    /// Reads the `network` option of the [zams] section (main, test, regtest or custom).
+    /// Falls back on the `testnet` flag.
+    /// Regtest needs every upgrade height, custom networks at least Overwinter and Sapling
+    pub fn from_config(conf: &Ini) -> crate::Result<ZamsNetwork> {
+        let name = match conf.get("zams", "network") {
+            Some(name) => name,
+            None => {
+                let testnet = conf
+                    .getbool("zams", "testnet")
+                    .map_err(|e| config_error("testnet", &e))?
+                    .unwrap_or(false);
+                let name = if testnet { "test" } else { "main" };
+                name.to_string()
+            }
+        };
+        let (mut network, required_upgrades) = match name.as_str() {
+            "main" => return Ok(ZamsNetwork::main()),
+            "test" => return Ok(ZamsNetwork::test()),
+            "regtest" => (ZamsNetwork::regtest(), UPGRADES.len()),
+            "custom" => (
+                ZamsNetwork {
+                    name,
+                    activation_heights: vec![],
+                    coin_type: get_uint(conf, "coin_type")?
+                        .ok_or_else(|| config_error("coin_type", "is required"))? as u32,
+                    hrp_sapling_extended_spending_key: required(conf, "hrp_sapling_extended_spending_key")?,
+                    hrp_sapling_extended_full_viewing_key: required(conf, "hrp_sapling_extended_full_viewing_key")?,
+                    hrp_sapling_payment_address: required(conf, "hrp_sapling_payment_address")?,
+                    b58_pubkey_address_prefix: parse_prefix(conf, "b58_pubkey_address_prefix")?,
+                    b58_script_address_prefix: parse_prefix(conf, "b58_script_address_prefix")?,
+                    hrp_unified_address: conf.get("zams", "hrp_unified_address"),
+                    hrp_unified_fvk: conf.get("zams", "hrp_unified_fvk"),
+                },
+                2,
+            ),
+            other => return Err(config_error("network", &format!("unknown network {}", other))),
+        };
+        for (i, (nu, key)) in UPGRADES.iter().enumerate() {
+            match get_uint(conf, key)? {
+                Some(height) => network.activation_heights.push((*nu, BlockHeight::from_u32(height as u32))),
+                None if i < required_upgrades => return Err(config_error(key, "is required")),
+                None => {}
+            }
+        }
+        Ok(network)
+    }
+}
+
+// Two bytes in hex, e.g. 1cb8
+fn parse_prefix(conf: &Ini, key: &str) -> crate::Result<[u8; 2]> {
+    let prefix = required(conf, key)?;
+    let mut bytes = [0u8; 2];
+    hex::decode_to_slice(&prefix, &mut bytes).map_err(|_| config_error(key, "expected two bytes in hex"))?;
+    Ok(bytes)
+}
+
+impl Parameters for ZamsNetwork {
+    fn activation_height(&self, nu: NetworkUpgrade) -> Option<BlockHeight> {
+        self.activation_heights
+            .iter()
+            .find(|(n, _)| *n == nu)
+            .map(|(_, height)| *height)
+    }
+
+    fn coin_type(&self) -> u32 {
+        self.coin_type
+    }
+
+    fn hrp_sapling_extended_spending_key(&self) -> &str {
+        &self.hrp_sapling_extended_spending_key
+    }
+
+    fn hrp_sapling_extended_full_viewing_key(&self) -> &str {
+        &self.hrp_sapling_extended_full_viewing_key
+    }
+
+    fn hrp_sapling_payment_address(&self) -> &str {
+        &self.hrp_sapling_payment_address
+    }
+
+    fn b58_pubkey_address_prefix(&self) -> [u8; 2] {
+        self.b58_pubkey_address_prefix
+    }
+
+    fn b58_script_address_prefix(&self) -> [u8; 2] {
+        self.b58_script_address_prefix
+    }
+}
+
+impl UnifiedParameters for ZamsNetwork {
+    fn hrp_unified_address(&self) -> Option<&str> {
+        self.hrp_unified_address.as_deref()
+    }
+
+    fn hrp_unified_fvk(&self) -> Option<&str> {
+        self.hrp_unified_fvk.as_deref()
+    }
+}
+
+impl UnifiedParameters for MainNetwork {
+    fn hrp_unified_address(&self) -> Option<&str> {
+        Some("u")
+    }
+
+    fn hrp_unified_fvk(&self) -> Option<&str> {
+        Some("uview")
+    }
+}
+
+impl UnifiedParameters for TestNetwork {
+    fn hrp_unified_address(&self) -> Option<&str> {
+        Some("utest")
+    }
+
+    fn hrp_unified_fvk(&self) -> Option<&str> {
+        Some("uviewtest")
+    }
+}
+
+impl UnifiedParameters for Network {
+    fn hrp_unified_address(&self) -> Option<&str> {
+        match self {
+            Network::MainNetwork => Some("u"),
+            Network::TestNetwork => Some("utest"),
+        }
+    }
+
+    fn hrp_unified_fvk(&self) -> Option<&str> {
+        match self {
+            Network::MainNetwork => Some("uview"),
+            Network::TestNetwork => Some("uviewtest"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CUSTOM: &str = "[zams]
+network=custom
+coin_type=1234
+hrp_sapling_extended_spending_key=secret-extended-key-fork
+hrp_sapling_extended_full_viewing_key=zxviewfork
+hrp_sapling_payment_address=zfork
+b58_pubkey_address_prefix=1cb8
+b58_script_address_prefix=1cbd
+overwinter_height=50
+sapling_height=100";
+
+    fn error_message(conf: &str) -> String {
+        let mut ini = Ini::new();
+        ini.read(conf.to_string()).unwrap();
+        format!("{:?}", ZamsNetwork::from_config(&ini).unwrap_err())
+    }
+
+    #[test]
+    fn test_custom_network() {
+        let mut conf = Ini::new();
+        conf.read(CUSTOM.to_string()).unwrap();
+        let network = ZamsNetwork::from_config(&conf).unwrap();
+        assert_eq!(network.coin_type(), 1234);
+        assert_eq!(network.hrp_sapling_payment_address(), "zfork");
+        assert_eq!(network.b58_pubkey_address_prefix(), [0x1c, 0xb8]);
+        assert_eq!(network.activation_height(NetworkUpgrade::Sapling), Some(BlockHeight::from_u32(100)));
+        assert_eq!(network.activation_height(NetworkUpgrade::Canopy), None);
+        assert_eq!(network.hrp_unified_address(), None);
+    }
+
+    #[test]
+    fn test_regtest_network() {
+        let mut conf = Ini::new();
+        conf.read(
+            "[zams]
+network=regtest
+overwinter_height=1
+sapling_height=1
+blossom_height=1
+heartwood_height=1
+canopy_height=200"
+                .to_string(),
+        )
+        .unwrap();
+        let network = ZamsNetwork::from_config(&conf).unwrap();
+        assert_eq!(network.activation_height(NetworkUpgrade::Sapling), Some(BlockHeight::from_u32(1)));
+        assert_eq!(network.activation_height(NetworkUpgrade::Canopy), Some(BlockHeight::from_u32(200)));
+        assert_eq!(ZamsNetwork::test().hrp_sapling_payment_address(), "ztestsapling");
+        assert_eq!(network.hrp_unified_address(), Some("uregtest"));
+        assert_eq!(ZamsNetwork::main().hrp_unified_fvk(), Some("uview"));
+    }
+
+    #[test]
+    fn test_config_errors_name_the_key() {
+        let message = error_message("[zams]\nnetwork=regtest\ncanopy_height=200");
+        assert!(message.contains("overwinter_height"), "{}", message);
+        let message = error_message(&CUSTOM.replace("sapling_height=100", ""));
+        assert!(message.contains("sapling_height"), "{}", message);
+        let message = error_message(&CUSTOM.replace("1cbd", "1c"));
+        assert!(message.contains("b58_script_address_prefix"), "{}", message);
+        let message = error_message(&CUSTOM.replace("coin_type=1234", "coin_type=abc"));
+        assert!(message.contains("coin_type"), "{}", message);
+        let message = error_message("[zams]\nnetwork=unknown");
+        assert!(message.contains("network"), "{}", message);
+    }
+}
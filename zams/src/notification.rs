@@ -16,6 +16,10 @@ pub struct NotificationRecord {
     pub txOutputIndex: i32,
     pub amount: i64,
     pub block: u32,
+    pub revertedEventType: Option<String>,
+    pub confirmedEventType: Option<String>,
+    pub confirmations: Option<u32>,
+    pub memo: Option<String>,
 }
 
 pub fn notify_tx(client: &mut Client, config: &ZamsConfig) -> crate::Result<()> {
@@ -62,6 +66,10 @@ mod tests {
             txOutputIndex: 5,
             amount: 1000,
             block: 1447639,
+            revertedEventType: None,
+            confirmedEventType: None,
+            confirmations: None,
+            memo: None,
         };
         crate::db::store_notification(&mut client, &record).unwrap();
         notify_tx(&mut client, &config).unwrap();
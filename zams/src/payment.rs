@@ -0,0 +1,107 @@
+use crate::error::WalletError;
+use anyhow::anyhow;
+use std::str::FromStr;
+
+/// Lifecycle of an outgoing payment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentState {
+    Prepared,
+    Signed,
+    Broadcast,
+    Mined,
+    Confirmed,
+    Expired,
+    Failed,
+    Cancelled,
+}
+
+impl PaymentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentState::Prepared => "prepared",
+            PaymentState::Signed => "signed",
+            PaymentState::Broadcast => "broadcast",
+            PaymentState::Mined => "mined",
+            PaymentState::Confirmed => "confirmed",
+            PaymentState::Expired => "expired",
+            PaymentState::Failed => "failed",
+            PaymentState::Cancelled => "cancelled",
+        }
+    }
+
+    /// The notes and utxos of the payment go back to the available balance
+    pub fn releases_inputs(&self) -> bool {
+        matches!(self, PaymentState::Expired | PaymentState::Failed | PaymentState::Cancelled)
+    }
+
+    /// The transaction was sent to the network
+    pub fn is_paid(&self) -> bool {
+        matches!(self, PaymentState::Broadcast | PaymentState::Mined | PaymentState::Confirmed)
+    }
+
+    pub fn can_transition_to(&self, to: PaymentState) -> bool {
+        use PaymentState::*;
+        match (self, to) {
+            (Prepared, Signed) | (Prepared, Cancelled) | (Prepared, Expired) => true,
+            (Signed, Broadcast) | (Signed, Failed) | (Signed, Cancelled) | (Signed, Expired) => true,
+            // broadcast again after zcashd could not be reached
+            (Signed, Signed) => true,
+            (Broadcast, Mined) | (Broadcast, Failed) | (Broadcast, Expired) => true,
+            // the block that mined the transaction can be reorged out
+            (Mined, Confirmed) | (Mined, Broadcast) => true,
+            _ => false,
+        }
+    }
+
+    pub fn check_transition(&self, to: PaymentState) -> crate::Result<()> {
+        if !self.can_transition_to(to) {
+            return Err(WalletError::Error(anyhow!(
+                "Payment cannot go from {} to {}",
+                self.as_str(),
+                to.as_str()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PaymentState {
+    type Err = WalletError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let state = match s {
+            "prepared" => PaymentState::Prepared,
+            "signed" => PaymentState::Signed,
+            "broadcast" => PaymentState::Broadcast,
+            "mined" => PaymentState::Mined,
+            "confirmed" => PaymentState::Confirmed,
+            "expired" => PaymentState::Expired,
+            "failed" => PaymentState::Failed,
+            "cancelled" => PaymentState::Cancelled,
+            _ => return Err(WalletError::Error(anyhow!("Unknown payment state {}", s))),
+        };
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payment_transitions() {
+        use PaymentState::*;
+        assert!(Prepared.can_transition_to(Cancelled));
+        assert!(Signed.can_transition_to(Broadcast));
+        assert!(Signed.can_transition_to(Signed));
+        assert!(!Prepared.can_transition_to(Prepared));
+        assert!(Mined.can_transition_to(Broadcast));
+        assert!(!Mined.can_transition_to(Cancelled));
+        assert!(!Broadcast.can_transition_to(Cancelled));
+        assert!(!Cancelled.can_transition_to(Signed));
+        assert!(Confirmed.check_transition(Failed).is_err());
+        for state in [Prepared, Signed, Broadcast, Mined, Confirmed, Expired, Failed, Cancelled].iter() {
+            assert_eq!(PaymentState::from_str(state.as_str()).unwrap(), *state);
+        }
+    }
+}
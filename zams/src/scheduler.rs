@@ -0,0 +1,107 @@
+use crate::wallet::scan::scan_chain;
+use crate::ZamsConfig;
+use postgres::Client;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Starts a background thread that scans the chain every `sync_interval` seconds
+/// and whenever something is sent through the returned channel (blocknotify, zmq).
+/// `scan_lock` must be held by anyone else who calls `scan_chain` or rewinds
+pub fn start_sync_scheduler(
+    client: Arc<Mutex<Client>>,
+    scan_lock: Arc<Mutex<()>>,
+    config: &ZamsConfig,
+) -> Sender<()> {
+    let (tx, rx) = channel::<()>();
+    let scheduler_config = config.clone();
+    thread::spawn(move || run_scheduler(client, scan_lock, rx, &scheduler_config));
+
+    start_zmq_listener(config, tx.clone());
+
+    tx
+}
+
+#[cfg(feature = "zmq")]
+fn start_zmq_listener(config: &ZamsConfig, trigger: Sender<()>) {
+    if let Some(endpoint) = config.zmq_hashblock.clone() {
+        thread::spawn(move || {
+            if let Err(e) = listen_hashblock(&endpoint, trigger) {
+                log::error!("ZMQ listener stopped: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(not(feature = "zmq"))]
+fn start_zmq_listener(config: &ZamsConfig, _trigger: Sender<()>) {
+    if config.zmq_hashblock.is_some() {
+        log::warn!("zmq_hashblock is ignored because ZAMS was built without the zmq feature");
+    }
+}
+
+fn run_scheduler(
+    client: Arc<Mutex<Client>>,
+    scan_lock: Arc<Mutex<()>>,
+    triggers: Receiver<()>,
+    config: &ZamsConfig,
+) {
+    let interval = Duration::from_secs(config.sync_interval);
+    let max_backoff = Duration::from_secs(config.sync_max_backoff).max(MIN_BACKOFF);
+    let mut backoff: Option<Duration> = None;
+    loop {
+        match backoff {
+            Some(backoff) => thread::sleep(backoff),
+            None => {
+                let triggered = if interval.as_secs() == 0 {
+                    triggers.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                } else {
+                    triggers.recv_timeout(interval)
+                };
+                if let Err(RecvTimeoutError::Disconnected) = triggered {
+                    break;
+                }
+            }
+        }
+        // Several block notifications may have arrived during the previous scan
+        // but one scan catches up with all of them
+        while triggers.try_recv().is_ok() {}
+
+        let res = {
+            let _lock = scan_lock.lock().unwrap();
+            scan_chain(client.clone(), config)
+        };
+        match res {
+            Ok(height) => {
+                log::info!("Synced to height {}", height);
+                backoff = None;
+            }
+            Err(e) => {
+                let next = backoff.map(|b| (b * 2).min(max_backoff)).unwrap_or(MIN_BACKOFF);
+                log::error!("Sync failed: {:?} - retrying in {:?}", e, next);
+                backoff = Some(next);
+            }
+        }
+    }
+    log::info!("Sync scheduler stopped");
+}
+
+#[cfg(feature = "zmq")]
+fn listen_hashblock(endpoint: &str, trigger: Sender<()>) -> crate::Result<()> {
+    use anyhow::Context;
+
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::SUB).context("Cannot create ZMQ socket")?;
+    socket.connect(endpoint).context("Cannot connect to ZMQ endpoint")?;
+    socket.set_subscribe(b"hashblock").context("Cannot subscribe to hashblock")?;
+    log::info!("Listening for hashblock on {}", endpoint);
+    loop {
+        socket.recv_multipart(0).context("Cannot receive ZMQ message")?;
+        if trigger.send(()).is_err() {
+            return Ok(());
+        }
+    }
+}
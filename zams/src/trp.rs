@@ -1,14 +1,13 @@
 use crate::db::{trp_rewind_to_height, DbPreparedStatements, store_notification};
 use crate::error::WalletError;
-use crate::trp::zcashdrpc::{get_block, Block, Transaction};
+use crate::trp::zcashdrpc::{stream_blocks, Block, Transaction};
 
-use postgres::Client;
+use postgres::{Client, GenericClient};
 
 use std::collections::HashMap;
 use std::ops::Range;
 
 use std::sync::{Arc, Mutex};
-use tokio::runtime::Runtime;
 use crate::{db, ZATPERZEC, ZamsConfig};
 use crate::notification::NotificationRecord;
 
@@ -31,30 +30,45 @@ impl BlockSource {
     where
         F: FnMut(Block) -> Result<(), WalletError>,
     {
-        let r = Runtime::new().unwrap();
-        let client = reqwest::Client::new();
-        for height in range {
-            let block = r.block_on(get_block(&height.to_string(), &client, &self.config))?;
-            let prev_block_hash = {
-                let mut client = self.client.lock().unwrap();
-                db::get_block_by_height(&mut *client, height - 1)?
-            };
-            if let (Some(ph), Some(ph2)) = (prev_block_hash, block.previousblockhash.as_ref()) {
-                if hex::encode(ph) != *ph2 {
-                    return Err(WalletError::Reorg)
-                }
-            }
+        for block in stream_blocks(range, &self.config) {
+            let block = block?;
+            check_block_connects(&self.client, &block)?;
             with_block(block)?;
         }
         Ok(())
     }
 }
 
+fn check_block_connects(client: &Arc<Mutex<Client>>, block: &Block) -> Result<(), WalletError> {
+    let prev_block_hash = {
+        let mut client = client.lock().unwrap();
+        db::get_block_by_height(&mut *client, block.height - 1)?
+    };
+    if let (Some(ph), Some(ph2)) = (prev_block_hash, block.previousblockhash.as_ref()) {
+        if hex::encode(ph) != *ph2 {
+            return Err(WalletError::Reorg)
+        }
+    }
+    Ok(())
+}
+
 pub struct TrpWallet {
     config: ZamsConfig,
     client: Arc<Mutex<Client>>,
     statements: DbPreparedStatements,
     addresses: HashMap<String, i32>,
+    utxos: HashMap<OutPoint, Utxo>,
+}
+
+/// (tx hash, output index)
+type OutPoint = (String, u32);
+
+pub struct Utxo {
+    pub account: i32,
+    pub address: String,
+    pub value: i64,
+    pub script: Vec<u8>,
+    pub height: u32,
 }
 
 impl TrpWallet {
@@ -66,79 +80,100 @@ impl TrpWallet {
             client: c.clone(),
             statements,
             addresses: HashMap::new(),
+            utxos: HashMap::new(),
         })
     }
 
-    fn scan_inputs(&self, tx: &Transaction, notifications: &mut Vec<NotificationRecord>, client: &mut Client) -> Result<(), WalletError> {
+    // Plain zcashd does not give the address and value of the inputs (they need
+    // -insightexplorer) so we look up the outpoint in our own utxos
+    fn scan_inputs<C: GenericClient>(&mut self, tx: &Transaction, notifications: &mut Vec<NotificationRecord>, client: &mut C) -> Result<(), WalletError> {
         for input in tx.vin.iter() {
-            if let Some(ref address) = input.address {
-                if let Some(account) = self.addresses.get(address.as_str()) {
-                    crate::perfcounters::RECEIVED_NOTES.inc();
-                    crate::perfcounters::RECEIVED_AMOUNT.inc_by((input.valueSat.unwrap() as f64) / ZATPERZEC);
-                    let tx_hash = input.txid.as_ref().unwrap().clone();
-                    let txid = hex::decode(&tx_hash)?;
-                    let script: Vec<u8> = vec![];
-                    let amount = input.valueSat.unwrap() as i64;
-                    client.execute(
-                        &self.statements.upsert_spent_utxo,
-                        &[
-                            &txid,
-                            account,
-                            address,
-                            &(input.vout.unwrap() as i32),
-                            &amount,
-                            &script,
-                            &0,
-                            &true,
-                            &(tx.height.unwrap() as i32),
-                        ],
-                    )?;
-                    let notification = NotificationRecord {
-                        id: 0, // ignored
-                        eventType: "outgoingTx".to_string(),
-                        txHash: tx_hash,
-                        account: *account,
-                        address: None,
-                        txOutputIndex: input.vout.unwrap() as i32,
-                        amount,
-                        block: tx.height.unwrap(),
-                    };
-                    notifications.push(notification);
-                }
+            let outpoint = match (&input.txid, input.vout) {
+                (Some(txid), Some(vout)) => (txid.clone(), vout),
+                _ => continue, // coinbase
+            };
+            if let Some(utxo) = self.utxos.remove(&outpoint) {
+                let (tx_hash, output_index) = outpoint;
+                crate::perfcounters::RECEIVED_NOTES.inc();
+                crate::perfcounters::RECEIVED_AMOUNT.inc_by((utxo.value as f64) / ZATPERZEC);
+                let txid = hex::decode(&tx_hash)?;
+                client.execute(
+                    &self.statements.upsert_spent_utxo,
+                    &[
+                        &txid,
+                        &utxo.account,
+                        &utxo.address,
+                        &(output_index as i32),
+                        &utxo.value,
+                        &utxo.script,
+                        &(utxo.height as i32),
+                        &true,
+                        &(tx.height.unwrap() as i32),
+                    ],
+                )?;
+                let notification = NotificationRecord {
+                    id: 0, // ignored
+                    eventType: "outgoingTx".to_string(),
+                    txHash: tx_hash,
+                    account: utxo.account,
+                    address: None,
+                    txOutputIndex: output_index as i32,
+                    amount: utxo.value,
+                    block: tx.height.unwrap(),
+                    revertedEventType: None,
+                    confirmedEventType: None,
+                    confirmations: None,
+                    memo: None,
+                };
+                notifications.push(notification);
             }
         }
         Ok(())
     }
 
-    fn scan_outputs(&self, tx: &Transaction, notifications: &mut Vec<NotificationRecord>, client: &mut Client) -> Result<(), WalletError> {
+    fn scan_outputs<C: GenericClient>(&mut self, tx: &Transaction, notifications: &mut Vec<NotificationRecord>, client: &mut C) -> Result<(), WalletError> {
         for (index, output) in tx.vout.iter().enumerate() {
             for address in output.scriptPubKey.addresses.iter() {
                 if let Some(account) = self.addresses.get(address.as_str()) {
+                    let account = *account;
                     let txid = hex::decode(&tx.txid)?;
                     let amount = output.valueSat as i64;
+                    let script = hex::decode(&output.scriptPubKey.hex).unwrap();
                     client.execute(
                         &self.statements.upsert_spent_utxo,
                         &[
                             &txid,
-                            account,
+                            &account,
                             address,
                             &(index as i32),
                             &amount,
-                            &hex::decode(&output.scriptPubKey.hex).unwrap(),
+                            &script,
                             &(tx.height.unwrap() as i32),
                             &false,
                             &Option::<i32>::None,
                         ],
                     )?;
+                    // may be spent later in the same batch of blocks
+                    self.utxos.insert((tx.txid.clone(), index as u32), Utxo {
+                        account,
+                        address: address.clone(),
+                        value: amount,
+                        script,
+                        height: tx.height.unwrap(),
+                    });
                     let notification = NotificationRecord {
                         id: 0, // ignored
                         eventType: "incomingTx".to_string(),
                         txHash: tx.txid.clone(),
-                        account: *account,
+                        account,
                         address: None,
                         txOutputIndex: index as i32,
                         amount,
                         block: tx.height.unwrap(),
+                        revertedEventType: None,
+                        confirmedEventType: None,
+                        confirmations: None,
+                        memo: None,
                     };
                     notifications.push(notification);
                 }
@@ -155,26 +190,67 @@ impl TrpWallet {
         Ok(())
     }
 
+    pub fn has_addresses(&self) -> bool {
+        !self.addresses.is_empty()
+    }
+
+    /// Only track these addresses, for the rescan of a new key
+    pub fn watch_addresses(&mut self, addresses: &[(i32, String)]) {
+        self.addresses
+            .extend(addresses.iter().map(|(id, addr)| (addr.clone(), *id)));
+    }
+
+    /// Indexes our unspent outputs by outpoint
+    pub fn load_utxos_from_db(&mut self) -> Result<(), WalletError> {
+        let mut c = self.client.lock().unwrap();
+        let utxos = crate::db::get_unspent_utxos(&mut *c)?;
+        self.utxos = utxos.into_iter().collect();
+        Ok(())
+    }
+
+    fn scan_block(&mut self, block: &Block, notifications: &mut Vec<NotificationRecord>) -> Result<(), WalletError> {
+        let client = self.client.clone();
+        let mut c = client.lock().unwrap();
+        for tx in block.tx.iter() {
+            self.scan_inputs(tx, notifications, &mut *c)?;
+            self.scan_outputs(tx, notifications, &mut *c)?;
+            crate::perfcounters::TRANSACTIONS.inc();
+        }
+        Ok(())
+    }
+
+    fn store_notifications(&self, notifications: &[NotificationRecord]) -> Result<(), WalletError> {
+        let mut c = self.client.lock().unwrap();
+        for n in notifications.iter() {
+            store_notification(&mut *c, n)?;
+        }
+        Ok(())
+    }
+
     pub fn scan_range(
         &mut self,
         range: Range<u32>
     ) -> Result<(), WalletError> {
+        self.load_utxos_from_db()?;
         let mut notifications: Vec<NotificationRecord> = Vec::new();
         let source = BlockSource::new(self.client.clone(), &self.config);
         source.with_blocks(range, |block| {
-            let mut c = self.client.lock().unwrap();
-            for tx in block.tx.iter() {
-                self.scan_inputs(tx, &mut notifications, &mut *c)?;
-                self.scan_outputs(tx, &mut notifications, &mut *c)?;
-                crate::perfcounters::TRANSACTIONS.inc();
-            }
-            Ok(())
+            self.scan_block(&block, &mut notifications)
         })?;
-        let mut c = self.client.lock().unwrap();
-        for n in notifications.iter() {
-            store_notification(&mut *c, n)?;
+        self.store_notifications(&notifications)
+    }
+
+    /// Scans blocks that were already downloaded (for instance by the sapling scanner)
+    pub fn scan_blocks(
+        &mut self,
+        blocks: &[Block]
+    ) -> Result<(), WalletError> {
+        let mut notifications: Vec<NotificationRecord> = Vec::new();
+        for block in blocks.iter() {
+            check_block_connects(&self.client, block)?;
+            self.scan_block(block, &mut notifications)?;
         }
-        Ok(())
+        self.store_notifications(&notifications)
     }
 
     pub fn rewind_to_height(&self, height: u32) -> Result<(), WalletError> {
@@ -185,14 +261,6 @@ impl TrpWallet {
         Ok(())
     }
 
-    pub fn scan_transparent(
-        &mut self,
-        range: Range<u32>
-    ) -> Result<(), WalletError> {
-        self.load_transparent_addresses_from_db()?;
-        self.scan_range(range)?;
-        Ok(())
-    }
 }
 
 #[cfg(test)]
@@ -211,4 +279,46 @@ mod tests {
         wallet.load_transparent_addresses_from_db().unwrap();
         wallet.scan_range(1_432_000..1_432_138).unwrap();
     }
+
+    #[test]
+    fn test_spend_detection() {
+        let config = ZamsConfig::default();
+        let client = Client::connect(&config.connection_string, NoTls).unwrap();
+        let client = Arc::new(Mutex::new(client));
+        let mut wallet = TrpWallet::new(client.clone(), config.clone()).unwrap();
+        wallet.addresses.insert("tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string(), 1);
+
+        let funding_txid = "00000000000000000000000000000000000000000000000000000000deadbeef";
+        let funding: Transaction = serde_json::from_value(serde_json::json!({
+            "txid": funding_txid,
+            "height": 1_000_000,
+            "vout": [{
+                "valueSat": 50000,
+                "scriptPubKey": { "addresses": ["tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8"], "hex": "76a914" }
+            }]
+        })).unwrap();
+        // plain zcashd: no address or value in the inputs
+        let spending: Transaction = serde_json::from_value(serde_json::json!({
+            "txid": "00000000000000000000000000000000000000000000000000000000cafebabe",
+            "height": 1_000_010,
+            "vin": [{ "txid": funding_txid, "vout": 0 }]
+        })).unwrap();
+
+        let mut notifications: Vec<NotificationRecord> = vec![];
+        let mut c = client.lock().unwrap();
+        let mut db_tx = c.transaction().unwrap();
+        wallet.scan_outputs(&funding, &mut notifications, &mut db_tx).unwrap();
+        wallet.scan_inputs(&spending, &mut notifications, &mut db_tx).unwrap();
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[1].eventType, "outgoingTx");
+        assert_eq!(notifications[1].amount, 50000);
+
+        let txid = hex::decode(funding_txid).unwrap();
+        let row = db_tx.query_one("SELECT height, script, spent, spent_height FROM utxos WHERE tx_hash = $1", &[&txid]).unwrap();
+        assert_eq!(row.get::<_, i32>(0), 1_000_000);
+        assert_eq!(row.get::<_, Vec<u8>>(1), vec![0x76, 0xa9, 0x14]);
+        assert!(row.get::<_, bool>(2));
+        assert_eq!(row.get::<_, Option<i32>>(3), Some(1_000_010));
+        db_tx.rollback().unwrap();
+    }
 }
@@ -1,4 +1,4 @@
-use anyhow::bail;
+use futures::StreamExt;
 
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -8,14 +8,16 @@ use crate::config::ZamsConfig;
 use zcash_client_backend::proto::compact_formats::{CompactBlock, CompactTx, CompactSpend, CompactOutput};
 use tokio::runtime::Runtime;
 use crate::WalletError;
+use std::fmt;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
 
 #[derive(Debug, Serialize, Deserialize)]
-#[allow(non_snake_case)]
 pub struct TransactionInput {
     pub txid: Option<String>,
     pub vout: Option<u32>,
-    pub valueSat: Option<u64>,
-    pub address: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -145,12 +147,30 @@ pub async fn make_json_rpc(
     let res: Map<String, Value> = res.json().await?;
     if let Some(error) = res.get("error") {
         if let Some(message) = error.get("message") {
-            bail!(message.clone())
+            let message = message.as_str().map(str::to_string).unwrap_or_else(|| message.to_string());
+            return Err(RpcError(message).into());
         }
     }
     Ok(res["result"].clone())
 }
 
+/// An error returned by zcashd itself, as opposed to a failure to reach it
+#[derive(Debug)]
+pub struct RpcError(pub String);
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// The node answered the request with an error
+pub fn is_rpc_error(e: &WalletError) -> bool {
+    matches!(e, WalletError::Error(e) if e.downcast_ref::<RpcError>().is_some())
+}
+
 pub fn get_latest_height(config: &ZamsConfig) -> crate::Result<u32> {
     let r = Runtime::new().unwrap();
     let height = r.block_on(async {
@@ -162,8 +182,20 @@ pub fn get_latest_height(config: &ZamsConfig) -> crate::Result<u32> {
     Ok(height)
 }
 
+pub fn get_block_hash(height: u32, config: &ZamsConfig) -> crate::Result<String> {
+    let r = Runtime::new().unwrap();
+    let hash = r.block_on(async {
+        let client = reqwest::Client::new();
+        let res = make_json_rpc(&client, "getblockhash", json!([height]), config).await?;
+        let hash = res.as_str().unwrap().to_string();
+        Ok::<_, WalletError>(hash)
+    })?;
+    Ok(hash)
+}
+
 pub struct TreeState {
     pub hash: String,
+    pub time: u32,
     pub tree: String,
 }
 
@@ -174,9 +206,11 @@ pub fn get_tree_state(height: u32, config: &ZamsConfig) -> crate::Result<TreeSta
         let height = height.to_string();
         let res = make_json_rpc(&client, "z_gettreestate", json!([&height]), config).await?;
         let hash = res[&"hash"].as_str().unwrap().to_string();
+        let time = res[&"time"].as_u64().unwrap() as u32;
         let tree = res[&"sapling"][&"commitments"][&"finalState"].as_str().unwrap().to_string();
         Ok::<_, WalletError>(TreeState {
             hash,
+            time,
             tree,
         })
     })?;
@@ -207,6 +241,98 @@ pub async fn get_block(
     Ok(block)
 }
 
+/// Blocks waiting for the scanner. The download pauses when the buffer is full
+const MAX_BUFFERED_BLOCKS: usize = 100;
+
+/// Blocks handed over in height order while they are downloaded in the background,
+/// so that scanning overlaps downloading. Ends after the first error.
+/// Dropping the stream stops the download
+pub struct BlockStream<T> {
+    receiver: Receiver<crate::Result<T>>,
+}
+
+impl<T: Send + 'static> BlockStream<T> {
+    /// Runs `download` on its own thread and runtime. It gives each block to the sender,
+    /// which returns false once the stream is dropped
+    pub fn spawn<F, Fut>(download: F) -> BlockStream<T>
+    where
+        F: FnOnce(BlockSender<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = crate::Result<()>>,
+    {
+        let (sender, receiver) = sync_channel(MAX_BUFFERED_BLOCKS);
+        thread::spawn(move || {
+            let r = Runtime::new().unwrap();
+            let error_sender = sender.clone();
+            if let Err(e) = r.block_on(download(BlockSender { sender })) {
+                let _ = error_sender.send(Err(e));
+            }
+        });
+        BlockStream { receiver }
+    }
+}
+
+impl<T> Iterator for BlockStream<T> {
+    type Item = crate::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+pub struct BlockSender<T> {
+    sender: SyncSender<crate::Result<T>>,
+}
+
+impl<T> BlockSender<T> {
+    /// Waits for room in the buffer. Returns false if the stream was dropped
+    pub fn send(&self, block: T) -> bool {
+        self.sender.send(Ok(block)).is_ok()
+    }
+}
+
+/// Downloads a range of blocks with at most `max_inflight_requests` concurrent
+/// calls to zcashd. Blocks are delivered in height order.
+pub fn stream_blocks(range: Range<u32>, config: &ZamsConfig) -> BlockStream<Block> {
+    let config = config.clone();
+    BlockStream::spawn(move |sender| async move {
+        let client = reqwest::Client::new();
+        let mut blocks = futures::stream::iter(range)
+            .map(|height| {
+                let (client, config) = (&client, &config);
+                async move {
+                    let height = height.to_string();
+                    get_block(&height, client, config).await
+                }
+            })
+            .buffered(config.max_inflight_requests.max(1));
+        while let Some(block) = blocks.next().await {
+            if !sender.send(block?) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+pub async fn get_raw_mempool(
+    client: &Client,
+    config: &ZamsConfig,
+) -> anyhow::Result<Vec<String>> {
+    let res = make_json_rpc(client, "getrawmempool", json!([]), config).await?;
+    let txids: Vec<String> = serde_json::from_value(res)?;
+    Ok(txids)
+}
+
+pub async fn get_raw_transaction_hex(
+    hash: &str,
+    client: &Client,
+    config: &ZamsConfig,
+) -> anyhow::Result<String> {
+    let res = make_json_rpc(client, "getrawtransaction", json!([hash, 0]), config).await?;
+    let raw_tx = res.as_str().unwrap().to_string();
+    Ok(raw_tx)
+}
+
 #[allow(dead_code)]
 pub async fn get_raw_transaction(
     hash: &str,
@@ -248,6 +374,18 @@ mod tests {
         // assert!(get_block("0000000000000000000000000000000000000000000000000000000000000000", &client, &config).await.is_ok());
     }
 
+    #[test]
+    fn test_stream_blocks() {
+        let config = ZamsConfig::default();
+        let blocks = stream_blocks(1_432_000..1_432_100, &config)
+            .collect::<crate::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(blocks.len(), 100);
+        for (block, height) in blocks.iter().zip(1_432_000..) {
+            assert_eq!(block.height, height);
+        }
+    }
+
     #[tokio::test]
     async fn test_get_raw_transaction() {
         let config = ZamsConfig::default();
@@ -0,0 +1,452 @@
+//! ZIP-316 unified addresses and unified full viewing keys.
+//! Our version of librustzcash predates them, so the encoding is done here.
+
+use anyhow::anyhow;
+use bech32::{FromBase32, ToBase32, Variant};
+use blake2b_simd::Params;
+use hmac::{Hmac, Mac, NewMac};
+use ripemd160::{Digest, Ripemd160};
+use secp256k1::{PublicKey, Secp256k1};
+use sha2::{Sha256, Sha512};
+use std::convert::TryInto;
+use crate::network::UnifiedParameters;
+use zcash_client_backend::address::RecipientAddress;
+use zcash_primitives::legacy::TransparentAddress;
+use zcash_primitives::sapling::PaymentAddress;
+use zcash_primitives::zip32::ExtendedFullViewingKey;
+
+const P2PKH: u32 = 0x00;
+const P2SH: u32 = 0x01;
+const SAPLING: u32 = 0x02;
+const ORCHARD: u32 = 0x03;
+
+const PADDING_LEN: usize = 16;
+const MIN_LEN: usize = 48;
+const MAX_LEN: usize = 4_194_368;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Receiver {
+    P2pkh([u8; 20]),
+    P2sh([u8; 20]),
+    Sapling([u8; 43]),
+    Orchard([u8; 43]),
+    Unknown(u32, Vec<u8>),
+}
+
+impl Receiver {
+    fn typecode(&self) -> u32 {
+        match self {
+            Receiver::P2pkh(_) => P2PKH,
+            Receiver::P2sh(_) => P2SH,
+            Receiver::Sapling(_) => SAPLING,
+            Receiver::Orchard(_) => ORCHARD,
+            Receiver::Unknown(typecode, _) => *typecode,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match self {
+            Receiver::P2pkh(data) | Receiver::P2sh(data) => data,
+            Receiver::Sapling(data) | Receiver::Orchard(data) => data,
+            Receiver::Unknown(_, data) => data,
+        }
+    }
+
+    fn from_item(typecode: u32, data: Vec<u8>) -> crate::Result<Receiver> {
+        let invalid = |_| anyhow!("Invalid receiver length for typecode {}", typecode);
+        Ok(match typecode {
+            P2PKH => Receiver::P2pkh(data.try_into().map_err(invalid)?),
+            P2SH => Receiver::P2sh(data.try_into().map_err(invalid)?),
+            SAPLING => Receiver::Sapling(data.try_into().map_err(invalid)?),
+            ORCHARD => Receiver::Orchard(data.try_into().map_err(invalid)?),
+            _ => Receiver::Unknown(typecode, data),
+        })
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Receiver::P2pkh(_) => "p2pkh",
+            Receiver::P2sh(_) => "p2sh",
+            Receiver::Sapling(_) => "sapling",
+            Receiver::Orchard(_) => "orchard",
+            Receiver::Unknown(_, _) => "unknown",
+        }
+    }
+
+    /// The receiver as a standalone address, if ZAMS can pay to it
+    pub fn to_recipient(&self) -> Option<RecipientAddress> {
+        match self {
+            Receiver::P2pkh(hash) => Some(RecipientAddress::Transparent(TransparentAddress::PublicKey(*hash))),
+            Receiver::P2sh(hash) => Some(RecipientAddress::Transparent(TransparentAddress::Script(*hash))),
+            Receiver::Sapling(bytes) => PaymentAddress::from_bytes(bytes).map(RecipientAddress::Shielded),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnifiedAddress {
+    pub receivers: Vec<Receiver>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnifiedFullViewingKey {
+    pub sapling: ExtendedFullViewingKey,
+    /// BIP-44 account level public key: chain code (32 bytes) followed by the compressed key
+    pub transparent: Option<[u8; 65]>,
+}
+
+fn address_hrp<P: UnifiedParameters>(network: &P) -> crate::Result<&str> {
+    network
+        .hrp_unified_address()
+        .ok_or_else(|| anyhow!("The network has no unified addresses").into())
+}
+
+fn fvk_hrp<P: UnifiedParameters>(network: &P) -> crate::Result<&str> {
+    network
+        .hrp_unified_fvk()
+        .ok_or_else(|| anyhow!("The network has no unified viewing keys").into())
+}
+
+pub fn is_unified_fvk(key: &str) -> bool {
+    key.starts_with("uview")
+}
+
+/// Decodes a regular or unified address. A unified address resolves to its
+/// preferred receiver that we can pay to: Sapling, then transparent.
+pub fn decode_recipient<P: UnifiedParameters>(network: &P, address: &str) -> Option<RecipientAddress> {
+    if let Some(recipient) = RecipientAddress::decode(network, address) {
+        return Some(recipient);
+    }
+    let ua = decode_unified_address(network, address).ok()?;
+    let mut receivers = ua.receivers;
+    receivers.sort_by_key(|r| match r {
+        Receiver::Sapling(_) => 0,
+        Receiver::P2pkh(_) | Receiver::P2sh(_) => 1,
+        _ => 2,
+    });
+    receivers.iter().find_map(|r| r.to_recipient())
+}
+
+pub fn encode_unified_address<P: UnifiedParameters>(network: &P, ua: &UnifiedAddress) -> crate::Result<String> {
+    let items: Vec<(u32, Vec<u8>)> = ua.receivers.iter().map(|r| (r.typecode(), r.data().to_vec())).collect();
+    encode_items(address_hrp(network)?, items)
+}
+
+pub fn decode_unified_address<P: UnifiedParameters>(network: &P, address: &str) -> crate::Result<UnifiedAddress> {
+    let items = decode_items(address_hrp(network)?, address)?;
+    let receivers = items
+        .into_iter()
+        .map(|(typecode, data)| Receiver::from_item(typecode, data))
+        .collect::<crate::Result<Vec<_>>>()?;
+    if receivers.iter().all(|r| matches!(r, Receiver::P2pkh(_) | Receiver::P2sh(_))) {
+        return Err(anyhow!("Unified address has no shielded receiver").into());
+    }
+    Ok(UnifiedAddress { receivers })
+}
+
+pub fn encode_unified_full_viewing_key<P: UnifiedParameters>(network: &P, ufvk: &UnifiedFullViewingKey) -> crate::Result<String> {
+    let mut items: Vec<(u32, Vec<u8>)> = vec![];
+    if let Some(transparent) = ufvk.transparent {
+        items.push((P2PKH, transparent.to_vec()));
+    }
+    let mut extfvk = vec![];
+    ufvk.sapling.write(&mut extfvk).map_err(crate::WalletError::IO)?;
+    // skip depth, parent tag, child index and chain code
+    items.push((SAPLING, extfvk[41..].to_vec()));
+    encode_items(fvk_hrp(network)?, items)
+}
+
+/// The Sapling item has the fvk and dk but not the ZIP-32 metadata, which
+/// we don't need to derive addresses
+pub fn decode_unified_full_viewing_key<P: UnifiedParameters>(network: &P, key: &str) -> crate::Result<UnifiedFullViewingKey> {
+    let items = decode_items(fvk_hrp(network)?, key)?;
+    let mut sapling: Option<ExtendedFullViewingKey> = None;
+    let mut transparent: Option<[u8; 65]> = None;
+    for (typecode, data) in items {
+        match typecode {
+            P2PKH => {
+                let key: [u8; 65] = data.try_into().map_err(|_| anyhow!("Invalid transparent key"))?;
+                PublicKey::from_slice(&key[32..]).map_err(|_| anyhow!("Invalid transparent key"))?;
+                transparent = Some(key);
+            }
+            SAPLING => {
+                if data.len() != 128 {
+                    return Err(anyhow!("Invalid Sapling key").into());
+                }
+                let mut extfvk = vec![0u8; 41];
+                extfvk.extend_from_slice(&data);
+                sapling = Some(ExtendedFullViewingKey::read(&*extfvk).map_err(|_| anyhow!("Invalid Sapling key"))?);
+            }
+            _ => log::warn!("Ignoring unsupported viewing key typecode {}", typecode),
+        }
+    }
+    let sapling = sapling.ok_or_else(|| anyhow!("Unified viewing key has no Sapling key"))?;
+    Ok(UnifiedFullViewingKey { sapling, transparent })
+}
+
+/// BIP-32 non hardened derivation of the P2PKH receiver at `index`
+pub fn derive_p2pkh_receiver(account_key: &[u8; 65], index: u32) -> crate::Result<[u8; 20]> {
+    if index >= 1 << 31 {
+        return Err(anyhow!("Diversifier index too large for a transparent receiver").into());
+    }
+    let secp = Secp256k1::verification_only();
+    let (chain_code, parent) = account_key.split_at(32);
+    // external chain m/../0 then the address m/../0/index
+    let (chain_code, key) = derive_child(&secp, chain_code, parent, 0)?;
+    let (_, key) = derive_child(&secp, &chain_code, &key.serialize(), index)?;
+    let hash = Ripemd160::digest(&Sha256::digest(&key.serialize()));
+    let mut receiver = [0u8; 20];
+    receiver.copy_from_slice(&hash);
+    Ok(receiver)
+}
+
+fn derive_child(
+    secp: &Secp256k1<secp256k1::VerifyOnly>,
+    chain_code: &[u8],
+    parent: &[u8],
+    index: u32,
+) -> crate::Result<([u8; 32], PublicKey)> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(chain_code).map_err(|_| anyhow!("Invalid chain code"))?;
+    mac.update(parent);
+    mac.update(&index.to_be_bytes());
+    let i = mac.finalize().into_bytes();
+    let mut key = PublicKey::from_slice(parent).map_err(|_| anyhow!("Invalid public key"))?;
+    key.add_exp_assign(secp, &i[..32]).map_err(|_| anyhow!("Invalid child index {}", index))?;
+    let mut child_chain_code = [0u8; 32];
+    child_chain_code.copy_from_slice(&i[32..]);
+    Ok((child_chain_code, key))
+}
+
+fn encode_items(hrp: &str, mut items: Vec<(u32, Vec<u8>)>) -> crate::Result<String> {
+    items.sort_by_key(|(typecode, _)| *typecode);
+    let mut raw: Vec<u8> = vec![];
+    for (typecode, data) in items.iter() {
+        write_compact_size(&mut raw, *typecode as u64);
+        write_compact_size(&mut raw, data.len() as u64);
+        raw.extend_from_slice(data);
+    }
+    raw.extend_from_slice(&padding(hrp));
+    let jumbled = f4jumble(&raw)?;
+    let encoded = bech32::encode(hrp, jumbled.to_base32(), Variant::Bech32m).map_err(|e| anyhow!("{}", e))?;
+    Ok(encoded)
+}
+
+fn decode_items(expected_hrp: &str, s: &str) -> crate::Result<Vec<(u32, Vec<u8>)>> {
+    let (hrp, data, variant) = bech32::decode(s).map_err(crate::WalletError::Bech32)?;
+    if hrp != expected_hrp || variant != Variant::Bech32m {
+        return Err(anyhow!("Not a unified encoding for this network").into());
+    }
+    let jumbled = Vec::<u8>::from_base32(&data).map_err(crate::WalletError::Bech32)?;
+    let mut raw = f4jumble_inv(&jumbled)?;
+    let padding_start = raw.len() - PADDING_LEN;
+    if raw[padding_start..] != padding(&hrp) {
+        return Err(anyhow!("Invalid padding").into());
+    }
+    raw.truncate(padding_start);
+
+    let mut items: Vec<(u32, Vec<u8>)> = vec![];
+    let mut cursor: &[u8] = &raw;
+    while !cursor.is_empty() {
+        let typecode = read_compact_size(&mut cursor)?;
+        let len = read_compact_size(&mut cursor)? as usize;
+        if len > cursor.len() {
+            return Err(anyhow!("Truncated item").into());
+        }
+        let (data, rest) = cursor.split_at(len);
+        let typecode: u32 = typecode.try_into().map_err(|_| anyhow!("Invalid typecode"))?;
+        if let Some((last, _)) = items.last() {
+            if typecode <= *last {
+                return Err(anyhow!("Items are not in ascending typecode order").into());
+            }
+        }
+        items.push((typecode, data.to_vec()));
+        cursor = rest;
+    }
+    if items.is_empty() {
+        return Err(anyhow!("No items").into());
+    }
+    Ok(items)
+}
+
+fn padding(hrp: &str) -> [u8; PADDING_LEN] {
+    let mut padding = [0u8; PADDING_LEN];
+    padding[..hrp.len()].copy_from_slice(hrp.as_bytes());
+    padding
+}
+
+fn write_compact_size(buf: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xFC => buf.push(n as u8),
+        0xFD..=0xFFFF => {
+            buf.push(0xFD);
+            buf.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x10000..=0xFFFF_FFFF => {
+            buf.push(0xFE);
+            buf.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xFF);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+fn read_compact_size(cursor: &mut &[u8]) -> crate::Result<u64> {
+    let mut take = |n: usize| -> crate::Result<Vec<u8>> {
+        if cursor.len() < n {
+            return Err(anyhow!("Truncated compact size").into());
+        }
+        let (head, rest) = cursor.split_at(n);
+        *cursor = rest;
+        Ok(head.to_vec())
+    };
+    let flag = take(1)?[0];
+    let (n, min) = match flag {
+        0xFD => (u16::from_le_bytes(take(2)?.try_into().unwrap()) as u64, 0xFD),
+        0xFE => (u32::from_le_bytes(take(4)?.try_into().unwrap()) as u64, 0x10000),
+        0xFF => (u64::from_le_bytes(take(8)?.try_into().unwrap()), 0x1_0000_0000),
+        n => (n as u64, 0),
+    };
+    if n < min {
+        return Err(anyhow!("Non canonical compact size").into());
+    }
+    Ok(n)
+}
+
+fn h_pers(i: u8) -> [u8; 16] {
+    let mut p = [0u8; 16];
+    p[..13].copy_from_slice(b"UA_F4Jumble_H");
+    p[13] = i;
+    p
+}
+
+fn g_pers(i: u8, j: u16) -> [u8; 16] {
+    let mut p = [0u8; 16];
+    p[..13].copy_from_slice(b"UA_F4Jumble_G");
+    p[13] = i;
+    p[14..].copy_from_slice(&j.to_le_bytes());
+    p
+}
+
+fn xor(target: &mut [u8], mask: &[u8]) {
+    for (t, m) in target.iter_mut().zip(mask.iter()) {
+        *t ^= m;
+    }
+}
+
+fn h(i: u8, u: &[u8], len: usize) -> Vec<u8> {
+    Params::new().hash_length(len).personal(&h_pers(i)).hash(u).as_bytes().to_vec()
+}
+
+fn g(i: u8, u: &[u8], len: usize) -> Vec<u8> {
+    let mut out: Vec<u8> = vec![];
+    let mut j = 0u16;
+    while out.len() < len {
+        out.extend_from_slice(Params::new().hash_length(64).personal(&g_pers(i, j)).hash(u).as_bytes());
+        j += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+fn split_lengths(len: usize) -> crate::Result<usize> {
+    if !(MIN_LEN..=MAX_LEN).contains(&len) {
+        return Err(anyhow!("Invalid unified encoding length").into());
+    }
+    Ok((len / 2).min(64))
+}
+
+fn f4jumble(m: &[u8]) -> crate::Result<Vec<u8>> {
+    let left_len = split_lengths(m.len())?;
+    let (mut a, mut b) = (m[..left_len].to_vec(), m[left_len..].to_vec());
+    xor(&mut b, &g(0, &a, b.len()));
+    xor(&mut a, &h(0, &b, a.len()));
+    xor(&mut b, &g(1, &a, b.len()));
+    xor(&mut a, &h(1, &b, a.len()));
+    a.extend_from_slice(&b);
+    Ok(a)
+}
+
+fn f4jumble_inv(m: &[u8]) -> crate::Result<Vec<u8>> {
+    let left_len = split_lengths(m.len())?;
+    let (mut a, mut b) = (m[..left_len].to_vec(), m[left_len..].to_vec());
+    xor(&mut a, &h(1, &b, a.len()));
+    xor(&mut b, &g(1, &a, b.len()));
+    xor(&mut a, &h(0, &b, a.len()));
+    xor(&mut b, &g(0, &a, b.len()));
+    a.extend_from_slice(&b);
+    Ok(a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ZamsNetwork;
+    use zcash_primitives::consensus::Network::TestNetwork;
+    use zcash_primitives::zip32::{DiversifierIndex, ExtendedSpendingKey};
+
+    #[test]
+    fn test_f4jumble_roundtrip() {
+        for len in [48usize, 83, 128, 200, 1000].iter() {
+            let m: Vec<u8> = (0..*len).map(|i| i as u8).collect();
+            let jumbled = f4jumble(&m).unwrap();
+            assert_ne!(jumbled, m);
+            assert_eq!(f4jumble_inv(&jumbled).unwrap(), m);
+        }
+    }
+
+    #[test]
+    fn test_unified_address() {
+        let extsk = ExtendedSpendingKey::master(&[7u8; 32]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let (_, pa) = extfvk.address(DiversifierIndex::new()).unwrap();
+        let ua = UnifiedAddress {
+            receivers: vec![Receiver::Sapling(pa.to_bytes()), Receiver::P2pkh([1u8; 20])],
+        };
+        let encoded = encode_unified_address(&TestNetwork, &ua).unwrap();
+        assert!(encoded.starts_with("utest1"));
+        let decoded = decode_unified_address(&TestNetwork, &encoded).unwrap();
+        assert_eq!(decoded.receivers, vec![Receiver::P2pkh([1u8; 20]), Receiver::Sapling(pa.to_bytes())]);
+        match decode_recipient(&TestNetwork, &encoded) {
+            Some(RecipientAddress::Shielded(recipient)) => assert_eq!(recipient, pa),
+            _ => panic!("Sapling receiver expected"),
+        }
+
+        let mut corrupted = encoded.into_bytes();
+        let last = corrupted.len() - 1;
+        corrupted[last] = if corrupted[last] == b'q' { b'p' } else { b'q' };
+        assert!(decode_unified_address(&TestNetwork, &String::from_utf8(corrupted).unwrap()).is_err());
+
+        let regtest = ZamsNetwork::regtest();
+        let encoded = encode_unified_address(&regtest, &ua).unwrap();
+        assert!(encoded.starts_with("uregtest1"));
+        assert_eq!(decode_unified_address(&regtest, &encoded).unwrap().receivers.len(), 2);
+        assert!(decode_unified_address(&TestNetwork, &encoded).is_err());
+    }
+
+    #[test]
+    fn test_unified_full_viewing_key() {
+        let extsk = ExtendedSpendingKey::master(&[7u8; 32]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let secp = Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let mut transparent = [0u8; 65];
+        transparent[32..].copy_from_slice(&PublicKey::from_secret_key(&secp, &sk).serialize());
+        let ufvk = UnifiedFullViewingKey {
+            sapling: extfvk.clone(),
+            transparent: Some(transparent),
+        };
+        let encoded = encode_unified_full_viewing_key(&TestNetwork, &ufvk).unwrap();
+        assert!(is_unified_fvk(&encoded));
+        let decoded = decode_unified_full_viewing_key(&TestNetwork, &encoded).unwrap();
+        assert_eq!(decoded.transparent.unwrap()[..], transparent[..]);
+        let di = DiversifierIndex::new();
+        assert_eq!(decoded.sapling.address(di).unwrap(), extfvk.address(di).unwrap());
+        assert_ne!(
+            derive_p2pkh_receiver(&transparent, 0).unwrap(),
+            derive_p2pkh_receiver(&transparent, 1).unwrap()
+        );
+    }
+}
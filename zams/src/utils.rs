@@ -1,6 +1,6 @@
 use crate::ZamsConfig;
 use postgres::{Client, NoTls};
-use zcash_primitives::consensus::{MainNetwork, Parameters};
+use zcash_primitives::consensus::Parameters;
 use bip39::{Mnemonic, Language, Seed};
 use secp256k1::{All, SecretKey, PublicKey, Secp256k1};
 use tiny_hderive::bip32::ExtendedPrivKey;
@@ -16,7 +16,7 @@ pub fn populate_taddr(count: u32) {
     let config = ZamsConfig::default();
     let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
     let p = client.prepare("INSERT INTO accounts(address) VALUES ($1) ON CONFLICT(address) DO NOTHING").unwrap();
-    let network = &MainNetwork;
+    let network = &config.network;
     let entropy = [21u8; 32];
     let seed = Seed::new(&Mnemonic::from_entropy(&entropy, Language::English).unwrap(), "");
     let path = "m";
@@ -38,7 +38,7 @@ pub fn populate_zaddr(count: u32) {
     let config = ZamsConfig::default();
     let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
     let p = client.prepare("INSERT INTO accounts(address) VALUES ($1) ON CONFLICT(address) DO NOTHING").unwrap();
-    let network = &MainNetwork;
+    let network = &config.network;
     let entropy = [21u8; 32];
     let seed = Seed::new(&Mnemonic::from_entropy(&entropy, Language::English).unwrap(), "");
     let master = ExtendedSpendingKey::master(seed.as_bytes());
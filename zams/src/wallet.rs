@@ -22,7 +22,7 @@ use zcash_client_backend::wallet::{AccountId, SpendableNote, WalletTx};
 use zcash_client_backend::DecryptedOutput;
 use zcash_primitives::block::BlockHash;
 
-use zcash_primitives::consensus::{BlockHeight, Network, NetworkUpgrade, Parameters};
+use zcash_primitives::consensus::{BlockHeight, NetworkUpgrade, Parameters};
 use zcash_primitives::memo::{Memo, MemoBytes};
 use zcash_primitives::merkle_tree::{CommitmentTree, IncrementalWitness};
 use zcash_primitives::sapling::{Diversifier, Node, Nullifier, PaymentAddress, Rseed};
@@ -30,16 +30,26 @@ use zcash_primitives::transaction::components::Amount;
 use zcash_primitives::transaction::{Transaction, TxId};
 use zcash_primitives::zip32::{ExtendedFullViewingKey};
 use crate::{ZamsConfig, ZATPERZEC};
+use crate::network::ZamsNetwork;
 use crate::db::store_notification;
 use crate::notification::NotificationRecord;
 
+pub mod coin_selector;
+pub mod fee;
+pub mod keyring;
+pub mod lwdrpc;
+pub mod memo;
+pub mod rescan;
 pub mod scan;
 pub mod shielded_output;
 pub mod transaction;
 
+/// Number of blocks of witness history kept for rollbacks
+pub const MAX_REORG_DEPTH: u32 = 100;
+
 pub struct PostgresWallet {
     pub client: Arc<Mutex<Client>>,
-    network: &'static Network,
+    network: ZamsNetwork,
     stmt_insert_block: Statement,
 
     stmt_upsert_tx_meta: Statement,
@@ -59,7 +69,7 @@ impl PostgresWallet {
     pub fn new(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> Result<PostgresWallet, WalletError> {
         let mut c = client.lock().unwrap();
         Ok(PostgresWallet {
-            network: config.network,
+            network: config.network.clone(),
             client: client.clone(),
             stmt_insert_block: c.prepare(
                 "INSERT INTO blocks (height, hash, time, sapling_tree)
@@ -81,14 +91,17 @@ impl PostgresWallet {
                 "UPDATE received_notes SET spent = $1 WHERE nf = $2"
             )?,
             stmt_upsert_received_note: c.prepare(
-                "INSERT INTO received_notes (tx, output_index, account, address, diversifier, value, rcm, memo, nf, is_change, height)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                "INSERT INTO received_notes (tx, output_index, account, address, diversifier, value, rcm, memo, nf, is_change, height,
+                    rseed, zip212)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
                     ON CONFLICT (tx, output_index) DO UPDATE
                     SET account = excluded.account,
                         address = excluded.address,
                         diversifier = excluded.diversifier,
                         value = excluded.value,
                         rcm = excluded.rcm,
+                        rseed = excluded.rseed,
+                        zip212 = excluded.zip212,
                         nf = coalesce(excluded.nf, received_notes.nf),
                         memo = coalesce(excluded.memo, received_notes.memo),
                         is_change = coalesce(excluded.is_change, received_notes.is_change),
@@ -122,7 +135,7 @@ impl PostgresWallet {
 }
 
 struct WalletDbTransaction<'a> {
-    network: &'a Network,
+    network: &'a ZamsNetwork,
     statements: &'a PostgresWallet,
     transaction: postgres::Transaction<'a>,
 }
@@ -216,6 +229,7 @@ impl<'a> WalletDbTransaction<'a> {
         let diversifier = output.to().diversifier().0.to_vec();
         let value = output.note().value as i64;
         let rcm = rcm.as_ref();
+        let (rseed, zip212) = rseed_to_bytes(&output.note().rseed);
         let memo = output.memo().map(|m| m.as_slice());
         let is_change = output.is_change();
         let tx = tx_ref;
@@ -245,6 +259,8 @@ impl<'a> WalletDbTransaction<'a> {
             &nf_bytes,
             &is_change,
             &height,
+            &rseed,
+            &zip212,
         ];
 
         crate::perfcounters::RECEIVED_NOTES.inc();
@@ -309,7 +325,7 @@ impl<'a> WalletDbTransaction<'a> {
         value: Amount,
         memo: Option<&MemoBytes>,
     ) -> Result<i32, WalletError> {
-        let to_str = to.encode(&Network::TestNetwork);
+        let to_str = to.encode(self.network);
         let row = self.transaction.query_one(
             "SELECT account FROM accounts WHERE address = $1 AND fvk = $2",
             &[&to_str, &(account.0 as i32)],
@@ -461,8 +477,14 @@ impl WalletRead for PostgresWallet {
         }
     }
 
-    fn get_memo(&self, _id_note: Self::NoteRef) -> Result<Memo, Self::Error> {
-        Ok(Memo::Empty)
+    fn get_memo(&self, id_note: Self::NoteRef) -> Result<Memo, Self::Error> {
+        let mut client = self.client.lock().unwrap();
+        let row = client.query_one("SELECT memo FROM received_notes WHERE id_note = $1", &[&id_note])?;
+        let memo: Option<Vec<u8>> = row.get(0);
+        match memo {
+            Some(memo) => memo::decode_memo(&memo),
+            None => Ok(Memo::Empty),
+        }
     }
 
     fn get_commitment_tree(
@@ -555,7 +577,7 @@ impl WalletWrite for PostgresWallet {
         let mut notifications: Vec<NotificationRecord> = Vec::new();
         let new_witnesses = {
             let mut db_tx = WalletDbTransaction {
-                network: self.network,
+                network: &self.network,
                 statements: self,
                 transaction: client.transaction()?,
             };
@@ -588,6 +610,10 @@ impl WalletWrite for PostgresWallet {
                         txOutputIndex: spend.index as i32,
                         amount,
                         block: u32::from(block.block_height),
+                        revertedEventType: None,
+                        confirmedEventType: None,
+                        confirmations: None,
+                        memo: None,
                     };
                     notifications.push(notification);
                 }
@@ -609,6 +635,10 @@ impl WalletWrite for PostgresWallet {
                         txOutputIndex: output.index as i32,
                         amount: output.note.value as i64,
                         block: u32::from(block.block_height),
+                        revertedEventType: None,
+                        confirmedEventType: None,
+                        confirmations: None,
+                        memo: None,
                     };
                     notifications.push(notification);
                 }
@@ -620,8 +650,8 @@ impl WalletWrite for PostgresWallet {
                 db_tx.insert_witness(rnid, witness, block.block_height)?;
             }
 
-            // Prune the stored witnesses (we only expect rollbacks of at most 100 blocks).
-            db_tx.prune_witnesses(block.block_height - 100)?;
+            // Prune the stored witnesses (we only expect rollbacks of at most MAX_REORG_DEPTH blocks).
+            db_tx.prune_witnesses(block.block_height - MAX_REORG_DEPTH)?;
 
             // Update now-expired transactions that didn't get mined.
             db_tx.update_expired_notes(block.block_height)?;
@@ -642,7 +672,7 @@ impl WalletWrite for PostgresWallet {
     ) -> Result<Self::TxRef, Self::Error> {
         let mut client = self.client.lock().unwrap();
         let mut db_tx = WalletDbTransaction {
-            network: self.network,
+            network: &self.network,
             statements: self,
             transaction: client.transaction()?,
         };
@@ -664,7 +694,7 @@ impl WalletWrite for PostgresWallet {
     fn store_sent_tx(&mut self, sent_tx: &SentTransaction) -> Result<Self::TxRef, Self::Error> {
         let mut client = self.client.lock().unwrap();
         let mut db_tx = WalletDbTransaction {
-            network: self.network,
+            network: &self.network,
             statements: self,
             transaction: client.transaction()?,
         };
@@ -701,7 +731,7 @@ impl WalletWrite for PostgresWallet {
         let mut client = self.client.lock().unwrap();
         let mut db_tx = client.transaction()?;
 
-        let sapling_activation_height = Network::TestNetwork
+        let sapling_activation_height = self.network
             .activation_height(NetworkUpgrade::Sapling)
             .ok_or_else(|| {
                 WalletError::Error(anyhow::anyhow!("Cannot rewind to before sapling"))
@@ -738,6 +768,9 @@ impl WalletWrite for PostgresWallet {
                 &[&(u32::from(block_height) as i32)],
             )?;
 
+            // Retract the notifications of un-mined transactions (shielded and transparent).
+            crate::db::revert_notifications(&mut db_tx, u32::from(block_height))?;
+
             Ok(())
         };
 
@@ -746,6 +779,25 @@ impl WalletWrite for PostgresWallet {
     }
 }
 
+/// The rseed bytes and whether the note uses a v2 plaintext (ZIP 212).
+/// Before ZIP 212 the rseed is the rcm itself
+pub fn rseed_to_bytes(rseed: &Rseed) -> (Vec<u8>, bool) {
+    match rseed {
+        Rseed::BeforeZip212(rcm) => (rcm.to_repr().as_ref().to_vec(), false),
+        Rseed::AfterZip212(rseed) => (rseed.to_vec(), true),
+    }
+}
+
+pub fn rseed_from_bytes(rseed: &[u8], zip212: bool) -> Result<Rseed, WalletError> {
+    let rseed: [u8; 32] = rseed.try_into().map_err(|_| WalletError::InvalidNote)?;
+    if zip212 {
+        Ok(Rseed::AfterZip212(rseed))
+    } else {
+        let rcm = jubjub::Fr::from_repr(rseed).ok_or(WalletError::InvalidNote)?;
+        Ok(Rseed::BeforeZip212(rcm))
+    }
+}
+
 pub fn to_spendable_note(row: &Row) -> Result<SpendableNoteWithId, WalletError> {
     let id_note: i32 = row.get(0);
     let diversifier = {
@@ -763,18 +815,16 @@ pub fn to_spendable_note(row: &Row) -> Result<SpendableNoteWithId, WalletError>
     let note_value = Amount::from_i64(row.get(2)).unwrap();
 
     let rseed = {
-        let rcm_bytes: Vec<_> = row.get(3);
-
-        // We store rcm directly in the data DB, regardless of whether the note
-        // used a v1 or v2 note plaintext, so for the purposes of spending let's
-        // pretend this is a pre-ZIP 212 note.
-        let rcm = jubjub::Fr::from_repr(
-            rcm_bytes[..]
-                .try_into()
-                .map_err(|_| WalletError::InvalidNote)?,
-        )
-        .ok_or(WalletError::InvalidNote)?;
-        Rseed::BeforeZip212(rcm)
+        let rseed: Option<Vec<u8>> = row.get(5);
+        let zip212: bool = row.get(6);
+        match rseed {
+            Some(rseed) => rseed_from_bytes(&rseed, zip212)?,
+            None => {
+                // Notes stored before we kept the rseed only have their rcm
+                let rcm_bytes: Vec<u8> = row.get(3);
+                rseed_from_bytes(&rcm_bytes, false)?
+            }
+        }
     };
 
     let witness = {
@@ -810,7 +860,7 @@ mod tests {
         );
 
         let mut db_tx = WalletDbTransaction {
-            network: config.network,
+            network: &config.network,
             statements: &w,
             transaction: client.transaction().unwrap(),
         };
@@ -837,4 +887,26 @@ mod tests {
 
         db_tx.transaction.commit().unwrap();
     }
+
+    #[test]
+    fn test_rseed_bytes() {
+        let rseed = Rseed::AfterZip212([7u8; 32]);
+        let (bytes, zip212) = rseed_to_bytes(&rseed);
+        assert!(zip212);
+        match rseed_from_bytes(&bytes, zip212).unwrap() {
+            Rseed::AfterZip212(r) => assert_eq!(r, [7u8; 32]),
+            _ => panic!("Expected a ZIP 212 rseed"),
+        }
+
+        let rcm = jubjub::Fr::from(42u64);
+        let (bytes, zip212) = rseed_to_bytes(&Rseed::BeforeZip212(rcm));
+        assert!(!zip212);
+        match rseed_from_bytes(&bytes, zip212).unwrap() {
+            Rseed::BeforeZip212(r) => assert_eq!(r, rcm),
+            _ => panic!("Expected a pre ZIP 212 rseed"),
+        }
+        assert!(rseed_from_bytes(&bytes[..31], false).is_err());
+        // not a canonical scalar
+        assert!(rseed_from_bytes(&[0xFF; 32], false).is_err());
+    }
 }
@@ -0,0 +1,198 @@
+use crate::error::WalletError;
+use anyhow::anyhow;
+use rand::prelude::SliceRandom;
+use rand::RngCore;
+use std::str::FromStr;
+use zcash_primitives::transaction::components::Amount;
+
+/// Notes smaller than this are dust, for `ConsumeDust`
+pub const DUST_THRESHOLD: i64 = 10_000;
+/// Maximum number of dust notes that `ConsumeDust` adds beyond the target
+pub const MAX_DUST_INPUTS: usize = 20;
+/// Branches explored by `ExactMatch` before it gives up
+const MAX_EXACT_MATCH_TRIES: usize = 100_000;
+
+/// How to pick the notes or utxos that pay for a transaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoinSelector {
+    /// Shuffles the notes and takes them until the target is met
+    Random,
+    /// Fewest inputs
+    LargestFirst,
+    /// Spends the small notes first
+    SmallestFirst,
+    /// Looks for notes that add up to the target exactly, so that there is no change.
+    /// Falls back to `LargestFirst`
+    ExactMatch,
+    /// Like `SmallestFirst`, then adds the remaining dust
+    ConsumeDust,
+}
+
+impl CoinSelector {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CoinSelector::Random => "random",
+            CoinSelector::LargestFirst => "largest_first",
+            CoinSelector::SmallestFirst => "smallest_first",
+            CoinSelector::ExactMatch => "exact_match",
+            CoinSelector::ConsumeDust => "consume_dust",
+        }
+    }
+
+    /// Returns the indices of the selected values, or None if they do not add up to the target
+    pub fn select<R: RngCore>(&self, values: &[Amount], target: Amount, rng: &mut R) -> Option<Vec<usize>> {
+        let values: Vec<i64> = values.iter().map(|&v| i64::from(v)).collect();
+        self.select_values(&values, i64::from(target), rng)
+    }
+
+    /// Indices of the values in the order this selector spends them.
+    /// `ExactMatch` does not have one and uses the order of `LargestFirst`
+    pub fn order<R: RngCore>(&self, values: &[Amount], rng: &mut R) -> Vec<usize> {
+        let values: Vec<i64> = values.iter().map(|&v| i64::from(v)).collect();
+        self.order_values(&values, rng)
+    }
+
+    fn order_values<R: RngCore>(&self, values: &[i64], rng: &mut R) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..values.len()).collect();
+        match self {
+            CoinSelector::Random => order.shuffle(rng),
+            CoinSelector::LargestFirst | CoinSelector::ExactMatch => order.sort_by_key(|&i| -values[i]),
+            CoinSelector::SmallestFirst | CoinSelector::ConsumeDust => order.sort_by_key(|&i| values[i]),
+        }
+        order
+    }
+
+    fn select_values<R: RngCore>(&self, values: &[i64], target: i64, rng: &mut R) -> Option<Vec<usize>> {
+        let order = self.order_values(values, rng);
+        match self {
+            CoinSelector::Random | CoinSelector::LargestFirst | CoinSelector::SmallestFirst => {
+                take_until(values, &order, target)
+            }
+            CoinSelector::ExactMatch => {
+                exact_match(values, target).or_else(|| CoinSelector::LargestFirst.select_values(values, target, rng))
+            }
+            CoinSelector::ConsumeDust => {
+                let mut selected = take_until(values, &order, target)?;
+                let dust = order[selected.len()..]
+                    .iter()
+                    .filter(|&&i| values[i] < DUST_THRESHOLD)
+                    .take(MAX_DUST_INPUTS);
+                selected.extend(dust);
+                Some(selected)
+            }
+        }
+    }
+}
+
+impl FromStr for CoinSelector {
+    type Err = WalletError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let selector = match s {
+            "random" => CoinSelector::Random,
+            "largest_first" => CoinSelector::LargestFirst,
+            "smallest_first" => CoinSelector::SmallestFirst,
+            "exact_match" => CoinSelector::ExactMatch,
+            "consume_dust" => CoinSelector::ConsumeDust,
+            _ => return Err(WalletError::Error(anyhow!("Unknown coin selection {}", s))),
+        };
+        Ok(selector)
+    }
+}
+
+fn take_until(values: &[i64], order: &[usize], target: i64) -> Option<Vec<usize>> {
+    let mut selected = vec![];
+    let mut sum = 0i64;
+    for &i in order.iter() {
+        if sum >= target {
+            break;
+        }
+        sum += values[i];
+        selected.push(i);
+    }
+    if sum >= target {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+/// Depth first search of the subsets of the values sorted from the largest,
+/// skipping the branches that cannot reach the target
+fn exact_match(values: &[i64], target: i64) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by_key(|&i| -values[i]);
+    let sorted: Vec<i64> = order.iter().map(|&i| values[i]).collect();
+    let mut suffix_sums = vec![0i64; sorted.len() + 1];
+    for i in (0..sorted.len()).rev() {
+        suffix_sums[i] = suffix_sums[i + 1] + sorted[i];
+    }
+    let mut selected = vec![];
+    let mut tries = MAX_EXACT_MATCH_TRIES;
+    if target > 0 && search(&sorted, &suffix_sums, 0, target, &mut selected, &mut tries) {
+        Some(selected.iter().map(|&i| order[i]).collect())
+    } else {
+        None
+    }
+}
+
+fn search(values: &[i64], suffix_sums: &[i64], i: usize, remaining: i64, selected: &mut Vec<usize>, tries: &mut usize) -> bool {
+    if remaining == 0 {
+        return true;
+    }
+    if i == values.len() || suffix_sums[i] < remaining || *tries == 0 {
+        return false;
+    }
+    *tries -= 1;
+    if values[i] <= remaining {
+        selected.push(i);
+        if search(values, suffix_sums, i + 1, remaining - values[i], selected, tries) {
+            return true;
+        }
+        selected.pop();
+    }
+    search(values, suffix_sums, i + 1, remaining, selected, tries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    fn amounts(values: &[i64]) -> Vec<Amount> {
+        values.iter().map(|&v| Amount::from_i64(v).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_coin_selectors() {
+        let mut rng = thread_rng();
+        let values = amounts(&[5_000, 70_000, 20_000, 1_000, 40_000]);
+        let target = Amount::from_i64(60_000).unwrap();
+
+        assert_eq!(CoinSelector::LargestFirst.select(&values, target, &mut rng), Some(vec![1]));
+        assert_eq!(CoinSelector::SmallestFirst.select(&values, target, &mut rng), Some(vec![3, 0, 2, 4]));
+        let mut exact = CoinSelector::ExactMatch.select(&values, target, &mut rng).unwrap();
+        exact.sort_unstable();
+        assert_eq!(exact, vec![2, 4]);
+        let random = CoinSelector::Random.select(&values, target, &mut rng).unwrap();
+        assert!(random.iter().map(|&i| i64::from(values[i])).sum::<i64>() >= 60_000);
+
+        let target = Amount::from_i64(20_000).unwrap();
+        assert_eq!(CoinSelector::ConsumeDust.select(&values, target, &mut rng), Some(vec![3, 0, 2]));
+        let target = Amount::from_i64(2_000).unwrap();
+        assert_eq!(CoinSelector::ConsumeDust.select(&values, target, &mut rng), Some(vec![3, 0]));
+
+        assert_eq!(CoinSelector::ConsumeDust.order(&values, &mut rng), vec![3, 0, 2, 4, 1]);
+        assert_eq!(CoinSelector::ExactMatch.order(&values, &mut rng), vec![1, 4, 2, 0, 3]);
+
+        // no exact match
+        let target = Amount::from_i64(2_000).unwrap();
+        assert_eq!(CoinSelector::ExactMatch.select(&values, target, &mut rng), Some(vec![1]));
+        let target = Amount::from_i64(200_000).unwrap();
+        assert_eq!(CoinSelector::Random.select(&values, target, &mut rng), None);
+
+        for selector in [CoinSelector::Random, CoinSelector::ExactMatch, CoinSelector::ConsumeDust].iter() {
+            assert_eq!(selector.as_str().parse::<CoinSelector>().unwrap(), *selector);
+        }
+    }
+}
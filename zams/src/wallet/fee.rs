@@ -0,0 +1,60 @@
+use crate::error::WalletError;
+use anyhow::anyhow;
+use std::str::FromStr;
+use zcash_primitives::transaction::components::amount::DEFAULT_FEE;
+use zcash_primitives::transaction::components::Amount;
+
+/// Number of inputs and outputs of each kind of a transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TxShape {
+    pub transparent_inputs: usize,
+    pub transparent_outputs: usize,
+    pub sapling_spends: usize,
+    pub sapling_outputs: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeeRule {
+    /// The fee of the transaction builder, whatever the size of the transaction
+    Fixed,
+}
+
+impl FeeRule {
+    pub fn fee(&self, _shape: &TxShape) -> Amount {
+        match self {
+            FeeRule::Fixed => DEFAULT_FEE,
+        }
+    }
+}
+
+impl FromStr for FeeRule {
+    type Err = WalletError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(FeeRule::Fixed),
+            // the transaction builder of the signer can only pay DEFAULT_FEE
+            "zip317" => Err(WalletError::Error(anyhow!("The signer cannot pay ZIP-317 fees yet"))),
+            _ => Err(WalletError::Error(anyhow!("Unknown fee rule {}", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_fee() {
+        let shape = TxShape {
+            transparent_inputs: 10,
+            transparent_outputs: 1,
+            sapling_outputs: 1,
+            ..TxShape::default()
+        };
+        assert_eq!(FeeRule::Fixed.fee(&shape), DEFAULT_FEE);
+        assert_eq!(FeeRule::Fixed.fee(&TxShape::default()), DEFAULT_FEE);
+        assert_eq!("fixed".parse::<FeeRule>().unwrap(), FeeRule::Fixed);
+        assert!("zip317".parse::<FeeRule>().is_err());
+    }
+}
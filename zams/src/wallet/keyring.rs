@@ -0,0 +1,87 @@
+use crate::error::WalletError;
+use crate::zams_rpc as grpc;
+use anyhow::anyhow;
+use ripemd160::{Digest, Ripemd160};
+use secp256k1::{All, PublicKey, Secp256k1, SecretKey};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::str::FromStr;
+use zcash_client_backend::encoding::{decode_extended_spending_key, decode_transparent_address};
+use zcash_primitives::consensus::Parameters;
+use zcash_primitives::legacy::TransparentAddress;
+use zcash_primitives::sapling::PaymentAddress;
+use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+
+/// The keys that sign a transaction. Every input is signed with the key of its address
+pub struct Keyring {
+    /// by hash of the public key
+    transparent: HashMap<[u8; 20], SecretKey>,
+    sapling: Vec<(ExtendedSpendingKey, ExtendedFullViewingKey)>,
+}
+
+impl Keyring {
+    /// Takes transparent secret keys in hex and sapling extended spending keys
+    pub fn new<P: Parameters>(network: &P, keys: &[String]) -> crate::Result<Keyring> {
+        let secp = Secp256k1::<All>::new();
+        let mut keyring = Keyring {
+            transparent: HashMap::new(),
+            sapling: vec![],
+        };
+        for key in keys.iter() {
+            if let Ok(Some(extsk)) = decode_extended_spending_key(network.hrp_sapling_extended_spending_key(), key) {
+                let extfvk = ExtendedFullViewingKey::from(&extsk);
+                keyring.sapling.push((extsk, extfvk));
+            } else if let Ok(seckey) = SecretKey::from_str(key) {
+                let pub_key = PublicKey::from_secret_key(&secp, &seckey).serialize();
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(&Ripemd160::digest(&Sha256::digest(&pub_key)));
+                keyring.transparent.insert(hash, seckey);
+            } else {
+                return Err(WalletError::Error(anyhow!("Invalid secret key")));
+            }
+        }
+        Ok(keyring)
+    }
+
+    /// The key of a utxo, by its address or else by its P2PKH script for
+    /// unsigned txs made before sweeps
+    pub fn transparent_key<P: Parameters>(&self, network: &P, utxo: &grpc::Utxo) -> crate::Result<SecretKey> {
+        let hash = if utxo.address.is_empty() {
+            let script = hex::decode(&utxo.hex)?;
+            match script.as_slice() {
+                [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => {
+                    let mut h = [0u8; 20];
+                    h.copy_from_slice(hash);
+                    Some(h)
+                }
+                _ => None,
+            }
+        } else {
+            match decode_transparent_address(
+                &network.b58_pubkey_address_prefix(),
+                &network.b58_script_address_prefix(),
+                &utxo.address,
+            ) {
+                Ok(Some(TransparentAddress::PublicKey(hash))) => Some(hash),
+                _ => None,
+            }
+        };
+        hash.and_then(|hash| self.transparent.get(&hash).copied()).ok_or_else(|| {
+            WalletError::Error(anyhow!(
+                "No key for the utxo {}:{} of {}",
+                utxo.tx_hash,
+                utxo.output_index,
+                utxo.address
+            ))
+        })
+    }
+
+    /// The spending key that has the address among its diversified addresses
+    pub fn sapling_key(&self, address: &PaymentAddress) -> crate::Result<&ExtendedSpendingKey> {
+        self.sapling
+            .iter()
+            .find(|(_, extfvk)| extfvk.fvk.vk.to_payment_address(*address.diversifier()).as_ref() == Some(address))
+            .map(|(extsk, _)| extsk)
+            .ok_or_else(|| WalletError::Error(anyhow!("No key for the note of this address")))
+    }
+}
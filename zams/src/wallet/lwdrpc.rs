@@ -0,0 +1,274 @@
+use anyhow::Context;
+use prost::Message;
+use tonic::transport::Channel;
+use tonic::Request;
+use zcash_client_backend::proto::compact_formats::CompactBlock;
+
+use crate::lwd_rpc as lwd;
+use crate::lwd_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
+use crate::trp::zcashdrpc::{BlockStream, TreeState};
+use std::ops::Range;
+
+pub async fn connect_lightwalletd(url: &str) -> crate::Result<CompactTxStreamerClient<Channel>> {
+    let client = CompactTxStreamerClient::connect(url.to_string()).await?;
+    Ok(client)
+}
+
+pub async fn get_latest_height(client: &mut CompactTxStreamerClient<Channel>) -> crate::Result<u32> {
+    let block_id = client
+        .get_latest_block(Request::new(lwd::ChainSpec {}))
+        .await?
+        .into_inner();
+    Ok(block_id.height as u32)
+}
+
+/// Streams the blocks of the range from lightwalletd, as they arrive
+pub fn stream_block_range(url: &str, range: Range<u32>) -> BlockStream<CompactBlock> {
+    let url = url.to_string();
+    BlockStream::spawn(move |sender| async move {
+        if range.end <= range.start {
+            return Ok(());
+        }
+        let mut client = connect_lightwalletd(&url).await?;
+        // lightwalletd ranges are inclusive
+        let range = lwd::BlockRange {
+            start: Some(lwd::BlockId {
+                height: range.start as u64,
+                hash: vec![],
+            }),
+            end: Some(lwd::BlockId {
+                height: (range.end - 1) as u64,
+                hash: vec![],
+            }),
+        };
+        let mut stream = client
+            .get_block_range(Request::new(range))
+            .await?
+            .into_inner();
+        while let Some(block) = stream.message().await? {
+            if !sender.send(to_compact_block(&block)?) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+pub async fn get_tree_state(
+    client: &mut CompactTxStreamerClient<Channel>,
+    height: u32,
+) -> crate::Result<TreeState> {
+    let tree_state = client
+        .get_tree_state(Request::new(lwd::BlockId {
+            height: height as u64,
+            hash: vec![],
+        }))
+        .await?
+        .into_inner();
+    Ok(TreeState {
+        hash: tree_state.hash,
+        time: tree_state.time,
+        tree: tree_state.tree,
+    })
+}
+
+// lightwalletd and zcash_client_backend share the same wire format
+// but not the same protobuf library
+fn to_compact_block(block: &lwd::CompactBlock) -> crate::Result<CompactBlock> {
+    let mut buf: Vec<u8> = vec![];
+    block.encode(&mut buf).context("Cannot encode compact block")?;
+    let mut cb: CompactBlock = protobuf::Message::parse_from_bytes(&buf).context("Cannot decode compact block")?;
+    // lightwalletd sends block and tx hashes in internal byte order but we store them
+    // in the same order as zcashd displays them (see Block::to_compact)
+    cb.hash.reverse();
+    cb.prevHash.reverse();
+    for ctx in cb.vtx.iter_mut() {
+        ctx.hash.reverse();
+    }
+    Ok(cb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lwd_rpc::compact_tx_streamer_server::{CompactTxStreamer, CompactTxStreamerServer};
+    use crate::trp::zcashdrpc::{Block, Transaction};
+    use futures::Stream;
+    use std::pin::Pin;
+    use tokio::net::TcpListener;
+    use tonic::transport::Server;
+    use tonic::{Response, Status};
+
+    struct StubStreamer {
+        blocks: Vec<lwd::CompactBlock>,
+    }
+
+    #[tonic::async_trait]
+    impl CompactTxStreamer for StubStreamer {
+        type GetBlockRangeStream =
+            Pin<Box<dyn Stream<Item = Result<lwd::CompactBlock, Status>> + Send + Sync + 'static>>;
+
+        async fn get_latest_block(
+            &self,
+            _request: Request<lwd::ChainSpec>,
+        ) -> Result<Response<lwd::BlockId>, Status> {
+            let tip = self.blocks.last().unwrap();
+            Ok(Response::new(lwd::BlockId {
+                height: tip.height,
+                hash: tip.hash.clone(),
+            }))
+        }
+
+        async fn get_block(
+            &self,
+            request: Request<lwd::BlockId>,
+        ) -> Result<Response<lwd::CompactBlock>, Status> {
+            let height = request.into_inner().height;
+            let block = self
+                .blocks
+                .iter()
+                .find(|b| b.height == height)
+                .ok_or_else(|| Status::not_found("Unknown block"))?;
+            Ok(Response::new(block.clone()))
+        }
+
+        async fn get_block_range(
+            &self,
+            request: Request<lwd::BlockRange>,
+        ) -> Result<Response<Self::GetBlockRangeStream>, Status> {
+            let range = request.into_inner();
+            let start = range.start.map(|b| b.height).unwrap_or(0);
+            let end = range.end.map(|b| b.height).unwrap_or(u64::MAX);
+            let blocks: Vec<_> = self
+                .blocks
+                .iter()
+                .filter(|b| b.height >= start && b.height <= end)
+                .cloned()
+                .map(Ok)
+                .collect();
+            Ok(Response::new(Box::pin(futures::stream::iter(blocks))))
+        }
+
+        async fn get_tree_state(
+            &self,
+            request: Request<lwd::BlockId>,
+        ) -> Result<Response<lwd::TreeState>, Status> {
+            let height = request.into_inner().height;
+            Ok(Response::new(lwd::TreeState {
+                network: "test".to_string(),
+                height,
+                hash: hex::encode([0u8; 32]),
+                time: 0,
+                tree: "000000".to_string(),
+            }))
+        }
+    }
+
+    fn recorded_blocks(start: u64, count: u64) -> Vec<lwd::CompactBlock> {
+        let mut prev_hash = vec![0u8; 32];
+        (start..start + count)
+            .map(|height| {
+                let mut hash = vec![0u8; 32];
+                hash[..8].copy_from_slice(&height.to_le_bytes());
+                let block = lwd::CompactBlock {
+                    proto_version: 1,
+                    height,
+                    hash: hash.clone(),
+                    prev_hash: prev_hash.clone(),
+                    time: 1_600_000_000 + height as u32,
+                    header: vec![],
+                    vtx: vec![lwd::CompactTx {
+                        index: 0,
+                        hash: hash.clone(),
+                        fee: 0,
+                        spends: vec![lwd::CompactSpend { nf: vec![1u8; 32] }],
+                        outputs: vec![lwd::CompactOutput {
+                            cmu: vec![2u8; 32],
+                            epk: vec![3u8; 32],
+                            ciphertext: vec![4u8; 52],
+                        }],
+                    }],
+                };
+                prev_hash = hash;
+                block
+            })
+            .collect()
+    }
+
+    /// Serves the stub on a free port and returns its url
+    async fn serve_stub(stub: StubStreamer) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        tokio::spawn(
+            Server::builder()
+                .add_service(CompactTxStreamerServer::new(stub))
+                .serve_with_incoming(Box::pin(incoming)),
+        );
+        url
+    }
+
+    #[tokio::test]
+    async fn test_stub_block_range() {
+        let stub = StubStreamer {
+            blocks: recorded_blocks(1_000_000, 20),
+        };
+        let url = serve_stub(stub).await;
+
+        let mut client = connect_lightwalletd(&url).await.unwrap();
+        assert_eq!(get_latest_height(&mut client).await.unwrap(), 1_000_019);
+        let blocks = tokio::task::spawn_blocking(move || {
+            stream_block_range(&url, 1_000_005..1_000_010).collect::<crate::Result<Vec<_>>>()
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(blocks.len(), 5);
+        for (b, height) in blocks.iter().zip(1_000_005u64..) {
+            assert_eq!(b.height, height);
+            assert_eq!(b.vtx[0].outputs[0].ciphertext.len(), 52);
+        }
+        for w in blocks.windows(2) {
+            assert_eq!(w[1].prevHash, w[0].hash);
+        }
+    }
+
+    // Both block sources must give the hashes that we store in the order zcashd displays them
+    #[test]
+    fn test_hashes_in_display_order() {
+        let lwd_blocks = recorded_blocks(1_000_000, 2);
+        let lwd_block = &lwd_blocks[1];
+        let display_hex = |bytes: &[u8]| {
+            let mut bytes = bytes.to_vec();
+            bytes.reverse();
+            hex::encode(bytes)
+        };
+        let zcashd_block = Block {
+            hash: display_hex(&lwd_block.hash),
+            height: lwd_block.height as u32,
+            anchor: String::new(),
+            previousblockhash: Some(display_hex(&lwd_block.prev_hash)),
+            nextblockhash: None,
+            time: lwd_block.time as u64,
+            tx: vec![Transaction {
+                txid: display_hex(&lwd_block.vtx[0].hash),
+                height: Some(lwd_block.height as u32),
+                vin: vec![],
+                vout: vec![],
+                vShieldedSpend: vec![],
+                vShieldedOutput: vec![],
+            }],
+        };
+
+        let from_lwd = to_compact_block(lwd_block).unwrap();
+        let from_zcashd = zcashd_block.to_compact().unwrap();
+        assert_eq!(hex::encode(&from_lwd.hash), zcashd_block.hash);
+        assert_eq!(from_lwd.hash, from_zcashd.hash);
+        assert_eq!(from_lwd.prevHash, from_zcashd.prevHash);
+        assert_eq!(hex::encode(&from_lwd.vtx[0].hash), zcashd_block.tx[0].txid);
+        assert_eq!(from_lwd.vtx[0].hash, from_zcashd.vtx[0].hash);
+    }
+}
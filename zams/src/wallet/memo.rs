@@ -0,0 +1,129 @@
+use crate::db;
+use crate::trp::zcashdrpc::get_raw_transaction_hex;
+use crate::wallet::PostgresWallet;
+use crate::ZamsConfig;
+use anyhow::anyhow;
+use postgres::Client;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+use zcash_client_backend::data_api::WalletRead;
+use zcash_client_backend::decrypt_transaction;
+use zcash_primitives::consensus::BlockHeight;
+use zcash_primitives::memo::{Memo, MemoBytes};
+use zcash_primitives::transaction::Transaction;
+
+/// Compact blocks do not have the memos. Downloads the full transactions
+/// of the received notes that don't have their memo yet and decrypts them.
+/// Notes whose memo cannot be recovered get an empty memo so that they are not fetched again.
+/// Transactions that cannot be downloaded are retried on the next scan
+pub fn fetch_memos(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> crate::Result<()> {
+    let txs = {
+        let mut c = client.lock().unwrap();
+        db::list_txs_without_memo(&mut *c)?
+    };
+    if txs.is_empty() {
+        return Ok(());
+    }
+    let wallet = PostgresWallet::new(client.clone(), config)?;
+    let extfvks = wallet.get_extended_full_viewing_keys()?;
+    let r = Runtime::new().unwrap();
+    for (tx_hash, height) in txs.iter() {
+        let raw_tx = r.block_on(async {
+            let client = reqwest::Client::new();
+            get_raw_transaction_hex(tx_hash, &client, config).await
+        });
+        let raw_tx = match raw_tx {
+            Ok(raw_tx) => raw_tx,
+            Err(e) => {
+                log::warn!("Cannot fetch tx {} for its memos: {}", tx_hash, e);
+                continue;
+            }
+        };
+        let mut c = client.lock().unwrap();
+        // a transaction that cannot be parsed has no readable memo
+        match Transaction::read(&*hex::decode(raw_tx)?) {
+            Ok(tx) => {
+                let outputs = decrypt_transaction(&config.network, BlockHeight::from_u32(*height), &tx, &extfvks);
+                for output in outputs.iter().filter(|o| !o.outgoing) {
+                    db::store_memo(&mut *c, tx_hash, output.index as i32, output.memo.as_slice())?;
+                }
+            }
+            Err(e) => log::warn!("Cannot read tx {} for its memos: {}", tx_hash, e),
+        }
+        db::mark_memos_unavailable(&mut *c, tx_hash)?;
+    }
+    Ok(())
+}
+
+pub fn decode_memo(memo: &[u8]) -> crate::Result<Memo> {
+    let memo = MemoBytes::from_bytes(memo).map_err(|_| anyhow!("Invalid memo"))?;
+    let memo = Memo::try_from(memo).map_err(|_| anyhow!("Invalid memo"))?;
+    Ok(memo)
+}
+
+/// An outgoing memo given either as UTF-8 text or as raw bytes in hex
+pub fn parse_memo(text: &str, raw_hex: &str) -> crate::Result<Option<MemoBytes>> {
+    match (text.is_empty(), raw_hex.is_empty()) {
+        (true, true) => Ok(None),
+        (false, true) => {
+            let memo = Memo::from_str(text).map_err(|_| anyhow!("Memo is longer than 512 bytes"))?;
+            Ok(Some(memo.into()))
+        }
+        (true, false) => {
+            let memo = MemoBytes::from_bytes(&hex::decode(raw_hex)?).map_err(|_| anyhow!("Memo is longer than 512 bytes"))?;
+            Ok(Some(memo))
+        }
+        (false, false) => Err(anyhow!("Memo must be either text or hex").into()),
+    }
+}
+
+/// Text memos as is, other non empty memos in hex.
+/// Memos that could not be fetched are stored empty
+pub fn memo_to_string(memo: &[u8]) -> Option<String> {
+    if memo.is_empty() {
+        return None;
+    }
+    match decode_memo(memo) {
+        Ok(Memo::Empty) => None,
+        Ok(Memo::Text(text)) => {
+            let text: &str = &text;
+            Some(text.to_string())
+        }
+        _ => Some(hex::encode(memo)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memo_to_string() {
+        let mut memo = [0u8; 512];
+        memo[0] = 0xF6;
+        assert_eq!(memo_to_string(&memo), None);
+        assert_eq!(memo_to_string(&[]), None);
+
+        let mut memo = [0u8; 512];
+        memo[..9].copy_from_slice(b"order-123");
+        assert_eq!(memo_to_string(&memo).as_deref(), Some("order-123"));
+
+        let mut memo = [0u8; 512];
+        memo[0] = 0xFF;
+        memo[1] = 0x01;
+        assert_eq!(memo_to_string(&memo), Some(hex::encode(&memo[..])));
+    }
+
+    #[test]
+    fn test_parse_memo() {
+        assert!(parse_memo("", "").unwrap().is_none());
+        let memo = parse_memo("refund-42", "").unwrap().unwrap();
+        assert_eq!(memo_to_string(memo.as_slice()).as_deref(), Some("refund-42"));
+        let memo = parse_memo("", "ff01").unwrap().unwrap();
+        assert_eq!(&memo.as_slice()[..2], &[0xFF, 0x01]);
+        assert!(parse_memo(&"x".repeat(513), "").is_err());
+        assert!(parse_memo("refund-42", "ff01").is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use crate::db;
+use crate::db::store_notification;
+use crate::error::WalletError;
+use crate::network::ZamsNetwork;
+use crate::notification::NotificationRecord;
+use crate::trp::zcashdrpc::stream_blocks;
+use crate::trp::TrpWallet;
+use crate::wallet::scan::MAX_CHUNK;
+use crate::wallet::{PostgresWallet, WalletDbTransaction, MAX_REORG_DEPTH};
+use crate::ZamsConfig;
+use anyhow::anyhow;
+use postgres::Client;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use zcash_client_backend::data_api::WalletRead;
+use zcash_client_backend::proto::compact_formats::CompactBlock;
+use zcash_client_backend::wallet::{AccountId, WalletTx};
+use zcash_client_backend::welding_rig::scan_block;
+use zcash_primitives::consensus::BlockHeight;
+use zcash_primitives::merkle_tree::{CommitmentTree, IncrementalWitness};
+use zcash_primitives::sapling::{Node, Nullifier};
+use zcash_primitives::zip32::ExtendedFullViewingKey;
+
+/// Key imported after the wallet synced, whose past transactions must be found
+#[derive(Clone, Copy, Debug)]
+pub enum RescanKey {
+    /// Sapling viewing key (and the transparent receivers of its unified addresses)
+    Fvk(i32),
+    /// Imported transparent address
+    Account(i32),
+}
+
+/// Replays the blocks from `from_height` (or the key birthday) to the wallet tip for this key only.
+/// Runs in the background and returns the id of the job for GetRescanStatus.
+/// `scan_lock` is the lock of the main scan which is only paused for the last blocks
+pub fn start_rescan(
+    client: Arc<Mutex<Client>>,
+    scan_lock: Arc<Mutex<()>>,
+    key: RescanKey,
+    from_height: Option<u32>,
+    config: &ZamsConfig,
+) -> Result<i32, WalletError> {
+    let (id_rescan, start_height) = create_rescan(&client, key, from_height)?;
+    let config = config.clone();
+    thread::spawn(move || {
+        let res = run_rescan(client.clone(), &scan_lock, key, start_height, id_rescan, &config);
+        finish_rescan(&client, id_rescan, res);
+    });
+    Ok(id_rescan)
+}
+
+/// Same as `start_rescan` but waits for the end of the rescan
+pub fn rescan(
+    client: Arc<Mutex<Client>>,
+    scan_lock: Arc<Mutex<()>>,
+    key: RescanKey,
+    from_height: Option<u32>,
+    config: &ZamsConfig,
+) -> Result<i32, WalletError> {
+    let (id_rescan, start_height) = create_rescan(&client, key, from_height)?;
+    let res = run_rescan(client.clone(), &scan_lock, key, start_height, id_rescan, config);
+    let failed = res.is_err();
+    finish_rescan(&client, id_rescan, res);
+    if failed {
+        return Err(WalletError::Error(anyhow!("Rescan {} failed", id_rescan)));
+    }
+    Ok(id_rescan)
+}
+
+fn create_rescan(client: &Arc<Mutex<Client>>, key: RescanKey, from_height: Option<u32>) -> Result<(i32, u32), WalletError> {
+    let mut c = client.lock().unwrap();
+    let from_height = match from_height {
+        Some(height) => height,
+        None => db::get_key_birthday(&mut *c, &key)?
+            .ok_or_else(|| anyhow!("The key has no birthday. Give the height to rescan from"))?,
+    };
+    let (min_height, max_height) = db::block_height_extrema(&mut *c)?
+        .map(|(min_height, max_height)| (u32::from(min_height), u32::from(max_height)))
+        .ok_or_else(|| anyhow!("No blocks scanned"))?;
+    // We start from the tree state of the previous block
+    if from_height <= min_height {
+        return Err(WalletError::Error(anyhow!(
+            "Cannot rescan before the wallet start at {}. Use reset-to-checkpoint",
+            min_height + 1
+        )));
+    }
+    let id_rescan = db::create_rescan(&mut *c, &key, from_height, max_height)?;
+    Ok((id_rescan, from_height))
+}
+
+fn finish_rescan(client: &Arc<Mutex<Client>>, id_rescan: i32, res: Result<(), WalletError>) {
+    let error = match res {
+        Ok(_) => {
+            log::info!("Rescan {} completed", id_rescan);
+            None
+        }
+        Err(e) => {
+            log::error!("Rescan {} failed: {:?}", id_rescan, e);
+            Some(format!("{:?}", e))
+        }
+    };
+    let mut c = client.lock().unwrap();
+    if let Err(e) = db::finish_rescan(&mut *c, id_rescan, error) {
+        log::error!("Cannot update rescan {}: {:?}", id_rescan, e);
+    }
+}
+
+fn run_rescan(
+    client: Arc<Mutex<Client>>,
+    scan_lock: &Mutex<()>,
+    key: RescanKey,
+    start_height: u32,
+    id_rescan: i32,
+    config: &ZamsConfig,
+) -> Result<(), WalletError> {
+    log::info!("Rescan {} of {:?} from {}", id_rescan, key, start_height);
+    let wallet = PostgresWallet::new(client.clone(), config)?;
+    let mut rescanner = Rescanner {
+        client: client.clone(),
+        config,
+        sapling: match key {
+            RescanKey::Fvk(id_fvk) => Some(SaplingRescan::new(&wallet, id_fvk, start_height)?),
+            RescanKey::Account(_) => None,
+        },
+        trp_wallet: TrpWallet::new(client.clone(), config.clone())?,
+        next_height: start_height,
+        last_hash: None,
+    };
+    let addresses = {
+        let mut c = client.lock().unwrap();
+        db::get_key_trp_addresses(&mut *c, &key)?
+    };
+    rescanner.trp_wallet.watch_addresses(&addresses);
+
+    // Catch up with the wallet tip while the main scan keeps running
+    rescanner.scan_to_tip(id_rescan)?;
+
+    // then pause the main scan for the last blocks and the merge
+    let _lock = scan_lock.lock().unwrap();
+    rescanner.scan_to_tip(id_rescan)?;
+    rescanner.check_not_reorged()?;
+    if let Some(sapling) = rescanner.sapling.as_ref() {
+        sapling.merge(&wallet)?;
+    }
+    Ok(())
+}
+
+struct Rescanner<'a> {
+    client: Arc<Mutex<Client>>,
+    config: &'a ZamsConfig,
+    sapling: Option<SaplingRescan>,
+    trp_wallet: TrpWallet,
+    next_height: u32,
+    last_hash: Option<Vec<u8>>,
+}
+
+impl<'a> Rescanner<'a> {
+    fn scan_to_tip(&mut self, id_rescan: i32) -> Result<(), WalletError> {
+        loop {
+            let tip_height = {
+                let mut c = self.client.lock().unwrap();
+                db::get_tip_height(&mut *c)?.unwrap_or(0)
+            };
+            if self.next_height > tip_height {
+                return Ok(());
+            }
+            let end_height = (tip_height + 1).min(self.next_height + MAX_CHUNK);
+            self.scan_range(self.next_height..end_height)?;
+            self.next_height = end_height;
+            let mut c = self.client.lock().unwrap();
+            db::update_rescan_progress(&mut *c, id_rescan, end_height - 1, tip_height)?;
+        }
+    }
+
+    fn scan_range(&mut self, range: Range<u32>) -> Result<(), WalletError> {
+        let mut height = range.start;
+        for block in stream_blocks(range.clone(), self.config) {
+            let block = block?;
+            if block.height != height {
+                return Err(WalletError::Error(anyhow!("Expected block {} but got {}", height, block.height)));
+            }
+            height += 1;
+            // Only replay the blocks that the main scan has
+            let block_hash = hex::decode(&block.hash)?;
+            let stored_hash = {
+                let mut c = self.client.lock().unwrap();
+                db::get_block_by_height(&mut *c, block.height)?
+            };
+            if stored_hash.as_ref() != Some(&block_hash) {
+                return Err(WalletError::Reorg);
+            }
+            if let Some(sapling) = self.sapling.as_mut() {
+                sapling.scan_block(&self.config.network, block.to_compact()?);
+            }
+            self.trp_wallet.scan_blocks(std::slice::from_ref(&block))?;
+            self.last_hash = Some(block_hash);
+        }
+        if height != range.end {
+            return Err(WalletError::Error(anyhow!("The download stopped at block {}", height)));
+        }
+        Ok(())
+    }
+
+    // The main scan may have rewound and replaced the blocks we scanned
+    fn check_not_reorged(&self) -> Result<(), WalletError> {
+        if let Some(last_hash) = self.last_hash.as_ref() {
+            let mut c = self.client.lock().unwrap();
+            let stored_hash = db::get_block_by_height(&mut *c, self.next_height - 1)?;
+            if stored_hash.as_ref() != Some(last_hash) {
+                return Err(WalletError::Reorg);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Notes of the key found so far and their witnesses, kept in memory until the merge
+struct SaplingRescan {
+    account: AccountId,
+    extfvk: ExtendedFullViewingKey,
+    tree: CommitmentTree<Node>,
+    nullifiers: Vec<(AccountId, Nullifier)>,
+    witnesses: Vec<IncrementalWitness<Node>>,
+    txs: Vec<(BlockHeight, WalletTx<Nullifier>)>,
+    // witnesses of the last MAX_REORG_DEPTH blocks, like the main scan keeps
+    snapshots: VecDeque<(BlockHeight, Vec<IncrementalWitness<Node>>)>,
+}
+
+impl SaplingRescan {
+    fn new(wallet: &PostgresWallet, id_fvk: i32, start_height: u32) -> Result<SaplingRescan, WalletError> {
+        let account = AccountId(id_fvk as u32);
+        let extfvk = wallet
+            .get_extended_full_viewing_keys()?
+            .remove(&account)
+            .ok_or_else(|| anyhow!("Unknown viewing key {}", id_fvk))?;
+        let tree = wallet
+            .get_commitment_tree(BlockHeight::from_u32(start_height - 1))?
+            .ok_or_else(|| anyhow!("No tree state at {}", start_height - 1))?;
+        Ok(SaplingRescan {
+            account,
+            extfvk,
+            tree,
+            nullifiers: vec![],
+            witnesses: vec![],
+            txs: vec![],
+            snapshots: VecDeque::new(),
+        })
+    }
+
+    fn scan_block(&mut self, network: &ZamsNetwork, block: CompactBlock) {
+        let height = BlockHeight::from_u32(block.height as u32);
+        let txs = {
+            let mut witnesses: Vec<&mut IncrementalWitness<Node>> = self.witnesses.iter_mut().collect();
+            scan_block(
+                network,
+                block,
+                &[(&self.account, &self.extfvk)],
+                &self.nullifiers,
+                &mut self.tree,
+                &mut witnesses,
+            )
+        };
+        for tx in txs {
+            for spend in tx.shielded_spends.iter() {
+                self.nullifiers.retain(|(_, nf)| *nf != spend.nf);
+            }
+            for output in tx.shielded_outputs.iter() {
+                self.witnesses.push(output.witness.clone());
+                self.nullifiers.push((output.account, output.nf));
+            }
+            self.txs.push((height, tx));
+        }
+        self.snapshots.push_back((height, self.witnesses.clone()));
+        if self.snapshots.len() > MAX_REORG_DEPTH as usize + 1 {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Stores the notes, spends and witnesses in one db transaction.
+    /// Notes that the main scan found after the key was imported are left as they are
+    fn merge(&self, wallet: &PostgresWallet) -> Result<(), WalletError> {
+        let mut client = wallet.client.lock().unwrap();
+        let mut notifications: Vec<NotificationRecord> = Vec::new();
+        {
+            let mut db_tx = WalletDbTransaction {
+                network: &wallet.network,
+                statements: wallet,
+                transaction: client.transaction()?,
+            };
+
+            // in the order of self.witnesses, None if the note was already known
+            let mut note_ids: Vec<Option<i32>> = vec![];
+            for (height, tx) in self.txs.iter() {
+                let tx_row = db_tx.put_tx_meta(tx, *height)?;
+                let tx_hash = hex::encode(tx.txid.0.to_vec());
+
+                for spend in tx.shielded_spends.iter() {
+                    db_tx.mark_spent(tx_row, &spend.nf)?;
+                    let (account, amount) = db_tx.get_account_by_nf(&spend.nf)?;
+                    notifications.push(NotificationRecord {
+                        id: 0, // ignored
+                        eventType: "outgoingTx".to_string(),
+                        txHash: tx_hash.clone(),
+                        account,
+                        address: None, // ignored
+                        txOutputIndex: spend.index as i32,
+                        amount,
+                        block: u32::from(*height),
+                        revertedEventType: None,
+                        confirmedEventType: None,
+                        confirmations: None,
+                        memo: None,
+                    });
+                }
+
+                for output in tx.shielded_outputs.iter() {
+                    let known = db_tx
+                        .transaction
+                        .query_opt(
+                            "SELECT id_note FROM received_notes WHERE tx = $1 AND output_index = $2",
+                            &[&tx_row, &(output.index as i32)],
+                        )?
+                        .is_some();
+                    let id_note = db_tx.put_received_note(output, tx_row)?;
+                    note_ids.push(if known { None } else { Some(id_note) });
+
+                    let row = db_tx.transaction.query_one(
+                        "SELECT account FROM received_notes WHERE id_note = $1",
+                        &[&id_note],
+                    )?;
+                    notifications.push(NotificationRecord {
+                        id: 0, // ignored
+                        eventType: "incomingTx".to_string(),
+                        txHash: tx_hash.clone(),
+                        account: row.get(0),
+                        address: None,
+                        txOutputIndex: output.index as i32,
+                        amount: output.note.value as i64,
+                        block: u32::from(*height),
+                        revertedEventType: None,
+                        confirmedEventType: None,
+                        confirmations: None,
+                        memo: None,
+                    });
+                }
+            }
+
+            for (height, witnesses) in self.snapshots.iter() {
+                for (id_note, witness) in note_ids.iter().zip(witnesses.iter()) {
+                    if let Some(id_note) = id_note {
+                        db_tx.insert_witness(*id_note, witness, *height)?;
+                    }
+                }
+            }
+
+            db_tx.transaction.commit()?;
+        }
+
+        for n in notifications.iter() {
+            store_notification(&mut *client, n)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zcash_primitives::zip32::ExtendedSpendingKey;
+
+    #[test]
+    fn test_rescan_keeps_reorg_witnesses() {
+        let extsk = ExtendedSpendingKey::master(&[0u8; 32]);
+        let mut sapling = SaplingRescan {
+            account: AccountId(1),
+            extfvk: ExtendedFullViewingKey::from(&extsk),
+            tree: CommitmentTree::read(&[0u8; 3][..]).unwrap(),
+            nullifiers: vec![],
+            witnesses: vec![],
+            txs: vec![],
+            snapshots: VecDeque::new(),
+        };
+        let network = ZamsNetwork::regtest();
+        for height in 1..=200 {
+            let mut block = CompactBlock::new();
+            block.height = height;
+            sapling.scan_block(&network, block);
+        }
+        assert!(sapling.txs.is_empty());
+        assert_eq!(sapling.snapshots.len(), MAX_REORG_DEPTH as usize + 1);
+        let (oldest_height, _) = sapling.snapshots.front().unwrap();
+        assert_eq!(u32::from(*oldest_height), 200 - MAX_REORG_DEPTH);
+    }
+}
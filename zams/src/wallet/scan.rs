@@ -2,35 +2,49 @@ use tokio::runtime::Runtime;
 use zcash_client_backend::data_api::chain::scan_cached_blocks;
 use zcash_client_backend::data_api::{BlockSource, WalletRead, WalletWrite};
 use zcash_client_backend::proto::compact_formats::CompactBlock;
-use zcash_primitives::consensus::{BlockHeight, Network, NetworkUpgrade, Parameters};
+use zcash_primitives::consensus::{BlockHeight, NetworkUpgrade, Parameters};
 use crate::error::WalletError;
 use crate::trp::TrpWallet;
-use crate::wallet::PostgresWallet;
+use crate::wallet::{PostgresWallet, MAX_REORG_DEPTH};
 use crate::db;
 use postgres::{Client, GenericClient};
+use std::cell::RefCell;
 use std::ops::Range;
 use std::sync::{Mutex, Arc};
-use crate::config::ZamsConfig;
-use crate::trp::zcashdrpc::{get_block, get_latest_height, get_tree_state};
+use crate::config::{BlockSourceType, ZamsConfig};
+use crate::trp::zcashdrpc::{get_block_hash, get_latest_height, get_tree_state, stream_blocks, Block, BlockStream, TreeState};
 use crate::notification::notify_tx;
+use crate::wallet::lwdrpc;
+use crate::wallet::memo::fetch_memos;
+use crate::checkpoints::{bundled_checkpoints, find_checkpoint, Checkpoint};
 
-const MAX_CHUNK: u32 = 1000;
+pub(crate) const MAX_CHUNK: u32 = 1000;
 
-pub struct ZcashdCompactBlockSource {
+/// Serves the compact blocks converted from the full zcashd blocks while they are downloaded.
+/// Each block goes through the transparent scanner just before the Sapling scanner,
+/// so it is downloaded once for both
+pub struct ZcashdCompactBlockSource<'a> {
     client: Arc<Mutex<Client>>,
-    config: ZamsConfig,
+    blocks: RefCell<BlockStream<Block>>,
+    trp_wallet: RefCell<&'a mut TrpWallet>,
 }
 
-impl ZcashdCompactBlockSource {
-    pub fn new(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> ZcashdCompactBlockSource {
+impl<'a> ZcashdCompactBlockSource<'a> {
+    pub fn new(
+        client: Arc<Mutex<Client>>,
+        range: Range<u32>,
+        trp_wallet: &'a mut TrpWallet,
+        config: &ZamsConfig,
+    ) -> ZcashdCompactBlockSource<'a> {
         ZcashdCompactBlockSource {
             client,
-            config: config.clone(),
+            blocks: RefCell::new(stream_blocks(range, config)),
+            trp_wallet: RefCell::new(trp_wallet),
         }
     }
 }
 
-impl BlockSource for ZcashdCompactBlockSource {
+impl<'a> BlockSource for ZcashdCompactBlockSource<'a> {
     type Error = WalletError;
 
     fn with_blocks<F>(
@@ -44,46 +58,100 @@ impl BlockSource for ZcashdCompactBlockSource {
     {
         // We scan [from_height+1, from_height+limit] (inclusive)
         let from_height = u32::from(from_height);
-        let start_height = from_height + 1;
-        let r = Runtime::new().unwrap();
-        let tip_height = get_latest_height(&self.config)?;
-        let end_height = from_height
-            .saturating_add(limit.unwrap_or(u32::MAX))
-            .min(tip_height);
-
-        let blocks = r.block_on(async {
-            let client = reqwest::Client::new();
-            let mut blocks: Vec<CompactBlock> = vec![];
-            for height in start_height..=end_height {
-                let block = get_block(&format!("{}", height), &client, &self.config).await?;
-                blocks.push(block.to_compact()?);
-            }
-            Ok::<_, WalletError>(blocks)
-        })?;
-        if blocks.is_empty() { return Ok(()) }
+        let mut blocks = self.blocks.borrow_mut();
+        let mut trp_wallet = self.trp_wallet.borrow_mut();
+        // the stream starts at from_height + 1, take no more than the scanner asked for
+        let compact_blocks = blocks
+            .by_ref()
+            .take(limit.map_or(usize::MAX, |limit| limit as usize))
+            .map(|b| -> crate::Result<(CompactBlock, Block)> {
+                let b = b?;
+                let cb = b.to_compact()?;
+                Ok((cb, b))
+            });
+        // the transparent scan waits until the blocks are known to connect
+        feed_blocks(&self.client, from_height, compact_blocks, &mut |cb, b| {
+            trp_wallet.scan_blocks(std::slice::from_ref(&b))?;
+            with_row(cb)
+        })
+    }
+}
+
+/// Serves the compact blocks of lightwalletd while they are downloaded. Only used when
+/// there is no transparent address because lightwalletd has no transparent data
+pub struct LightwalletdCompactBlockSource {
+    client: Arc<Mutex<Client>>,
+    blocks: RefCell<BlockStream<CompactBlock>>,
+}
+
+impl LightwalletdCompactBlockSource {
+    pub fn new(client: Arc<Mutex<Client>>, range: Range<u32>, url: &str) -> LightwalletdCompactBlockSource {
+        LightwalletdCompactBlockSource {
+            client,
+            blocks: RefCell::new(lwdrpc::stream_block_range(url, range)),
+        }
+    }
+}
+
+impl BlockSource for LightwalletdCompactBlockSource {
+    type Error = WalletError;
+
+    fn with_blocks<F>(
+        &self,
+        from_height: BlockHeight,
+        limit: Option<u32>,
+        mut with_row: F,
+    ) -> Result<(), Self::Error>
+    where
+        F: FnMut(CompactBlock) -> Result<(), Self::Error>,
+    {
+        // We scan [from_height+1, from_height+limit] (inclusive)
+        let from_height = u32::from(from_height);
+        let mut blocks = self.blocks.borrow_mut();
+        let compact_blocks = blocks
+            .by_ref()
+            .take(limit.map_or(usize::MAX, |limit| limit as usize))
+            .map(|cb| cb.map(|cb| (cb, ())));
+        feed_blocks(&self.client, from_height, compact_blocks, &mut |cb, _| with_row(cb))
+    }
+}
 
+// Checks that the first block connects to our last scanned block
+// before handing them over to the scanner, each with the data it came with
+fn feed_blocks<T, I, F>(
+    client: &Arc<Mutex<Client>>,
+    from_height: u32,
+    blocks: I,
+    with_row: &mut F,
+) -> Result<(), WalletError>
+where
+    I: Iterator<Item = crate::Result<(CompactBlock, T)>>,
+    F: FnMut(CompactBlock, T) -> Result<(), WalletError>,
+{
+    let mut blocks = blocks.peekable();
+    if let Some(Ok((first, _))) = blocks.peek() {
         let block_hash = {
-            let mut client = self.client.lock().unwrap();
+            let mut client = client.lock().unwrap();
             db::get_block_by_height(&mut *client, from_height)
         }?;
         if let Some(block_hash) = block_hash {
-            let b = blocks.first().unwrap();
-            if b.prevHash != block_hash {
+            if first.prevHash != block_hash {
                 return Err(WalletError::Reorg)
             }
         }
+    }
 
-        for cb in blocks {
-            with_row(cb).unwrap();
-        }
-
-        Ok(())
+    for block in blocks {
+        let (cb, data) = block?;
+        with_row(cb, data)?;
     }
+
+    Ok(())
 }
 
 pub fn get_scan_range(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> anyhow::Result<Range<u32>, WalletError> {
     let wallet = PostgresWallet::new(client, config).unwrap();
-    let sapling_activation_height: u32 = Network::TestNetwork
+    let sapling_activation_height: u32 = config.network
         .activation_height(NetworkUpgrade::Sapling)
         .unwrap()
         .into();
@@ -91,51 +159,200 @@ pub fn get_scan_range(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> anyhow
         opt.map(|(_, max)| u32::from(max))
             .unwrap_or(sapling_activation_height - 1)
     })? + 1;
-    let tip_height = get_latest_height(config)? + 1;
-    let to_height = tip_height.min(from_height + MAX_CHUNK);
+    let mut tip_height = get_latest_height(config)?;
+    if let BlockSourceType::Lightwalletd(url) = &config.block_source {
+        // lightwalletd may lag behind zcashd
+        let r = Runtime::new().unwrap();
+        let lwd_height = r.block_on(async {
+            let mut client = lwdrpc::connect_lightwalletd(url).await?;
+            lwdrpc::get_latest_height(&mut client).await
+        })?;
+        tip_height = tip_height.min(lwd_height);
+    }
+    let to_height = (tip_height + 1).min(from_height + MAX_CHUNK);
     Ok(from_height..to_height)
 }
 
-pub fn scan_sapling(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> anyhow::Result<(), WalletError> {
-    let source = ZcashdCompactBlockSource::new(client.clone(), config);
-    let mut data = PostgresWallet::new(client, config)?;
-    scan_cached_blocks(&Network::TestNetwork, &source, &mut data, Some(MAX_CHUNK))?;
+/// Scans the range with both scanners while it is downloaded. The transparent scanner
+/// needs the full zcashd blocks so lightwalletd only serves wallets without transparent addresses
+pub fn scan_range(
+    client: Arc<Mutex<Client>>,
+    trp_wallet: &mut TrpWallet,
+    range: Range<u32>,
+    config: &ZamsConfig,
+) -> anyhow::Result<(), WalletError> {
+    let mut data = PostgresWallet::new(client.clone(), config)?;
+    trp_wallet.load_transparent_addresses_from_db()?;
+    trp_wallet.load_utxos_from_db()?;
+    match &config.block_source {
+        BlockSourceType::Lightwalletd(url) if !trp_wallet.has_addresses() => {
+            let source = LightwalletdCompactBlockSource::new(client, range, url);
+            scan_cached_blocks(&config.network, &source, &mut data, Some(MAX_CHUNK))?;
+        }
+        _ => {
+            let source = ZcashdCompactBlockSource::new(client, range, trp_wallet, config);
+            scan_cached_blocks(&config.network, &source, &mut data, Some(MAX_CHUNK))?;
+        }
+    }
     Ok(())
 }
 
+/// Starts a new wallet from the block before the earliest key birthday, using the tree
+/// state from the node or else the nearest bundled checkpoint.
+/// Returns false if there is nothing to scan because no key is imported yet
+pub fn bootstrap_checkpoint(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> Result<bool, WalletError> {
+    let mut c = client.lock().unwrap();
+    if db::block_height_extrema(&mut *c)?.is_some() {
+        return Ok(true);
+    }
+    if !db::has_keys(&mut *c)? {
+        return Ok(false);
+    }
+    let sapling_activation_height: u32 = config.network
+        .activation_height(NetworkUpgrade::Sapling)
+        .unwrap()
+        .into();
+    let birthday = match db::get_wallet_birthday(&mut *c)? {
+        Some(birthday) if birthday > sapling_activation_height => birthday,
+        _ => return Ok(true), // scan from the Sapling activation
+    };
+    let error = match load_checkpoint(&mut *c, birthday - 1, config) {
+        Ok(_) => {
+            log::info!("Start from checkpoint {} for birthday {}", birthday - 1, birthday);
+            return Ok(true);
+        }
+        Err(error) => error,
+    };
+    log::warn!("No tree state at {} from the node: {:?}", birthday - 1, error);
+    let checkpoints = bundled_checkpoints(&config.network);
+    let cp = find_checkpoint(&checkpoints, birthday - 1)
+        .filter(|cp| cp.height >= sapling_activation_height)
+        .ok_or(error)?;
+    log::info!("Start from bundled checkpoint {} for birthday {}", cp.height, birthday);
+    let hash = get_block_hash(cp.height, config)?;
+    if hash != cp.hash {
+        return Err(WalletError::Error(anyhow::anyhow!(
+            "Bundled checkpoint {} has hash {} but the node has {}", cp.height, cp.hash, hash)));
+    }
+    db::load_checkpoint(
+        &mut *c,
+        cp.height,
+        &hex::decode(&cp.hash).map_err(|_| anyhow::anyhow!("Not hex"))?,
+        cp.time as i32,
+        &hex::decode(&cp.tree).map_err(|_| anyhow::anyhow!("Not hex"))?,
+    )?;
+    Ok(true)
+}
+
 pub fn scan_chain(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> anyhow::Result<u32, WalletError> {
+    if !bootstrap_checkpoint(client.clone(), config)? {
+        log::info!("No key imported yet - nothing to scan");
+        return Ok(0);
+    }
     let mut trp_wallet = TrpWallet::new(client.clone(), config.clone())?;
+    let mut last_start = None;
     let range = loop {
         let range = get_scan_range(client.clone(), config)?;
         log::info!("Scan {:?}", &range);
         if range.end <= range.start {
             break range;
         }
-        let mut scan = || {
-            scan_sapling(client.clone(), config)?;
-            trp_wallet.scan_transparent(range.clone())?;
-            Ok(())
-        };
-        let scan_result = scan();
+        if last_start == Some(range.start) {
+            log::warn!("The block source stopped at {} - retry on the next scan", range.start);
+            break range.start..range.start;
+        }
+        last_start = Some(range.start);
+        let scan_result = scan_range(client.clone(), &mut trp_wallet, range.clone(), config);
         match scan_result {
-            Err(WalletError::Reorg) => rewind_to_height(client.clone(), range.start - 10, config)?,
+            Err(WalletError::Reorg) => {
+                let fork_height = find_fork_height(client.clone(), config)?;
+                log::warn!("Reorg detected - common ancestor at {}", fork_height);
+                rewind_to_height(client.clone(), fork_height, config)?;
+                last_start = None;
+            }
             _ => scan_result?,
         }
     };
+    if let Err(e) = fetch_memos(client.clone(), config) {
+        log::warn!("Cannot fetch memos: {:?}", e); // retried on the next scan
+    }
     let mut c = client.lock().unwrap();
+    db::store_confirmations(&mut *c, &config.confirmation_thresholds)?;
+    db::update_payment_states(&mut *c, config.payment_confirmations)?;
     let _ = notify_tx(&mut *c, config); // ignore failures - will retry
 
     Ok(range.end)
 }
 
+/// Walks back from our tip until our block hash matches zcashd's.
+/// Fails if the common ancestor is older than the witnesses we keep
+pub fn find_fork_height(client: Arc<Mutex<Client>>, config: &ZamsConfig) -> Result<u32, WalletError> {
+    let (min_height, max_height) = {
+        let mut c = client.lock().unwrap();
+        db::block_height_extrema(&mut *c)?
+    }
+    .map(|(min_height, max_height)| (u32::from(min_height), u32::from(max_height)))
+    .ok_or_else(|| anyhow::anyhow!("No blocks scanned"))?;
+    let oldest_height = max_height.saturating_sub(MAX_REORG_DEPTH).max(min_height);
+
+    let mut height = max_height;
+    loop {
+        let stored_hash = {
+            let mut c = client.lock().unwrap();
+            db::get_block_by_height(&mut *c, height)?
+        };
+        let node_hash = get_block_hash(height, config)?;
+        if stored_hash.map(hex::encode) == Some(node_hash) {
+            return Ok(height);
+        }
+        if height <= oldest_height {
+            log::error!("Reorg is deeper than {}. Use reset-to-checkpoint to recover", oldest_height);
+            return Err(WalletError::ReorgTooDeep(oldest_height));
+        }
+        height -= 1;
+    }
+}
+
+/// Discards the scanned chain and restarts from the checkpoint at `height`.
+/// Recovery path when the chain reorganized deeper than MAX_REORG_DEPTH
+pub fn reset_to_checkpoint(client: Arc<Mutex<Client>>, height: u32, config: &ZamsConfig) -> Result<(), WalletError> {
+    log::warn!("Reset to checkpoint {}", height);
+    let mut c = client.lock().unwrap();
+    let mut db_tx = c.transaction()?;
+    db::reset_to_checkpoint(&mut db_tx, height)?;
+    load_checkpoint(&mut db_tx, height, config)?;
+    db_tx.commit()?;
+    Ok(())
+}
+
+fn fetch_tree_state(height: u32, config: &ZamsConfig) -> Result<TreeState, WalletError> {
+    let tree_state = match &config.block_source {
+        BlockSourceType::Zcashd => get_tree_state(height, config)?,
+        BlockSourceType::Lightwalletd(url) => {
+            let r = Runtime::new().unwrap();
+            r.block_on(async {
+                let mut client = lwdrpc::connect_lightwalletd(url).await?;
+                lwdrpc::get_tree_state(&mut client, height).await
+            })?
+        }
+    };
+    Ok(tree_state)
+}
+
+/// Fetches the tree state at `height` in the format of the bundled checkpoints
+pub fn export_checkpoint(height: u32, config: &ZamsConfig) -> Result<Checkpoint, WalletError> {
+    let tree_state = fetch_tree_state(height, config)?;
+    Ok(Checkpoint::new(height, tree_state))
+}
+
 pub fn load_checkpoint<C: GenericClient>(client: &mut C, height: u32, config: &ZamsConfig) -> Result<(), WalletError> {
-    let tree_state = get_tree_state(height, config)?;
+    let tree_state = fetch_tree_state(height, config)?;
 
     db::load_checkpoint(
         client,
         height as u32,
         &hex::decode(tree_state.hash).map_err(|_| anyhow::anyhow!("Not hex"))?,
-        0, // TODO: tree_state.time as i32,
+        tree_state.time as i32,
         &hex::decode(tree_state.tree).map_err(|_| anyhow::anyhow!("Not hex"))?,
     )?;
     Ok(())
@@ -149,3 +366,22 @@ pub fn rewind_to_height(client: Arc<Mutex<Client>>, height: u32, config: &ZamsCo
     trp_wallet.rewind_to_height(height)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres::NoTls;
+
+    #[test]
+    fn test_find_fork_height() {
+        let config = ZamsConfig::default();
+        let client = Client::connect(&config.connection_string, NoTls).unwrap();
+        let client = Arc::new(Mutex::new(client));
+        let (_, max_height) = db::block_height_extrema(&mut *client.lock().unwrap())
+            .unwrap()
+            .unwrap();
+        // Without a reorg, the fork point is our tip
+        let fork_height = find_fork_height(client, &config).unwrap();
+        assert_eq!(fork_height, u32::from(max_height));
+    }
+}
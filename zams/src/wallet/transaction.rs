@@ -1,29 +1,39 @@
 use crate::error::WalletError;
-use anyhow::{anyhow, Context};
-use jubjub::Fr;
+use anyhow::anyhow;
 use zcash_client_backend::address::RecipientAddress;
+use crate::network::UnifiedParameters;
+use crate::unified::decode_recipient;
+use crate::wallet::{rseed_from_bytes, rseed_to_bytes};
+use crate::wallet::memo::parse_memo;
+use zcash_client_backend::decrypt_transaction;
 use zcash_client_backend::encoding::{
-    decode_extended_full_viewing_key, decode_extended_spending_key, decode_payment_address,
+    decode_extended_full_viewing_key, decode_payment_address,
+    encode_payment_address,
 };
 use zcash_primitives::consensus::{BlockHeight, BranchId, Parameters};
 use zcash_primitives::merkle_tree::IncrementalWitness;
 use zcash_primitives::sapling::{Diversifier, Node, Rseed};
 use zcash_primitives::transaction::builder::Builder;
+use zcash_primitives::transaction::Transaction;
 use zcash_primitives::transaction::components::{Amount, OutPoint, TxOut};
 use crate::{db, ZamsConfig, ZATPERZEC};
 use crate::db::DbPreparedStatements;
-use postgres::{Client, GenericClient};
-use rand::prelude::SliceRandom;
+use crate::payment::PaymentState;
+use postgres::GenericClient;
 use rand::RngCore;
-use std::str::FromStr;
+use std::collections::HashMap;
 use std::time::SystemTime;
-use zcash_client_backend::wallet::SpendableNote;
+use zcash_client_backend::wallet::{AccountId, SpendableNote};
 use zcash_primitives::legacy::Script;
+use zcash_primitives::memo::MemoBytes;
 use zcash_primitives::sapling::keys::OutgoingViewingKey;
 use zcash_primitives::transaction::components::amount::DEFAULT_FEE;
+use crate::wallet::coin_selector::CoinSelector;
+use crate::wallet::fee::{FeeRule, TxShape};
+use crate::wallet::keyring::Keyring;
 use zcash_proofs::prover::LocalTxProver;
 use crate::zams_rpc as grpc;
-use crate::trp::zcashdrpc::send_raw_tx;
+use crate::trp::zcashdrpc::{is_rpc_error, send_raw_tx};
 
 #[derive(Debug, Clone)]
 pub enum Account {
@@ -52,23 +62,27 @@ impl NoteLike<grpc::SaplingTxIn> for SpendableNoteWithId {
 
     fn to_tx_input(&self, id: i32, from_address: &str) -> Result<grpc::SaplingTxIn, WalletError> {
         let a = u64::from(self.note.note_value);
-        match self.note.rseed {
-            Rseed::BeforeZip212(rcm) => {
-                let mut mp = Vec::<u8>::new();
-                self.note.witness.write(&mut mp).map_err(WalletError::IO)?;
-
-                let input = grpc::SaplingTxIn {
-                    id,
-                    amount: a,
-                    address: from_address.to_string(),
-                    diversifier: hex::encode(&self.note.diversifier.0),
-                    rcm: hex::encode(rcm.to_bytes()),
-                    witness: hex::encode(mp),
-                };
-                Ok(input)
-            }
-            _ => unreachable!(),
-        }
+        let mut mp = Vec::<u8>::new();
+        self.note.witness.write(&mut mp).map_err(WalletError::IO)?;
+        // Signers that predate ZIP 212 only know rcm
+        let rcm = match self.note.rseed {
+            Rseed::BeforeZip212(rcm) => hex::encode(rcm.to_bytes()),
+            Rseed::AfterZip212(_) => String::new(),
+        };
+        let (rseed, zip212) = rseed_to_bytes(&self.note.rseed);
+
+        let input = grpc::SaplingTxIn {
+            id,
+            amount: a,
+            address: from_address.to_string(),
+            diversifier: hex::encode(&self.note.diversifier.0),
+            rcm,
+            witness: hex::encode(mp),
+            rseed: hex::encode(rseed),
+            zip212,
+            key_path: None,
+        };
+        Ok(input)
     }
 }
 
@@ -84,52 +98,197 @@ impl NoteLike<grpc::Utxo> for grpc::Utxo {
     }
 }
 
+/// A note of a shielded account or a utxo of the transparent receiver of its unified addresses
+enum AccountNote {
+    Sapling(SpendableNoteWithId),
+    Transparent(grpc::Utxo),
+}
+
+enum AccountTxIn {
+    Sapling(grpc::SaplingTxIn),
+    Transparent(grpc::Utxo),
+}
+
+impl NoteLike<AccountTxIn> for AccountNote {
+    fn id(&self) -> i32 {
+        match self {
+            AccountNote::Sapling(note) => note.id,
+            AccountNote::Transparent(utxo) => utxo.id,
+        }
+    }
+    fn note_value(&self) -> Amount {
+        match self {
+            AccountNote::Sapling(note) => note.note_value(),
+            AccountNote::Transparent(utxo) => utxo.note_value(),
+        }
+    }
+    fn to_tx_input(&self, id: i32, from_address: &str) -> Result<AccountTxIn, WalletError> {
+        Ok(match self {
+            AccountNote::Sapling(note) => AccountTxIn::Sapling(note.to_tx_input(id, from_address)?),
+            AccountNote::Transparent(utxo) => AccountTxIn::Transparent(utxo.clone()),
+        })
+    }
+}
+
+/// The notes of a shielded account and the utxos of its transparent receiver
+fn get_account_notes<C: GenericClient>(
+    c: &mut C,
+    statements: &DbPreparedStatements,
+    account: i32,
+    address: &str,
+    anchor_height: u32,
+) -> crate::Result<Vec<AccountNote>> {
+    let mut notes: Vec<AccountNote> = db::get_spendable_notes_by_address(c, statements, address, anchor_height)?
+        .into_iter()
+        .map(AccountNote::Sapling)
+        .collect();
+    if let Some(receiver) = db::get_transparent_receiver(c, account)? {
+        let utxos = db::get_spendable_transparent_notes_by_address(c, statements, &receiver)?;
+        notes.extend(utxos.into_iter().map(AccountNote::Transparent));
+    }
+    Ok(notes)
+}
+
 fn select_notes<TxIn, N: NoteLike<TxIn>, R: RngCore>(
     from_address: &str,
-    spendable_notes: &mut Vec<N>,
+    spendable_notes: &[N],
     target_value: Amount,
+    selector: &CoinSelector,
     rng: &mut R,
 ) -> crate::Result<Vec<TxIn>> {
-    spendable_notes.shuffle(rng);
-    let mut partial_sum = Amount::zero();
-    let mut index = 0usize;
-    for s in spendable_notes.iter() {
-        partial_sum += s.note_value();
-        index += 1;
-        if partial_sum >= target_value {
-            break;
-        }
-    }
-    let (selected_notes, _) = spendable_notes.split_at(index);
-    let selected_value: Amount = selected_notes.iter().map(|n| n.note_value()).sum();
-    if selected_value < target_value {
-        return Err(WalletError::Error(anyhow!(
+    let values: Vec<Amount> = spendable_notes.iter().map(|n| n.note_value()).collect();
+    let selected = selector.select(&values, target_value, rng).ok_or_else(|| {
+        let available_value: Amount = values.iter().copied().sum();
+        WalletError::Error(anyhow!(
             "Not enough funds: needed={:?}, available={:?}",
             target_value,
-            selected_value
-        )));
-    }
+            available_value
+        ))
+    })?;
 
-    selected_notes
+    selected
         .iter()
-        .map(|s| s.to_tx_input(s.id(), from_address))
+        .map(|&i| {
+            let s = &spendable_notes[i];
+            s.to_tx_input(s.id(), from_address)
+        })
         .collect::<Result<Vec<_>, _>>()
 }
 
+/// Sum of amounts that fails instead of panicking when it is above the maximum supply
+fn checked_total<I: IntoIterator<Item = Amount>>(amounts: I) -> crate::Result<Amount> {
+    amounts
+        .into_iter()
+        .try_fold(0i64, |total, amount| total.checked_add(i64::from(amount)))
+        .and_then(|total| Amount::from_i64(total).ok())
+        .ok_or_else(|| WalletError::Error(anyhow!("total amount out of range")))
+}
+
+/// Selects inputs for `amount` and the fee of a transaction with these inputs.
+/// More inputs can raise the fee, so this repeats until the fee is covered
+fn select_notes_with_fee<TxIn, N: NoteLike<TxIn>, R: RngCore, F: Fn(&[TxIn]) -> Amount>(
+    from_address: &str,
+    spendable_notes: &[N],
+    amount: Amount,
+    fee_for_inputs: F,
+    selector: &CoinSelector,
+    rng: &mut R,
+) -> crate::Result<(Vec<TxIn>, Amount)> {
+    let mut fee = fee_for_inputs(&[]);
+    loop {
+        let target_value = checked_total([amount, fee].iter().copied())?;
+        let tx_ins = select_notes(from_address, spendable_notes, target_value, selector, rng)?;
+        let needed_fee = fee_for_inputs(&tx_ins);
+        if needed_fee <= fee {
+            return Ok((tx_ins, fee));
+        }
+        fee = needed_fee;
+    }
+}
+
+/// Same as the default of the transaction builder.
+/// The expiry height is fixed when the payment is prepared, so a payment that is not
+/// broadcast within TX_EXPIRY_DELTA blocks expires, even if it is still `prepared`
+pub const TX_EXPIRY_DELTA: u32 = 20;
+
 #[allow(clippy::too_many_arguments)]
-pub fn prepare_tx<P: Parameters, C: GenericClient, R: RngCore>(
+pub fn prepare_tx<P: UnifiedParameters, C: GenericClient, R: RngCore>(
     network: &P,
     datetime: SystemTime,
     from_account: i32,
     to_address: &str,
     change_account: i32,
     amount: i64,
+    fee_rule: &FeeRule,
+    selector: &CoinSelector,
     c: &mut C,
     statements: &DbPreparedStatements,
     rng: &mut R
 ) -> crate::Result<grpc::UnsignedTx> {
     let amount = Amount::from_i64(amount).map_err(|_| anyhow!("Cannot convert amount"))?;
-    let target_value = amount + DEFAULT_FEE;
+    let outputs = [grpc::PaymentOutput {
+        address: to_address.to_string(),
+        amount: u64::from(amount),
+        memo: String::new(),
+        memo_hex: String::new(),
+    }];
+    prepare_batch_tx(network, datetime, from_account, &outputs, change_account, fee_rule, selector, c, statements, rng)
+}
+
+/// Pays several recipients, shielded or transparent, in a single transaction
+/// with one fee and one change output
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_batch_tx<P: UnifiedParameters, C: GenericClient, R: RngCore>(
+    network: &P,
+    datetime: SystemTime,
+    from_account: i32,
+    outputs: &[grpc::PaymentOutput],
+    change_account: i32,
+    fee_rule: &FeeRule,
+    selector: &CoinSelector,
+    c: &mut C,
+    statements: &DbPreparedStatements,
+    rng: &mut R
+) -> crate::Result<grpc::UnsignedTx> {
+    if outputs.is_empty() {
+        return Err(WalletError::Error(anyhow!("No recipient")));
+    }
+    // the change is always shielded
+    let mut shape = TxShape {
+        sapling_outputs: 1,
+        ..TxShape::default()
+    };
+    // Pay unified addresses to one of their receivers
+    let mut recipients: Vec<(String, Amount, Option<MemoBytes>)> = vec![];
+    for output in outputs.iter() {
+        let recipient = decode_recipient(network, &output.address)
+            .ok_or_else(|| WalletError::Error(anyhow!("Could not decode address {}", output.address)))?;
+        let amount = Amount::from_u64(output.amount).map_err(|_| anyhow!("Cannot convert amount"))?;
+        let memo = parse_memo(&output.memo, &output.memo_hex)?;
+        match recipient {
+            RecipientAddress::Shielded(_) => shape.sapling_outputs += 1,
+            RecipientAddress::Transparent(_) if memo.is_some() => {
+                return Err(WalletError::Error(anyhow!(
+                    "Cannot send a memo to the transparent address {}",
+                    output.address
+                )));
+            }
+            RecipientAddress::Transparent(_) => shape.transparent_outputs += 1,
+        }
+        recipients.push((recipient.encode(network), amount, memo));
+    }
+    let amount = checked_total(recipients.iter().map(|(_, amount, _)| *amount))?;
+    // the memos are stored in raw form
+    let outputs: Vec<_> = outputs
+        .iter()
+        .zip(recipients.iter())
+        .map(|(output, (_, _, memo))| grpc::PaymentOutput {
+            address: output.address.clone(),
+            amount: output.amount,
+            memo: String::new(),
+            memo_hex: memo.as_ref().map(|m| hex::encode(m.as_slice())).unwrap_or_default(),
+        })
+        .collect();
 
     let (height, anchor_height) = db::get_target_and_anchor_heights(c)?.unwrap();
 
@@ -141,6 +300,11 @@ pub fn prepare_tx<P: Parameters, C: GenericClient, R: RngCore>(
         }
         Account::Shielded(change_address, change_fvk) => (change_address, change_fvk),
     };
+    let change_ovk = decode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), &change_fvk)
+        .map_err(WalletError::Bech32)?
+        .ok_or_else(|| WalletError::Error(anyhow!("Invalid change fvk")))?
+        .fvk
+        .ovk;
 
     let mut tx = grpc::UnsignedTx {
         id: 0,
@@ -151,12 +315,15 @@ pub fn prepare_tx<P: Parameters, C: GenericClient, R: RngCore>(
         output: None,
         change_address: change_address.clone(),
         change_fvk,
+        outputs: Vec::new(),
+        fee: 0,
     };
 
     let mut ovk: Option<OutgoingViewingKey> = None;
 
     let mut notes: Vec<i32> = vec![];
     let mut utxos: Vec<i32> = vec![];
+    let fee: Amount;
 
     let from_address = match db::get_account(c, from_account)? {
         Account::Shielded(from_address, extfvk) => {
@@ -166,44 +333,70 @@ pub fn prepare_tx<P: Parameters, C: GenericClient, R: RngCore>(
                     .unwrap()
                     .unwrap();
             ovk = Some(extfvk.fvk.ovk);
-            let mut spendable_notes = db::get_spendable_notes_by_address(
-                c,
-                statements,
-                &from_address,
-                u32::from(anchor_height),
-            )?;
-            let mut tx_ins = select_notes(&from_address, &mut spendable_notes, target_value, rng)?;
-            tx_ins.iter().for_each(|txin| notes.push(txin.id));
-            tx.sap_inputs.append(&mut tx_ins);
+            let spendable_notes = get_account_notes(c, statements, from_account, &from_address, u32::from(anchor_height))?;
+            let (tx_ins, tx_fee) = select_notes_with_fee(&from_address, &spendable_notes, amount,
+                |tx_ins: &[AccountTxIn]| {
+                    let sapling_spends = tx_ins.iter().filter(|txin| matches!(txin, AccountTxIn::Sapling(_))).count();
+                    fee_rule.fee(&TxShape {
+                        sapling_spends,
+                        transparent_inputs: tx_ins.len() - sapling_spends,
+                        ..shape
+                    })
+                }, selector, rng)?;
+            fee = tx_fee;
+            for txin in tx_ins {
+                match txin {
+                    AccountTxIn::Sapling(txin) => {
+                        notes.push(txin.id);
+                        tx.sap_inputs.push(txin);
+                    }
+                    AccountTxIn::Transparent(utxo) => {
+                        utxos.push(utxo.id);
+                        tx.trp_inputs.push(utxo);
+                    }
+                }
+            }
             from_address
         }
         Account::Transparent(from_address) => {
-            let mut spendable_notes =
+            let spendable_notes =
                 db::get_spendable_transparent_notes_by_address(c, statements, &from_address)?;
-            let mut tx_ins = select_notes(&from_address, &mut spendable_notes, target_value, rng)?;
+            let (mut tx_ins, tx_fee) = select_notes_with_fee(&from_address, &spendable_notes, amount,
+                |tx_ins: &[grpc::Utxo]| fee_rule.fee(&TxShape { transparent_inputs: tx_ins.len(), ..shape }), selector, rng)?;
+            fee = tx_fee;
             tx_ins.iter().for_each(|txin| utxos.push(txin.id));
             tx.trp_inputs.append(&mut tx_ins);
             from_address
         }
     };
 
-    RecipientAddress::decode(network, to_address)
-        .ok_or_else(|| WalletError::Error(anyhow!("Could not decode address {}", to_address)))?;
-
-    tx.output = Some(grpc::SaplingTxOut {
-        address: to_address.to_string(),
-        amount: u64::from(amount),
-        ovk: ovk.map(|ovk| hex::encode(ovk.0)).unwrap_or_else(String::new),
-    });
+    // Transparent senders have no ovk. Use the change account's
+    // so that ZAMS can recover the outgoing notes and their memos
+    let ovk = hex::encode(ovk.unwrap_or(change_ovk).0);
+    tx.outputs = recipients
+        .into_iter()
+        .map(|(address, amount, memo)| grpc::SaplingTxOut {
+            address,
+            amount: u64::from(amount),
+            ovk: ovk.clone(),
+            memo: memo.map(|m| hex::encode(m.as_slice())).unwrap_or_default(),
+        })
+        .collect();
+    tx.fee = u64::from(fee);
+    // A single recipient stays where the signers that predate batches expect it
+    if tx.outputs.len() == 1 {
+        tx.output = tx.outputs.pop();
+    }
 
     let id_payment = db::store_payment(
         c,
         datetime,
         from_account,
         &from_address,
-        &to_address,
+        &outputs,
         &change_address,
-        i64::from(amount),
+        i64::from(fee),
+        u32::from(height) + TX_EXPIRY_DELTA,
         &notes,
         &utxos,
     )?;
@@ -211,11 +404,39 @@ pub fn prepare_tx<P: Parameters, C: GenericClient, R: RngCore>(
 
     crate::perfcounters::PAYMENTS.inc_by((i64::from(amount) as f64) / ZATPERZEC);
     crate::perfcounters::PREPARED_PAYMENTS.inc();
+    set_key_paths(c, &mut tx)?;
 
     Ok(tx)
 }
 
-pub fn sign_tx<P: Parameters>(network: &P, spending_key: &str, unsigned_tx: grpc::UnsignedTx) -> crate::Result<grpc::SignedTx> {
+/// Tells signers with a keystore where to derive the key of each input
+fn set_key_paths<C: GenericClient>(c: &mut C, tx: &mut grpc::UnsignedTx) -> crate::Result<()> {
+    let mut key_paths: HashMap<String, Option<grpc::KeyPath>> = HashMap::new();
+    for input in tx.trp_inputs.iter_mut() {
+        if !key_paths.contains_key(&input.address) {
+            key_paths.insert(input.address.clone(), db::get_key_path(c, &input.address)?);
+        }
+        input.key_path = key_paths[&input.address].clone();
+    }
+    for input in tx.sap_inputs.iter_mut() {
+        if !key_paths.contains_key(&input.address) {
+            key_paths.insert(input.address.clone(), db::get_key_path(c, &input.address)?);
+        }
+        input.key_path = key_paths[&input.address].clone();
+    }
+    Ok(())
+}
+
+/// Signs every input with the key of its address. Fails if one of them has no key in the keyring
+pub fn sign_tx<P: Parameters>(network: &P, keyring: &Keyring, unsigned_tx: grpc::UnsignedTx) -> crate::Result<grpc::SignedTx> {
+    // unsigned txs from older versions of ZAMS have no fee
+    if unsigned_tx.fee != 0 && unsigned_tx.fee != u64::from(DEFAULT_FEE) {
+        return Err(WalletError::Error(anyhow!(
+            "The transaction builder can only pay a fee of {} (requested {})",
+            u64::from(DEFAULT_FEE),
+            unsigned_tx.fee
+        )));
+    }
     let prover = LocalTxProver::with_default_location()
         .ok_or_else(|| WalletError::Error(anyhow!("Could not build local prover")))?;
     let height = BlockHeight::from_u32(unsigned_tx.height as u32);
@@ -223,8 +444,7 @@ pub fn sign_tx<P: Parameters>(network: &P, spending_key: &str, unsigned_tx: grpc
     let mut builder = Builder::new(network.clone(), height);
 
     for input in unsigned_tx.trp_inputs.iter() {
-        let seckey =
-            secp256k1::SecretKey::from_str(spending_key).context("Cannot parse secret key")?;
+        let seckey = keyring.transparent_key(network, input)?;
         let mut tx_hash = [0u8; 32];
         hex::decode_to_slice(&input.tx_hash, &mut tx_hash)?;
         tx_hash.reverse();
@@ -239,18 +459,18 @@ pub fn sign_tx<P: Parameters>(network: &P, spending_key: &str, unsigned_tx: grpc
     }
 
     for input in unsigned_tx.sap_inputs.iter() {
-        let extsk = decode_extended_spending_key(network.hrp_sapling_extended_spending_key(), spending_key)
-            .map_err(WalletError::Bech32)?
-            .unwrap();
         let mut d = [0u8; 11];
         hex::decode_to_slice(&input.diversifier, &mut d)?;
         let diversifier = Diversifier(d);
         let from = decode_payment_address(network.hrp_sapling_payment_address(), &input.address)
             .map_err(WalletError::Bech32)?
-            .unwrap();
-        let mut rcm = [0u8; 32];
-        hex::decode_to_slice(&input.rcm, &mut rcm)?;
-        let rseed = Rseed::BeforeZip212(Fr::from_bytes(&rcm).unwrap());
+            .ok_or_else(|| WalletError::Error(anyhow!("Invalid address {}", input.address)))?;
+        let extsk = keyring.sapling_key(&from)?;
+        let rseed = if input.rseed.is_empty() {
+            rseed_from_bytes(&hex::decode(&input.rcm)?, false)?
+        } else {
+            rseed_from_bytes(&hex::decode(&input.rseed)?, input.zip212)?
+        };
         let note = from.create_note(input.amount, rseed).unwrap();
         let w = hex::decode(&input.witness)?;
         let witness = IncrementalWitness::<Node>::read(&w[..]).map_err(WalletError::IO)?;
@@ -260,45 +480,56 @@ pub fn sign_tx<P: Parameters>(network: &P, spending_key: &str, unsigned_tx: grpc
             .map_err(WalletError::TxBuilder)?;
     }
 
-    let output = unsigned_tx.output.unwrap();
-    let recipient = RecipientAddress::decode(network, &output.address)
-        .ok_or_else(|| WalletError::Error(anyhow!("Invalid recipient address")))?;
-    let ovk = match output.ovk.as_str() {
-        "" => None,
-        o => {
-            let mut ovk = [0u8; 32];
-            hex::decode_to_slice(o, &mut ovk).unwrap();
-            Some(OutgoingViewingKey(ovk))
-        }
-    };
-    match recipient {
-        RecipientAddress::Shielded(pa) => {
-            builder.add_sapling_output(ovk, pa, Amount::from_u64(output.amount).unwrap(), None)?;
-        }
-        RecipientAddress::Transparent(ta) => {
-            builder.add_transparent_output(&ta, Amount::from_u64(output.amount).unwrap())?;
+    for output in unsigned_tx.output.iter().chain(unsigned_tx.outputs.iter()) {
+        let recipient = RecipientAddress::decode(network, &output.address)
+            .ok_or_else(|| WalletError::Error(anyhow!("Invalid recipient address")))?;
+        let ovk = match output.ovk.as_str() {
+            "" => None,
+            o => {
+                let mut ovk = [0u8; 32];
+                hex::decode_to_slice(o, &mut ovk).unwrap();
+                Some(OutgoingViewingKey(ovk))
+            }
+        };
+        let memo = match output.memo.as_str() {
+            "" => None,
+            m => Some(MemoBytes::from_bytes(&hex::decode(m)?).map_err(|_| anyhow!("Invalid memo"))?),
+        };
+        match recipient {
+            RecipientAddress::Shielded(pa) => {
+                builder.add_sapling_output(ovk, pa, Amount::from_u64(output.amount).unwrap(), memo)?;
+            }
+            RecipientAddress::Transparent(_) if memo.is_some() => {
+                return Err(WalletError::Error(anyhow!("Transparent outputs cannot have a memo")));
+            }
+            RecipientAddress::Transparent(ta) => {
+                builder.add_transparent_output(&ta, Amount::from_u64(output.amount).unwrap())?;
+            }
         }
     }
 
-    let change_recipient =
-        RecipientAddress::decode(network, &unsigned_tx.change_address)
-            .ok_or_else(|| WalletError::Error(anyhow!("Invalid recipient address")))?;
-    let change_pa = match change_recipient {
-        RecipientAddress::Shielded(pa) => pa,
-        RecipientAddress::Transparent(_) => {
-            return Err(WalletError::Error(anyhow!(
-                "Change address must be shielded"
-            )))
-        }
-    };
-    let change_fvk = decode_extended_full_viewing_key(
-        network.hrp_sapling_extended_full_viewing_key(),
-        &unsigned_tx.change_fvk,
-    )
-    .map_err(WalletError::Bech32)?
-    .unwrap();
-    let change_ovk = change_fvk.fvk.ovk;
-    builder.send_change_to(change_ovk, change_pa);
+    // consolidations spend everything and have no change
+    if !unsigned_tx.change_address.is_empty() {
+        let change_recipient =
+            RecipientAddress::decode(network, &unsigned_tx.change_address)
+                .ok_or_else(|| WalletError::Error(anyhow!("Invalid recipient address")))?;
+        let change_pa = match change_recipient {
+            RecipientAddress::Shielded(pa) => pa,
+            RecipientAddress::Transparent(_) => {
+                return Err(WalletError::Error(anyhow!(
+                    "Change address must be shielded"
+                )))
+            }
+        };
+        let change_fvk = decode_extended_full_viewing_key(
+            network.hrp_sapling_extended_full_viewing_key(),
+            &unsigned_tx.change_fvk,
+        )
+        .map_err(WalletError::Bech32)?
+        .unwrap();
+        let change_ovk = change_fvk.fvk.ovk;
+        builder.send_change_to(change_ovk, change_pa);
+    }
     let (tx, _) = builder.build(consensus_branch_id, &prover)?;
     let mut raw_tx = vec![];
     tx.write(&mut raw_tx).map_err(WalletError::IO)?;
@@ -310,9 +541,346 @@ pub fn sign_tx<P: Parameters>(network: &P, spending_key: &str, unsigned_tx: grpc
     })
 }
 
-pub fn broadcast_tx(c: &mut Client, signed_tx: &grpc::SignedTx, config: &ZamsConfig) -> crate::Result<String> {
-    let tx_id = send_raw_tx(&signed_tx.raw_tx, config)?;
+/// Where the notes to consolidate come from
+#[derive(Debug, Clone, Copy)]
+pub enum ConsolidationSource {
+    /// A shielded account or an imported transparent address
+    Account(i32),
+    /// All the diversified addresses of a viewing key, into its first address
+    Fvk(i32),
+}
+
+/// Default number of notes or utxos merged by a consolidation
+pub const DEFAULT_CONSOLIDATION_INPUTS: usize = 50;
+
+/// Merges up to `max_inputs` (0 for the default) of the smallest notes (or utxos) of the
+/// source into one output to itself, in the order of `ConsumeDust`. Shielded accounts also
+/// merge the utxos of their transparent receivers. The inputs are reserved by a payment like for `prepare_tx`
+pub fn prepare_consolidation<P: Parameters, C: GenericClient>(
+    network: &P,
+    source: &ConsolidationSource,
+    max_inputs: usize,
+    fee_rule: &FeeRule,
+    c: &mut C,
+    statements: &DbPreparedStatements,
+) -> crate::Result<grpc::Consolidation> {
+    let max_inputs = if max_inputs == 0 { DEFAULT_CONSOLIDATION_INPUTS } else { max_inputs };
+    let (height, anchor_height) = db::get_target_and_anchor_heights(c)?
+        .ok_or_else(|| WalletError::Error(anyhow!("The wallet has no blocks")))?;
+    let accounts = match source {
+        ConsolidationSource::Account(id_account) => vec![*id_account],
+        ConsolidationSource::Fvk(id_fvk) => db::get_fvk_accounts(c, *id_fvk)?,
+    };
+    let id_account = *accounts
+        .first()
+        .ok_or_else(|| WalletError::Error(anyhow!("The key has no address")))?;
+
+    let mut tx = grpc::UnsignedTx {
+        id: 0,
+        height: u32::from(height) as i32,
+        fvk: String::new(),
+        trp_inputs: Vec::new(),
+        sap_inputs: Vec::new(),
+        output: None,
+        change_address: String::new(),
+        change_fvk: String::new(),
+        outputs: Vec::new(),
+        fee: 0,
+    };
+    let mut shape = TxShape::default();
+    let mut notes: Vec<i32> = vec![];
+    let mut utxos: Vec<i32> = vec![];
+    let mut values: Vec<Amount> = vec![];
+
+    let (address, ovk, candidates) = match db::get_account(c, id_account)? {
+        Account::Shielded(address, extfvk) => {
+            let mut candidates: Vec<(String, AccountNote)> = vec![];
+            for id in accounts.iter() {
+                if let Account::Shielded(from_address, _) = db::get_account(c, *id)? {
+                    for note in get_account_notes(c, statements, *id, &from_address, u32::from(anchor_height))? {
+                        candidates.push((from_address.clone(), note));
+                    }
+                }
+            }
+            shape.sapling_outputs = 1;
+            let ovk = decode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), &extfvk)
+                .map_err(WalletError::Bech32)?
+                .ok_or_else(|| WalletError::Error(anyhow!("Invalid fvk")))?
+                .fvk
+                .ovk;
+            tx.fvk = extfvk;
+            (address, hex::encode(ovk.0), candidates)
+        }
+        Account::Transparent(address) => {
+            let candidates: Vec<(String, AccountNote)> = db::get_spendable_transparent_notes_by_address(c, statements, &address)?
+                .into_iter()
+                .map(|utxo| (address.clone(), AccountNote::Transparent(utxo)))
+                .collect();
+            shape.transparent_outputs = 1;
+            (address, String::new(), candidates)
+        }
+    };
+    let notes_before = candidates.len();
+    let candidate_values: Vec<Amount> = candidates.iter().map(|(_, note)| note.note_value()).collect();
+    let mut selected = CoinSelector::ConsumeDust.order(&candidate_values, &mut rand::thread_rng());
+    selected.truncate(max_inputs);
+    for i in selected {
+        let (from_address, note) = &candidates[i];
+        match note.to_tx_input(note.id(), from_address)? {
+            AccountTxIn::Sapling(input) => {
+                notes.push(input.id);
+                tx.sap_inputs.push(input);
+            }
+            AccountTxIn::Transparent(utxo) => {
+                utxos.push(utxo.id);
+                tx.trp_inputs.push(utxo);
+            }
+        }
+        values.push(note.note_value());
+    }
+    shape.sapling_spends = notes.len();
+    shape.transparent_inputs = utxos.len();
+    if values.len() < 2 {
+        return Err(WalletError::Error(anyhow!("Nothing to consolidate")));
+    }
+
+    let total: Amount = values.iter().copied().sum();
+    let fee = fee_rule.fee(&shape);
+    if total <= fee {
+        return Err(WalletError::Error(anyhow!(
+            "Not enough funds to pay the fee: total={:?}, fee={:?}",
+            total,
+            fee
+        )));
+    }
+    let amount = total - fee;
+    tx.output = Some(grpc::SaplingTxOut {
+        amount: u64::from(amount),
+        address: address.clone(),
+        ovk,
+        memo: String::new(),
+    });
+    tx.fee = u64::from(fee);
+
+    let outputs = [grpc::PaymentOutput {
+        address: address.clone(),
+        amount: u64::from(amount),
+        memo: String::new(),
+        memo_hex: String::new(),
+    }];
+    tx.id = db::store_payment(
+        c,
+        SystemTime::now(),
+        id_account,
+        &address,
+        &outputs,
+        &address,
+        i64::from(fee),
+        u32::from(height) + TX_EXPIRY_DELTA,
+        &notes,
+        &utxos,
+    )?;
+    crate::perfcounters::PREPARED_PAYMENTS.inc();
+    set_key_paths(c, &mut tx)?;
+
+    let notes_after = notes_before - values.len() + 1;
+    Ok(grpc::Consolidation {
+        tx: Some(tx),
+        notes_before: notes_before as u32,
+        notes_after: notes_after as u32,
+    })
+}
+
+/// Default number of utxos spent by a sweep
+pub const DEFAULT_SWEEP_INPUTS: usize = 50;
+
+/// Shields the utxos of many imported transparent addresses into one shielded account.
+/// Each input carries its address so that the signer can pick its key
+pub fn prepare_sweep<P: Parameters, C: GenericClient>(
+    network: &P,
+    from_accounts: &[i32],
+    to_account: i32,
+    min_balance: u64,
+    min_confirmations: u32,
+    max_inputs: usize,
+    fee_rule: &FeeRule,
+    c: &mut C,
+) -> crate::Result<grpc::Sweep> {
+    let max_inputs = if max_inputs == 0 { DEFAULT_SWEEP_INPUTS } else { max_inputs };
+    let (height, _) = db::get_target_and_anchor_heights(c)?
+        .ok_or_else(|| WalletError::Error(anyhow!("The wallet has no blocks")))?;
+    let (address, extfvk) = match db::get_account(c, to_account)? {
+        Account::Shielded(address, extfvk) => (address, extfvk),
+        Account::Transparent(_) => return Err(WalletError::Error(anyhow!("Sweeps must go to a shielded account"))),
+    };
+    let ovk = decode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), &extfvk)
+        .map_err(WalletError::Bech32)?
+        .ok_or_else(|| WalletError::Error(anyhow!("Invalid fvk")))?
+        .fvk
+        .ovk;
+
+    let max_height = sweep_max_height(u32::from(height), min_confirmations);
+    let mut utxos = db::get_sweep_utxos(c, from_accounts, min_balance as i64, max_height)?;
+    utxos.truncate(max_inputs);
+    if utxos.is_empty() {
+        return Err(WalletError::Error(anyhow!("Nothing to sweep")));
+    }
+    let mut accounts: Vec<i32> = utxos.iter().map(|(account, _)| *account).collect();
+    accounts.sort_unstable();
+    accounts.dedup();
+    let utxo_ids: Vec<i32> = utxos.iter().map(|(_, utxo)| utxo.id).collect();
+    let trp_inputs: Vec<grpc::Utxo> = utxos.into_iter().map(|(_, utxo)| utxo).collect();
+
+    let shape = TxShape {
+        transparent_inputs: trp_inputs.len(),
+        sapling_outputs: 1,
+        ..TxShape::default()
+    };
+    let total: Amount = trp_inputs.iter().map(|utxo| utxo.note_value()).sum();
+    let fee = fee_rule.fee(&shape);
+    if total <= fee {
+        return Err(WalletError::Error(anyhow!(
+            "Not enough funds to pay the fee: total={:?}, fee={:?}",
+            total,
+            fee
+        )));
+    }
+    let amount = total - fee;
+
+    let outputs = [grpc::PaymentOutput {
+        address: address.clone(),
+        amount: u64::from(amount),
+        memo: String::new(),
+        memo_hex: String::new(),
+    }];
+    let id = db::store_payment(
+        c,
+        SystemTime::now(),
+        to_account,
+        &address,
+        &outputs,
+        &address,
+        i64::from(fee),
+        u32::from(height) + TX_EXPIRY_DELTA,
+        &[],
+        &utxo_ids,
+    )?;
+    db::store_payment_sources(c, id, &accounts)?;
+    crate::perfcounters::PREPARED_PAYMENTS.inc();
+
+    let mut tx = grpc::UnsignedTx {
+        id,
+        height: u32::from(height) as i32,
+        fvk: String::new(),
+        trp_inputs,
+        sap_inputs: Vec::new(),
+        output: Some(grpc::SaplingTxOut {
+            amount: u64::from(amount),
+            address,
+            ovk: hex::encode(ovk.0),
+            memo: String::new(),
+        }),
+        change_address: String::new(),
+        change_fvk: String::new(),
+        outputs: Vec::new(),
+        fee: u64::from(fee),
+    };
+    set_key_paths(c, &mut tx)?;
+    Ok(grpc::Sweep {
+        tx: Some(tx),
+        accounts,
+        amount: u64::from(amount),
+    })
+}
+
+/// Highest height of the utxos with `min_confirmations` in a transaction mined at `target_height`.
+/// A utxo of the tip block has one confirmation
+fn sweep_max_height(target_height: u32, min_confirmations: u32) -> u32 {
+    target_height.saturating_sub(min_confirmations)
+}
+
+/// Prepares a new transaction for an expired or failed payment,
+/// with the same sender, recipients and amounts
+pub fn reissue_payment<P: UnifiedParameters, C: GenericClient, R: RngCore>(
+    network: &P,
+    id_payment: i32,
+    fee_rule: &FeeRule,
+    selector: &CoinSelector,
+    c: &mut C,
+    statements: &DbPreparedStatements,
+    rng: &mut R,
+) -> crate::Result<grpc::UnsignedTx> {
+    let mut db_tx = c.transaction()?;
+    let (from_account, outputs, change_account) = db::get_reissue_request(&mut db_tx, id_payment)?;
+    let tx = prepare_batch_tx(
+        network,
+        SystemTime::now(),
+        from_account,
+        &outputs,
+        change_account,
+        fee_rule,
+        selector,
+        &mut db_tx,
+        statements,
+        rng,
+    )?;
+    db::set_payment_reissue_of(&mut db_tx, tx.id, id_payment)?;
+    db_tx.commit()?;
+    Ok(tx)
+}
+
+/// Recovers the outgoing notes of the payment with the ovks we gave the signer
+fn store_sent_notes<P: Parameters, C: GenericClient>(c: &mut C, network: &P, id_payment: i32, tx: &Transaction) -> crate::Result<()> {
+    let mut extfvks = HashMap::new();
+    for (id_fvk, extfvk) in db::get_payment_fvks(c, id_payment)? {
+        let extfvk = decode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), &extfvk)
+            .map_err(WalletError::Bech32)?
+            .ok_or_else(|| WalletError::Error(anyhow!("Invalid fvk")))?;
+        extfvks.insert(AccountId(id_fvk as u32), extfvk);
+    }
+    let height = BlockHeight::from_u32(u32::from(tx.expiry_height).saturating_sub(TX_EXPIRY_DELTA));
+    let notes: Vec<_> = decrypt_transaction(network, height, tx, &extfvks)
+        .into_iter()
+        .filter(|output| output.outgoing)
+        .map(|output| {
+            (
+                output.index as i32,
+                encode_payment_address(network.hrp_sapling_payment_address(), &output.to),
+                output.note.value as i64,
+                output.memo.as_slice().to_vec(),
+            )
+        })
+        .collect();
+    let mut raw_tx = vec![];
+    tx.write(&mut raw_tx).map_err(WalletError::IO)?;
+    db::store_sent_notes(c, id_payment, &tx.txid().0, u32::from(tx.expiry_height), &raw_tx, &notes)
+}
+
+/// Sends a signed payment to zcashd. The payment fails only if zcashd rejects the
+/// transaction. If zcashd cannot be reached, it stays `signed` and can be broadcast again
+pub fn broadcast_tx<C: GenericClient>(c: &mut C, signed_tx: &grpc::SignedTx, config: &ZamsConfig) -> crate::Result<String> {
+    let tx = Transaction::read(&*hex::decode(&signed_tx.raw_tx)?).map_err(WalletError::IO)?;
+    let mut db_tx = c.transaction()?;
+    db::set_payment_state(&mut db_tx, signed_tx.id, PaymentState::Signed, None)?;
+    db::set_payment_expiry_height(&mut db_tx, signed_tx.id, u32::from(tx.expiry_height))?;
+    db_tx.commit()?;
+    let tx_id = match send_raw_tx(&signed_tx.raw_tx, config) {
+        Ok(tx_id) => tx_id,
+        Err(e) if is_rpc_error(&e) => {
+            let detail = format!("{:?}", e);
+            db::set_payment_state(c, signed_tx.id, PaymentState::Failed, Some(&detail))?;
+            return Err(e);
+        }
+        Err(e) => {
+            log::warn!("Cannot reach zcashd to broadcast payment {}: {:?}", signed_tx.id, e);
+            return Err(e);
+        }
+    };
     db::mark_paid(c, signed_tx.id, &tx_id)?;
+    // the payment went through even if this fails
+    if let Err(e) = store_sent_notes(c, &config.network, signed_tx.id, &tx) {
+        log::warn!("Cannot store the sent notes of payment {}: {:?}", signed_tx.id, e);
+    }
     crate::perfcounters::BROADCAST_PAYMENTS.inc();
     Ok(tx_id)
 }
@@ -326,6 +894,9 @@ mod tests {
     use crate::ZamsConfig;
     use zcash_primitives::consensus::Network::TestNetwork;
 
+    const SAPLING_SK: &str = "secret-extended-key-test1qfkvrtdpqqqqpqqr6g4fx2nwjx9788l0deqqtq9mcfmar4vk3dwtcjwfqaklemn9j4em4cggyw6n8heukq963nqx6upz7ktyg4kyeanmal5l3ssely5q4nd2jcsnulytl5zpyp7zyftrfhzfyec9rdf3hyg9cm70jeg0zrs8jzp7wak2envsy8tv9txq2tkkfa2y99rfxztza3lhvsswmz4q9p2xe05kh4yg7q3nad5s2vjj763maju3hpkpwwgavk7jpl2y8vqu5jqega2yj";
+    const TRANSPARENT_SK: &str = "877c779ad9687164e9c2f4f0f4ff0340814392330693ce95a58fe18fd52e6e93";
+
     fn setup() -> (Arc<Mutex<Client>>, DbPreparedStatements) {
         let config = ZamsConfig::default();
         let client = Client::connect(&config.connection_string, NoTls).unwrap();
@@ -345,6 +916,7 @@ mod tests {
                             "ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn",
                             1,
                             20_000_000,
+                            &FeeRule::Fixed, &CoinSelector::Random,
                             &mut *c.lock().unwrap(), &statements,
                             &mut rng).unwrap();
         println!("{}", serde_json::to_string(&tx).unwrap());
@@ -358,37 +930,223 @@ mod tests {
                             "ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn",
                             1,
                             500_000,
+                            &FeeRule::Fixed, &CoinSelector::Random,
                             &mut *c.lock().unwrap(), &statements,
                             &mut rng).unwrap();
         println!("{}", serde_json::to_string(&tx).unwrap());
     }
 
+    #[test]
+    fn test_prepare_batch_tx() {
+        let mut rng = thread_rng();
+        let (c, statements) = setup();
+        let outputs = [
+            grpc::PaymentOutput {
+                address: "ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn".to_string(),
+                amount: 100_000,
+                memo: "invoice-1".to_string(),
+                memo_hex: String::new(),
+            },
+            grpc::PaymentOutput {
+                address: "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string(),
+                amount: 200_000,
+                memo: String::new(),
+                memo_hex: String::new(),
+            },
+        ];
+        let mut client = c.lock().unwrap();
+        let tx = prepare_batch_tx(&TestNetwork, SystemTime::UNIX_EPOCH, 1, &outputs, 1,
+                                  &FeeRule::Fixed, &CoinSelector::LargestFirst, &mut *client, &statements, &mut rng).unwrap();
+        assert!(tx.output.is_none());
+        assert_eq!(tx.outputs.len(), 2);
+        let payment = db::get_payment_info(&mut *client, tx.id).unwrap();
+        assert_eq!(payment.amount, 300_000);
+        assert_eq!(payment.outputs.len(), 2);
+        assert_eq!(payment.outputs[0].memo, "invoice-1");
+        // two sapling outputs with the change, one transparent output
+        assert_eq!(tx.fee, u64::from(DEFAULT_FEE));
+        assert_eq!(payment.fee, tx.fee);
+    }
+
+    #[test]
+    fn test_batch_amount_out_of_range() {
+        let mut rng = thread_rng();
+        let (c, statements) = setup();
+        let output = grpc::PaymentOutput {
+            address: "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string(),
+            amount: 2_000_000_000_000_000,
+            memo: String::new(),
+            memo_hex: String::new(),
+        };
+        let outputs = [output.clone(), output];
+        let mut client = c.lock().unwrap();
+        let e = prepare_batch_tx(&TestNetwork, SystemTime::UNIX_EPOCH, 1, &outputs, 1,
+                                 &FeeRule::Fixed, &CoinSelector::LargestFirst, &mut *client, &statements, &mut rng).unwrap_err();
+        assert!(format!("{:?}", e).contains("total amount out of range"));
+        assert!(checked_total(vec![Amount::from_i64(1).unwrap(), DEFAULT_FEE]).is_ok());
+    }
+
+    #[test]
+    fn test_reissue_payment() {
+        let mut rng = thread_rng();
+        let (c, statements) = setup();
+        let outputs = [grpc::PaymentOutput {
+            address: "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string(),
+            amount: 100_000,
+            memo: String::new(),
+            memo_hex: String::new(),
+        }];
+        let mut client = c.lock().unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let tx = prepare_batch_tx(&TestNetwork, SystemTime::UNIX_EPOCH, 1, &outputs, 1,
+                                  &FeeRule::Fixed, &CoinSelector::LargestFirst, &mut db_tx, &statements, &mut rng).unwrap();
+        // prepared payments cannot be reissued
+        assert!(reissue_payment(&TestNetwork, tx.id, &FeeRule::Fixed, &CoinSelector::LargestFirst,
+                                &mut db_tx, &statements, &mut rng).is_err());
+        db::set_payment_state(&mut db_tx, tx.id, PaymentState::Expired, None).unwrap();
+        let reissued = reissue_payment(&TestNetwork, tx.id, &FeeRule::Fixed, &CoinSelector::LargestFirst,
+                                       &mut db_tx, &statements, &mut rng).unwrap();
+        let payment = db::get_payment_info(&mut db_tx, reissued.id).unwrap();
+        assert_eq!(payment.reissue_of, tx.id);
+        assert_eq!(payment.amount, 100_000);
+        let row = db_tx.query_one(
+            "SELECT (SELECT COUNT(*) FROM received_notes WHERE payment = $1) + (SELECT COUNT(*) FROM utxos WHERE payment = $1)",
+            &[&reissued.id]).unwrap();
+        let reserved: i64 = row.get(0);
+        assert_eq!(reserved as usize, reissued.sap_inputs.len() + reissued.trp_inputs.len());
+        assert!(reserved > 0);
+        assert!(reissue_payment(&TestNetwork, tx.id, &FeeRule::Fixed, &CoinSelector::LargestFirst,
+                                &mut db_tx, &statements, &mut rng).is_err());
+        db_tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_prepare_consolidation() {
+        let (c, statements) = setup();
+        let mut client = c.lock().unwrap();
+        let consolidation = prepare_consolidation(&TestNetwork, &ConsolidationSource::Account(1), 2,
+                                                  &FeeRule::Fixed, &mut *client, &statements).unwrap();
+        let tx = consolidation.tx.unwrap();
+        assert_eq!(tx.sap_inputs.len() + tx.trp_inputs.len(), 2);
+        assert!(tx.change_address.is_empty());
+        let output = tx.output.unwrap();
+        let total: u64 = tx.sap_inputs.iter().map(|i| i.amount).chain(tx.trp_inputs.iter().map(|i| i.amount)).sum();
+        assert_eq!(output.amount + tx.fee, total);
+        assert_eq!(consolidation.notes_after, consolidation.notes_before - 1);
+    }
+
+    #[test]
+    fn test_reissue_consolidation() {
+        let mut rng = thread_rng();
+        let (c, statements) = setup();
+        let mut client = c.lock().unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let consolidation = prepare_consolidation(&TestNetwork, &ConsolidationSource::Account(1), 2,
+                                                  &FeeRule::Fixed, &mut db_tx, &statements).unwrap();
+        let id = consolidation.tx.unwrap().id;
+        db::set_payment_state(&mut db_tx, id, PaymentState::Expired, None).unwrap();
+        let e = reissue_payment(&TestNetwork, id, &FeeRule::Fixed, &CoinSelector::LargestFirst,
+                                &mut db_tx, &statements, &mut rng).unwrap_err();
+        assert!(format!("{:?}", e).contains("consolidation"));
+        db_tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_prepare_sweep() {
+        let (c, _) = setup();
+        let mut client = c.lock().unwrap();
+        let sweep = prepare_sweep(&TestNetwork, &[], 1, 0, 1, 0, &FeeRule::Fixed, &mut *client).unwrap();
+        let tx = sweep.tx.unwrap();
+        assert!(tx.sap_inputs.is_empty());
+        assert!(tx.trp_inputs.iter().all(|utxo| !utxo.address.is_empty()));
+        let total: u64 = tx.trp_inputs.iter().map(|utxo| utxo.amount).sum();
+        assert_eq!(sweep.amount + tx.fee, total);
+        assert!(!sweep.accounts.is_empty());
+        let payment = db::get_payment_info(&mut *client, tx.id).unwrap();
+        assert_eq!(payment.source_accounts, sweep.accounts);
+    }
+
+    #[test]
+    fn test_reissue_sweep() {
+        let mut rng = thread_rng();
+        let (c, statements) = setup();
+        let mut client = c.lock().unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let sweep = prepare_sweep(&TestNetwork, &[], 1, 0, 1, 0, &FeeRule::Fixed, &mut db_tx).unwrap();
+        let id = sweep.tx.unwrap().id;
+        db::set_payment_state(&mut db_tx, id, PaymentState::Expired, None).unwrap();
+        let e = reissue_payment(&TestNetwork, id, &FeeRule::Fixed, &CoinSelector::LargestFirst,
+                                &mut db_tx, &statements, &mut rng).unwrap_err();
+        assert!(format!("{:?}", e).contains("sweep"));
+        let row = db_tx.query_one("SELECT COUNT(*) FROM payments WHERE reissue_of = $1", &[&id]).unwrap();
+        let reissued: i64 = row.get(0);
+        assert_eq!(reissued, 0);
+        db_tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_sweep_max_height() {
+        // tip at 100: its utxos have one confirmation
+        assert_eq!(sweep_max_height(101, 1), 100);
+        assert_eq!(sweep_max_height(101, 2), 99);
+        assert_eq!(sweep_max_height(101, 0), 101);
+        assert_eq!(sweep_max_height(5, 10), 0);
+    }
+
+    #[test]
+    fn test_transparent_memo() {
+        let mut rng = thread_rng();
+        let (c, statements) = setup();
+        let outputs = [grpc::PaymentOutput {
+            address: "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string(),
+            amount: 100_000,
+            memo: "invoice-1".to_string(),
+            memo_hex: String::new(),
+        }];
+        let mut client = c.lock().unwrap();
+        assert!(prepare_batch_tx(&TestNetwork, SystemTime::UNIX_EPOCH, 1, &outputs, 1,
+                                 &FeeRule::Fixed, &CoinSelector::Random, &mut *client, &statements, &mut rng).is_err());
+    }
+
     #[test]
     fn test_sign_tx() {
         let tx_json = r#"{"id":7,"height":1438929,"fvk":"zxviewtestsapling1qfkvrtdpqqqqpqqr6g4fx2nwjx9788l0deqqtq9mcfmar4vk3dwtcjwfqaklemn9j4fcskzsl4fsqecxs5wx7n8sna4lcgh4lynd40hw3dv02tyc6l80xfj0wfuzmxwesw8kzvtskg6h8tzzmfxky7gslhpeacn6tl2s2c0zjzp7wak2envsy8tv9txq2tkkfa2y99rfxztza3lhvsswmz4q9p2xe05kh4yg7q3nad5s2vjj763maju3hpkpwwgavk7jpl2y8vqu5jqmglfeq","trp_inputs":[],"sap_inputs":[{"id":8,"amount":49496000,"address":"ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn","diversifier":"79b99cb8c2a4647b06906b","rcm":"7ca5ad2265311704a4764eb838dfe07cb3fce96f7a9f29b024b8fde62ce1fa01","witness":"01b402041c0990cec1a94ccc7b1891fb435ab7c5ee3d77f76ea553c54464cbe643001001aacb702d2abed6aeaf918a21b2ac81a7d094d396f4a48229765269bea18dc82b000138a4ed6a370ac246e809c0bdd8c1bb92599379c410d517e55b9065e76570cc0e0000000001cc23dbfe7d27d7ad768868d7a96b6b31260ca34e4fbf164f652eb8e651f2fd3801b4c1c846cae1423eaf52f1a8b1bfdde9ed9d43ced4d80dba9e72d862a0e03e4001ba0d7aa9e68417291c63b835fa64114f5899208238de59ee360f594c8b6c1b72018469338dcbdf2f7e54bca5bc3e1c5fad4a656f206040436d3d0433a901218b5e016d559de7a1a382349cf97fe01a2fba41a49bb5e3b306d9ff8c2bcc301c731c00000001f08f39275112dd8905b854170b7f247cf2df18454d4fa94e6e4f9320cca05f24011f8322ef806eb2430dc4a7a41c1b344bea5be946efc7b4349c1c9edb14ff9d39045453a956cdb8ac799791415d8719cd77c46242bc53e6f83bd5c43889c9f81a2c5949057dc54d4f3190e18c095c4b1b0ebc676a2efc4cc19340ce5f7e03e3e5691d2dcba385f143b0f2cca16fd2f0faafeca2ae257742c266318626965c173536d2dbdc965c08d23d09b457328de48a248105c643b6c522f6291f087dc7746c1a0101df4c68750fe1db09744cd5af904b53a4a339d34d7a6a86642cd61381a9ee8b4c017c3dd9e32ca1d0fcacaa6b211543622b7766e391919680747fef03b33bb5ca2805000001b77627db19f550fb7b42dd2ad78b7f9a70fb5438c789ba14394f09a06c7b2a4700012c2c133c9aa15ecc67f808c159b1b7b78ea51df86ef02ca993d2f7d6ba4a1043"}],"output":{"amount":20000000,"address":"ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn","ovk":"9083e776caccd9021d6c2acc052ed64f5442946930962ec7f76420ed8aa02854"},"change_address":"ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn","change_fvk":"zxviewtestsapling1qfkvrtdpqqqqpqqr6g4fx2nwjx9788l0deqqtq9mcfmar4vk3dwtcjwfqaklemn9j4fcskzsl4fsqecxs5wx7n8sna4lcgh4lynd40hw3dv02tyc6l80xfj0wfuzmxwesw8kzvtskg6h8tzzmfxky7gslhpeacn6tl2s2c0zjzp7wak2envsy8tv9txq2tkkfa2y99rfxztza3lhvsswmz4q9p2xe05kh4yg7q3nad5s2vjj763maju3hpkpwwgavk7jpl2y8vqu5jqmglfeq"}"#;
         let tx = serde_json::from_str::<grpc::UnsignedTx>(tx_json).unwrap();
-        let signed_tx = sign_tx(&TestNetwork, "secret-extended-key-test1qfkvrtdpqqqqpqqr6g4fx2nwjx9788l0deqqtq9mcfmar4vk3dwtcjwfqaklemn9j4em4cggyw6n8heukq963nqx6upz7ktyg4kyeanmal5l3ssely5q4nd2jcsnulytl5zpyp7zyftrfhzfyec9rdf3hyg9cm70jeg0zrs8jzp7wak2envsy8tv9txq2tkkfa2y99rfxztza3lhvsswmz4q9p2xe05kh4yg7q3nad5s2vjj763maju3hpkpwwgavk7jpl2y8vqu5jqega2yj",
-                tx).unwrap();
+        let keyring = Keyring::new(&TestNetwork, &[SAPLING_SK.to_string()]).unwrap();
+        let signed_tx = sign_tx(&TestNetwork, &keyring, tx.clone()).unwrap();
         assert!(!signed_tx.raw_tx.is_empty());
+
+        // the transparent key cannot sign the note
+        let keyring = Keyring::new(&TestNetwork, &[TRANSPARENT_SK.to_string()]).unwrap();
+        assert!(sign_tx(&TestNetwork, &keyring, tx).is_err());
     }
 
     #[test]
     fn test_sign_trp_tx() {
         let tx_json = r#"{"id":8,"height":1438929,"fvk":"","trp_inputs":[{"id":5,"amount":500000,"tx_hash":"e416d3dbc1b7f34ba62ce6474bd59021bd96cf38a51be41f7cbd59c84db6258e","output_index":0,"hex":"76a914d8ab493736da02f11ed682f88339e720fb0379d188ac","spent":false},{"id":4,"amount":500000,"tx_hash":"6f84bf20c302ffcbcc7885647da7541ef956e3ce73e0ea1c7186aa910a52b723","output_index":0,"hex":"76a914d8ab493736da02f11ed682f88339e720fb0379d188ac","spent":false}],"sap_inputs":[],"output":{"amount":500000,"address":"ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn","ovk":""},"change_address":"ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn","change_fvk":"zxviewtestsapling1qfkvrtdpqqqqpqqr6g4fx2nwjx9788l0deqqtq9mcfmar4vk3dwtcjwfqaklemn9j4fcskzsl4fsqecxs5wx7n8sna4lcgh4lynd40hw3dv02tyc6l80xfj0wfuzmxwesw8kzvtskg6h8tzzmfxky7gslhpeacn6tl2s2c0zjzp7wak2envsy8tv9txq2tkkfa2y99rfxztza3lhvsswmz4q9p2xe05kh4yg7q3nad5s2vjj763maju3hpkpwwgavk7jpl2y8vqu5jqmglfeq"}"#;
         let tx = serde_json::from_str::<grpc::UnsignedTx>(tx_json).unwrap();
-        let signed_tx = sign_tx(&TestNetwork,
-            "877c779ad9687164e9c2f4f0f4ff0340814392330693ce95a58fe18fd52e6e93",
-            tx,
-        )
-        .unwrap();
+        let keyring = Keyring::new(&TestNetwork, &[SAPLING_SK.to_string(), TRANSPARENT_SK.to_string()]).unwrap();
+        let signed_tx = sign_tx(&TestNetwork, &keyring, tx.clone()).unwrap();
         assert!(!signed_tx.raw_tx.is_empty());
+
+        // by address
+        let mut tx = tx;
+        for input in tx.trp_inputs.iter_mut() {
+            input.address = "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string();
+        }
+        let keyring = Keyring::new(&TestNetwork, &[SAPLING_SK.to_string()]).unwrap();
+        assert!(sign_tx(&TestNetwork, &keyring, tx).is_err());
+    }
+
+    fn test_signed_tx() -> grpc::SignedTx {
+        serde_json::from_str(r#"{"id":21,"raw_tx":"0400008085202f8901a0a8689597f119d02e07930c38d70c411e4b711f5d119f635bae31fe3d38d659000000006a47304402202f85a86d3716c9825c9d426b757a1c48a1cd16495f7d5a298ba55d8494f3cf33022004ffaeeec82ba9d203bf5c4bd4064d09997a0bfab698250cef27199cdb384014012103c01e7425647bdefa82b12d9bad5e3e6865bee0502694b94ca58b666abc0a5c3bffffffff000000000038e51500c862f8ffffffffff00028f7e59e53cdb8437e485bc55cb817ad953190c7f6aca539337939d57581eaf30de46ccfcc2eccd88f72e5513b05ac0b069ab9d03ea270d3b11d9da9acb467165825a342833e3b64d1ce05a24610129e60fe338491578b1512d3d8deaa7a102e1fb7cd191370313be5e6131fbe9e398fe618dfc4534b214e1b54421f52b040970827c7934eec8229f274e9b813f06d4a151ac67b9de6ddb8788d0fe3871938acddacadf481eb83094399229e0aee178ad6128478aee5608dda2147506bcfdf9feed4983be05fd0619b910fbfe613e7e4862a4bae337188b2c6996dfa6496f08623d26d0a1b4fb4b206a2793fe84aaf307b3aca05dc3f4bd3c94bc1da01dedb9e4b63b705850f7b1b76ed675eb1f39eef9e1e55d90ea1e9fc768f3cc75bb270f9778f2c85b12a2979cd25133b2a69f168647e62adb174cfd4886734b02aca4d95d7f2d1ee74c02d1b556b7e6a692bb20f08bd9f6c4bb111dd11c875578abc4350c61d36afd709cbb89252e4161dd933edb4f74d292e0a739c27a37e138022c3cd78fddbded3873c1315b9da4b6209a8378f1cd560a7ce565c1c6cbc93e71510bb64e5f7754418b198a5f00c28b1acbcddc680590c2dd33b315ab38198dadcfa437fe8c9922280ba6b47c8077501a945f63697164b122fa50515dc220ee50210a7e8a44c5af8cad4d1ba8e3b5a6a27f160eba211a3b745d089dd873f83166634013e7067e12a59da0f0d8ed0c600c01c2d05b8beb19209efd787ec7944e35228c1ad75a428ffc22841e19e4c5055d9058994ee9320a9fa8abd5a9813359287eb6dd3975f624ce878771312c734cbd4b773103f2fff076d381003b55a8b2c1ffcf3244bd4f9f7ec1f6f5e398531ee203a1f22be7b6fdeaf98efacb6f1a289ad53e91af4dbcd7f6f8718d85e528eb7e061989e4f372dc27fc49e05ed23e8bcf234d209ace316f803c3388f298b448db01c992349fa784545fc49f0d39cedd61ffe94a022e5dbc60d4cbee6e19724381bceba6e1c66dcc8d0098b3ebe5afb43df909e61e65c8515af864916292671f3c128ec72a04ba6485a6952996e73ddb21178f03d459ba604a8cdc31e4be682f8c4b65684a9ea8451c43171da9c5e23873290aa337cd7c1bae9a7f4421c7663d07334ad05d21a0c7a368ea0aacfd268096b342581a740a1b454c418621887769d7eab0fc9eebd65c0fec05403f2b8fc32f165bcc3ca28627076c5989a0f1cc8b4b87cea9ac4e99900f895c552b98e620630cf05d90db8ebe96f594cc0b432daae775d2b2be4618a154a92ca688a70650ff22f754c9294f1252015499b466df4a60f0a6efdda5a49525ab9e4f2329af25b388ef5f3368452198f05cfb5c190adb23e05ddaca4798488f5580baa494339b81908035c101da796df09692a089d800e7d73f829ce38711cdb2e7e1fcf5fb7df79e00c81cc68498da2cedc3e10d9c7dc2a6b0fec17eccd6d38d73a90fadae7d9314f516f841f8094a02be9095777317b55ef27bf8b4493d8d0293f697633f0647a89e8a94dd04ec8516b5f8adbaa01ac4d91798ad65d77724b24a6c4e3eecb8344358ff3c9c7bd0342f0f5b7275a58ab786bba4381da57d8471da071ad59504df3acc6ea7e1bbe18701c2190aeb9fa89f588198cd802c53efc942caf2bfcd620c3717d7f9444c37f33248bf546bf10c240c43e8119ed6d4769a7a78439954531c614a203c76f47ac1e124926f8f5e8b546a68c850fbe3d5c46c657c243cb4283719173df498857882dc2668b880b348ff5d370ab1cd683548c60c16c6036d25f72a017dea825d9cfdb571d14fecf19cf750e57922f9e5a9336be8d22ffcb4e554a2aedb954fbf9a275153c0355605e2e35f35d2c39cb09f02e8b26ec54ed189f4650fd51c3ff0cc677f6ae929ea9e454e0cf4bd13c45aa8337a7fd5991e3aac5465b03bb4e7e37e8571d44ea474db0bd02506b0cb6399f8eb3da141ebd50933260043d5d9abbcd9315049c83059cbd2a3f594422907176dfb286c8cb22adc4533c85171d7ceb3450588b057773455df5bceedd0e7a3d841ae912475b7f6fd61c2e897a990a17368ccd86e89ef83ee69e39097793ec2810a10ac75cb7730064dfa623b1f2c74a3a2818d62ecbf99024508d0136c0a28ac3366036f0f19a6b16a806067b239666d8cd92b74c5dbe5e8deda0c5dcead199ed254131dc8e58fe7e87261c509e13918a62a1347c3190b3ff477cf4a07f493bef0180463b0fe331b1d9a9683da27c34608b1e4aa5c3d9c9ce09926737769d94cc66edebd331e89c140ea315f6798223432c8b1ad5d4ed44de3a83effbd510c3c0c54c39b30fe5b5cc7467ad9a610305e26a879c1a194ef34e43a3ffbd02da013a476b97bcf17035246a4e6c8beca4fb2fd1770494e8db1c0bbb99c06ef8d24ecf304840290b1d5a6fc728c793c2b708f3ddcad59bd8a94edf7a7febf702745e1d7b6abdcc9a36a7bd75a922652cfea291b14114e112c9c073587add4958b64475b7ddaf82958ddb70aeb7155f9c182fd38be38de2510b6dd09ce98dae8764f5559bdd4fbcbe29546a2929106751f13d9bac6f6ddf89309e04e76d774c97495f9d98eb2d30a840e6d1f3f64d2d274834dc9e1573fff1bc2e1dbd22be6afc51c88e0efe954d114ddb109a0f2b9fb9e4f01fcd830e2af00161669bd6b3663caf3001636c35cfe16a192bac02c5aee0511896281b8b0393dd675e69167b61305ad1c4972b8a049fdc7ec56e257e571903e20b24cdbe07e00"}"#).unwrap()
     }
 
     #[test]
     fn test_broadcast_tx() {
         let config = ZamsConfig::default();
         let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
-        let signed_tx: grpc::SignedTx = serde_json::from_str(r#"{"id":21,"raw_tx":"0400008085202f8901a0a8689597f119d02e07930c38d70c411e4b711f5d119f635bae31fe3d38d659000000006a47304402202f85a86d3716c9825c9d426b757a1c48a1cd16495f7d5a298ba55d8494f3cf33022004ffaeeec82ba9d203bf5c4bd4064d09997a0bfab698250cef27199cdb384014012103c01e7425647bdefa82b12d9bad5e3e6865bee0502694b94ca58b666abc0a5c3bffffffff000000000038e51500c862f8ffffffffff00028f7e59e53cdb8437e485bc55cb817ad953190c7f6aca539337939d57581eaf30de46ccfcc2eccd88f72e5513b05ac0b069ab9d03ea270d3b11d9da9acb467165825a342833e3b64d1ce05a24610129e60fe338491578b1512d3d8deaa7a102e1fb7cd191370313be5e6131fbe9e398fe618dfc4534b214e1b54421f52b040970827c7934eec8229f274e9b813f06d4a151ac67b9de6ddb8788d0fe3871938acddacadf481eb83094399229e0aee178ad6128478aee5608dda2147506bcfdf9feed4983be05fd0619b910fbfe613e7e4862a4bae337188b2c6996dfa6496f08623d26d0a1b4fb4b206a2793fe84aaf307b3aca05dc3f4bd3c94bc1da01dedb9e4b63b705850f7b1b76ed675eb1f39eef9e1e55d90ea1e9fc768f3cc75bb270f9778f2c85b12a2979cd25133b2a69f168647e62adb174cfd4886734b02aca4d95d7f2d1ee74c02d1b556b7e6a692bb20f08bd9f6c4bb111dd11c875578abc4350c61d36afd709cbb89252e4161dd933edb4f74d292e0a739c27a37e138022c3cd78fddbded3873c1315b9da4b6209a8378f1cd560a7ce565c1c6cbc93e71510bb64e5f7754418b198a5f00c28b1acbcddc680590c2dd33b315ab38198dadcfa437fe8c9922280ba6b47c8077501a945f63697164b122fa50515dc220ee50210a7e8a44c5af8cad4d1ba8e3b5a6a27f160eba211a3b745d089dd873f83166634013e7067e12a59da0f0d8ed0c600c01c2d05b8beb19209efd787ec7944e35228c1ad75a428ffc22841e19e4c5055d9058994ee9320a9fa8abd5a9813359287eb6dd3975f624ce878771312c734cbd4b773103f2fff076d381003b55a8b2c1ffcf3244bd4f9f7ec1f6f5e398531ee203a1f22be7b6fdeaf98efacb6f1a289ad53e91af4dbcd7f6f8718d85e528eb7e061989e4f372dc27fc49e05ed23e8bcf234d209ace316f803c3388f298b448db01c992349fa784545fc49f0d39cedd61ffe94a022e5dbc60d4cbee6e19724381bceba6e1c66dcc8d0098b3ebe5afb43df909e61e65c8515af864916292671f3c128ec72a04ba6485a6952996e73ddb21178f03d459ba604a8cdc31e4be682f8c4b65684a9ea8451c43171da9c5e23873290aa337cd7c1bae9a7f4421c7663d07334ad05d21a0c7a368ea0aacfd268096b342581a740a1b454c418621887769d7eab0fc9eebd65c0fec05403f2b8fc32f165bcc3ca28627076c5989a0f1cc8b4b87cea9ac4e99900f895c552b98e620630cf05d90db8ebe96f594cc0b432daae775d2b2be4618a154a92ca688a70650ff22f754c9294f1252015499b466df4a60f0a6efdda5a49525ab9e4f2329af25b388ef5f3368452198f05cfb5c190adb23e05ddaca4798488f5580baa494339b81908035c101da796df09692a089d800e7d73f829ce38711cdb2e7e1fcf5fb7df79e00c81cc68498da2cedc3e10d9c7dc2a6b0fec17eccd6d38d73a90fadae7d9314f516f841f8094a02be9095777317b55ef27bf8b4493d8d0293f697633f0647a89e8a94dd04ec8516b5f8adbaa01ac4d91798ad65d77724b24a6c4e3eecb8344358ff3c9c7bd0342f0f5b7275a58ab786bba4381da57d8471da071ad59504df3acc6ea7e1bbe18701c2190aeb9fa89f588198cd802c53efc942caf2bfcd620c3717d7f9444c37f33248bf546bf10c240c43e8119ed6d4769a7a78439954531c614a203c76f47ac1e124926f8f5e8b546a68c850fbe3d5c46c657c243cb4283719173df498857882dc2668b880b348ff5d370ab1cd683548c60c16c6036d25f72a017dea825d9cfdb571d14fecf19cf750e57922f9e5a9336be8d22ffcb4e554a2aedb954fbf9a275153c0355605e2e35f35d2c39cb09f02e8b26ec54ed189f4650fd51c3ff0cc677f6ae929ea9e454e0cf4bd13c45aa8337a7fd5991e3aac5465b03bb4e7e37e8571d44ea474db0bd02506b0cb6399f8eb3da141ebd50933260043d5d9abbcd9315049c83059cbd2a3f594422907176dfb286c8cb22adc4533c85171d7ceb3450588b057773455df5bceedd0e7a3d841ae912475b7f6fd61c2e897a990a17368ccd86e89ef83ee69e39097793ec2810a10ac75cb7730064dfa623b1f2c74a3a2818d62ecbf99024508d0136c0a28ac3366036f0f19a6b16a806067b239666d8cd92b74c5dbe5e8deda0c5dcead199ed254131dc8e58fe7e87261c509e13918a62a1347c3190b3ff477cf4a07f493bef0180463b0fe331b1d9a9683da27c34608b1e4aa5c3d9c9ce09926737769d94cc66edebd331e89c140ea315f6798223432c8b1ad5d4ed44de3a83effbd510c3c0c54c39b30fe5b5cc7467ad9a610305e26a879c1a194ef34e43a3ffbd02da013a476b97bcf17035246a4e6c8beca4fb2fd1770494e8db1c0bbb99c06ef8d24ecf304840290b1d5a6fc728c793c2b708f3ddcad59bd8a94edf7a7febf702745e1d7b6abdcc9a36a7bd75a922652cfea291b14114e112c9c073587add4958b64475b7ddaf82958ddb70aeb7155f9c182fd38be38de2510b6dd09ce98dae8764f5559bdd4fbcbe29546a2929106751f13d9bac6f6ddf89309e04e76d774c97495f9d98eb2d30a840e6d1f3f64d2d274834dc9e1573fff1bc2e1dbd22be6afc51c88e0efe954d114ddb109a0f2b9fb9e4f01fcd830e2af00161669bd6b3663caf3001636c35cfe16a192bac02c5aee0511896281b8b0393dd675e69167b61305ad1c4972b8a049fdc7ec56e257e571903e20b24cdbe07e00"}"#).unwrap();
+        let signed_tx = test_signed_tx();
         let res = broadcast_tx(&mut client, &signed_tx, &config);
         match res {
             Err(WalletError::Error(e)) => {
@@ -399,4 +1157,33 @@ mod tests {
             _ => {}
         }
     }
+
+    #[test]
+    fn test_broadcast_unreachable_zcashd() {
+        let mut config = ZamsConfig::default();
+        let mut client = Client::connect(&config.connection_string, NoTls).unwrap();
+        let mut db_tx = client.transaction().unwrap();
+        let row = db_tx.query_one(
+            "INSERT INTO utxos(tx_hash, account, address, output_index, value, script, height, spent)
+            VALUES ($1, 1, 'tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8', 0, 200000, $2, 1438900, FALSE) RETURNING id_utxo",
+            &[&vec![0xFFu8; 32], &Vec::<u8>::new()]).unwrap();
+        let id_utxo: i32 = row.get(0);
+        let id = db::store_payment(&mut db_tx, SystemTime::now(), 1,
+            "ztestsapling10xueewxz53j8kp5sdd79uk5ffsgshukkauyxduscu86zjp778xyavmqftz87pcs2zexzxyclmwn",
+            &[grpc::PaymentOutput { address: "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8".to_string(), amount: 100_000,
+                memo: String::new(), memo_hex: String::new() }],
+            "tmVTzUmRp4mNb8jSF8qUs2P39gM8oGZ4zo8", 1_000, 1_438_949, &[], &[id_utxo]).unwrap();
+        config.zcashd = "http://127.0.0.1:1".to_string();
+        let signed_tx = grpc::SignedTx { id, ..test_signed_tx() };
+        for _ in 0..2 {
+            let e = broadcast_tx(&mut db_tx, &signed_tx, &config).unwrap_err();
+            assert!(!is_rpc_error(&e));
+            assert_eq!(db::get_payment_state(&mut db_tx, id).unwrap(), PaymentState::Signed);
+            // the inputs stay reserved for the next attempt
+            let row = db_tx.query_one("SELECT payment FROM utxos WHERE id_utxo = $1", &[&id_utxo]).unwrap();
+            let payment: Option<i32> = row.get(0);
+            assert_eq!(payment, Some(id));
+        }
+        db_tx.rollback().unwrap();
+    }
 }